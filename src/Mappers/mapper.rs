@@ -5,11 +5,23 @@ pub enum Mirroring {
     Horizontal,
     Hardware,
 }
+//Peeks map an address without touching mapper state, for debuggers and memory viewers.
+//Mappers whose reads have side effects (MMC2 latches, IRQ counters) override the read methods
 pub trait Mapper{
-    fn cpu_mapper_read(&mut self, address: u16, mapped_address: &mut i32, data: &mut u8) -> bool;
+    fn cpu_mapper_peek(&self, address: u16, mapped_address: &mut i32, data: &mut u8) -> bool;
+    fn ppu_mapper_peek(&self, address: u16, mapped_address: &mut u32) -> bool;
+    fn cpu_mapper_read(&mut self, address: u16, mapped_address: &mut i32, data: &mut u8) -> bool {
+        return self.cpu_mapper_peek(address, mapped_address, data);
+    }
     fn cpu_mapper_write(&mut self, address: u16, mapped_address: &mut i32, data: &mut u8) -> bool;
-    fn ppu_mapper_read(&mut self, address: u16, mapped_address: &mut u32) -> bool;
+    fn ppu_mapper_read(&mut self, address: u16, mapped_address: &mut u32) -> bool {
+        return self.ppu_mapper_peek(address, mapped_address);
+    }
     fn ppu_mapper_write(&mut self, address: u16, mapped_address: &mut u32) -> bool;
     fn reset(&mut self);
-    fn mirror(&mut self) -> Mirroring;
+    fn mirror(&self) -> Mirroring;
+    //The cartridge's IRQ line, for mappers with scanline or cycle counters
+    fn irq_asserted(&self) -> bool {
+        return false;
+    }
 }
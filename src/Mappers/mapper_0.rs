@@ -16,7 +16,7 @@ impl Mapper0 {
 
 #[allow(unused_comparisons)]
 impl Mapper for Mapper0 {
-    fn cpu_mapper_read(&mut self, address: u16, mapped_address: &mut i32, data: &mut u8) -> bool {
+    fn cpu_mapper_peek(&self, address: u16, mapped_address: &mut i32, data: &mut u8) -> bool {
         if address >= 0x8000 && address <= 0xBFFF 
         {
             let m_address = (address & 0x3FFF) as u32;
@@ -45,7 +45,7 @@ impl Mapper for Mapper0 {
         return false;
     }
 
-    fn ppu_mapper_read(&mut self, address: u16, mapped_address: &mut u32) -> bool {
+    fn ppu_mapper_peek(&self, address: u16, mapped_address: &mut u32) -> bool {
         if address >= 0x0000 && address <= 0x1FFF {
             *mapped_address = address as u32;
             return true;
@@ -64,7 +64,7 @@ impl Mapper for Mapper0 {
     fn reset(&mut self){
 
     }
-    fn mirror(&mut self) -> Mirroring{
+    fn mirror(&self) -> Mirroring{
         return Mirroring::Hardware;
     }
 }
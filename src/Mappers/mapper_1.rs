@@ -46,7 +46,7 @@ impl Mapper1 {
 
 #[allow(unused_comparisons)]
 impl Mapper for Mapper1 {
-    fn cpu_mapper_read(&mut self, address: u16, mapped_address: &mut i32, data: &mut u8) -> bool {
+    fn cpu_mapper_peek(&self, address: u16, mapped_address: &mut i32, data: &mut u8) -> bool {
         if address >= 0x6000 && address <= 0x7FFF 
         {
             *mapped_address = -1;
@@ -161,7 +161,7 @@ impl Mapper for Mapper1 {
         return false;
     }
 
-    fn ppu_mapper_read(&mut self, address: u16, mapped_address: &mut u32) -> bool {
+    fn ppu_mapper_peek(&self, address: u16, mapped_address: &mut u32) -> bool {
         if address < 0x2000
         {
             if self.n_chr_banks == 0
@@ -226,7 +226,7 @@ impl Mapper for Mapper1 {
         self.count = 0;
 
     }
-    fn mirror(&mut self) -> Mirroring{
+    fn mirror(&self) -> Mirroring{
         return self.mirror;
     }
 }
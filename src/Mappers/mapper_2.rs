@@ -22,7 +22,7 @@ impl Mapper2 {
 
 #[allow(unused_comparisons)]
 impl Mapper for Mapper2 {
-    fn cpu_mapper_read(&mut self, address: u16, mapped_address: &mut i32, data: &mut u8) -> bool {
+    fn cpu_mapper_peek(&self, address: u16, mapped_address: &mut i32, data: &mut u8) -> bool {
 
         if address >= 0x8000 && address <= 0xBFFF
         {
@@ -46,7 +46,7 @@ impl Mapper for Mapper2 {
         return false;
     }
 
-    fn ppu_mapper_read(&mut self, address: u16, mapped_address: &mut u32) -> bool {
+    fn ppu_mapper_peek(&self, address: u16, mapped_address: &mut u32) -> bool {
 
         if address < 0x2000
         {
@@ -76,7 +76,7 @@ impl Mapper for Mapper2 {
         self.prg_bank_low = 0;
         self.prg_bank_high = self.n_prg_banks - 1;
     }
-    fn mirror(&mut self) -> Mirroring{
+    fn mirror(&self) -> Mirroring{
         return Mirroring::Hardware;
     }
 }
@@ -1,4 +1,6 @@
 use crate::cartridge::Cartridge;
+use crate::region::Region;
+use crate::resampler::{Resampler, NTSC_CLOCK_RATE};
 use crate::RefCell;
 use crate::Rc;
 
@@ -14,7 +16,9 @@ pub enum Frame {
 
 pub const WAVEFORMS: [[u8; 8]; 4] = [[0, 1, 0, 0, 0, 0, 0, 0],[0, 1, 1, 0, 0, 0, 0, 0],[0, 1, 1, 1, 1, 0, 0, 0],[1, 0, 0, 1, 1, 1, 1, 1]];
 
+//https://wiki.nesdev.com/w/index.php/APU_DMC, rates in APU cycles (two CPU cycles)
 pub const PERIODS: [u8; 16] = [214, 190, 170, 160, 143, 127, 113, 107, 95, 80, 71, 64, 53, 42, 36, 27];
+pub const PAL_PERIODS: [u8; 16] = [199, 177, 158, 149, 138, 118, 105, 99, 88, 74, 66, 59, 49, 39, 33, 25];
 
 //https://wiki.nesdev.com/w/index.php/APU_Length_Counter#:
 pub const LENGTHS: [u8; 32] =[  
@@ -30,12 +34,55 @@ pub const TRIANGLE_SEQUENCE: [u8; 32] = [
     0, 1, 2, 3, 4, 5, 6, 7,
     8, 9, 10, 11, 12, 13, 14, 15];
 
-//https://wiki.nesdev.com/w/index.php/APU_Noise
+//https://wiki.nesdev.com/w/index.php/APU_Noise, periods in CPU cycles
 const NOISE_TIMER: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+const PAL_NOISE_TIMER: [u16; 16] = [4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778];
+
+//Frame counter steps in CPU cycles, the 4 step sequence ends on the fourth and the 5 step on the
+//fifth. Dendy keeps the NTSC APU timing
+//https://wiki.nesdev.com/w/index.php/APU_Frame_Counter
+const FRAME_STEPS: [i64; 5] = [7457, 14913, 22371, 29829, 37281];
+const PAL_FRAME_STEPS: [i64; 5] = [8313, 16627, 24939, 33253, 41565];
 
                     
 const PI: f64 = 3.141592;
 
+pub const DEFAULT_SAMPLE_RATE: f64 = 44100.0;
+//WAV and video captures have their own output at this rate, see APU::set_capture
+pub const CAPTURE_SAMPLE_RATE: f64 = 48000.0;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Channel {
+    Pulse0,
+    Pulse1,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+pub const CHANNELS: [Channel; 5] = [Channel::Pulse0, Channel::Pulse1, Channel::Triangle, Channel::Noise, Channel::Dmc];
+
+impl Channel {
+    pub fn name(self) -> &'static str {
+        match self {
+            Channel::Pulse0 => "pulse_0",
+            Channel::Pulse1 => "pulse_1",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "dmc",
+        }
+    }
+}
+
+//Per channel mixer settings, applied to the channel's output level before the nonlinear mix
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ChannelMix {
+    pub enabled: bool,
+    pub gain: f32,
+}
+//Resampler is flushed this often, keeps its buffer small and latency under a millisecond
+const FLUSH_CLOCKS: u32 = 1024;
+
 bitfield!{
     pub struct EnvelopeRegister(u8);
     pub constant_volume,   _: 3, 0;
@@ -50,34 +97,231 @@ pub struct APU {
     pulse_1: PULSE,
     triangle: TRIANGLE,
     noise: NOISE,
-    global_time: u32,
-
+    dmc: DMC,
+    pub mix: [ChannelMix; 5],
+    pulse_table: [f32; 31],
+    tnd_table: [f32; 203],
+    resampler: Resampler,
+    resampler_clock: u32,
+    //The mix for captures, only produced while capturing
+    pub capture_samples: Vec<i16>,
+    capture_resampler: Option<Resampler>,
+    //Each channel on its own through the mixer at the capture rate, only produced while capturing channels
+    pub channel_samples: [Vec<i16>; 5],
+    channel_resamplers: Vec<Resampler>,
+
+    //Frame counter, https://wiki.nesdev.com/w/index.php/APU_Frame_Counter
     pub counter: i64,
     pub cycles: u64,
-    pub irq: bool,
-    pub public_irq: bool,
-    pub private_irq: bool,
+    pub irq_inhibit: bool,
+    pub frame_irq: bool,
     counter_mode: CounterMode,
+    frame_reset_delay: u8,
+    region: Region,
 }
 
 impl APU {
     pub fn new() -> APU {
         APU {
-            pulse_0: PULSE::new(false), 
-            pulse_1: PULSE::new(true),
+            pulse_0: PULSE::new(true),
+            pulse_1: PULSE::new(false),
             triangle: TRIANGLE::new(),
             noise: NOISE::new(),
-            global_time: 0,
+            dmc: DMC::new(),
+            mix: [ChannelMix { enabled: true, gain: 1.0 }; 5],
+            pulse_table: APU::pulse_table(),
+            tnd_table: APU::tnd_table(),
             samples: Vec::new(),
+            resampler: Resampler::new(NTSC_CLOCK_RATE, DEFAULT_SAMPLE_RATE),
+            resampler_clock: 0,
+            capture_samples: Vec::new(),
+            capture_resampler: None,
+            channel_samples: [Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new()],
+            channel_resamplers: Vec::new(),
 
             counter: 0,
             cycles: 0,
-            irq: true,
-            public_irq: false,
-            private_irq: false,
+            irq_inhibit: false,
+            frame_irq: false,
             counter_mode: CounterMode::Zero,
+            frame_reset_delay: 0,
+            region: Region::Ntsc,
+        }
+    }
+
+    //Period tables, frame counter and the resampler's input rate all follow the region
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.resampler = Resampler::new(region.cpu_clock_rate(), self.resampler.sample_rate());
+        self.resampler_clock = 0;
+        let (capturing, channels) = (self.capture_resampler.is_some(), !self.channel_resamplers.is_empty());
+        self.capture_resampler = None;
+        self.set_capture(capturing);
+        self.set_channel_capture(channels);
+    }
+
+    //https://wiki.nesdev.com/w/index.php/CPU_power_up_state
+    //Every register starts at $00, so the channels and frame counter start from scratch
+    pub fn power_on(&mut self) {
+        self.pulse_0 = PULSE::new(true);
+        self.pulse_1 = PULSE::new(false);
+        self.triangle = TRIANGLE::new();
+        self.noise = NOISE::new();
+        self.dmc = DMC::new();
+        self.counter = 0;
+        self.cycles = 0;
+        self.irq_inhibit = false;
+        self.frame_irq = false;
+        self.counter_mode = CounterMode::Zero;
+        self.frame_reset_delay = 0;
+    }
+
+    //Reset acts like a $4015 = 0 write, $4017 keeps its mode and the frame counter restarts
+    pub fn reset(&mut self) {
+        self.cpu_write(0x4015, 0x00);
+        self.frame_irq = false;
+        self.triangle.sequencer.current_step = 0;
+        self.dmc.output_level &= 0x01;
+        self.frame_reset_delay = if self.cycles & 1 == 0 { 3 } else { 4 };
+    }
+
+    fn frame_steps(&self) -> &'static [i64; 5] {
+        match self.region {
+            Region::Pal => &PAL_FRAME_STEPS,
+            _ => &FRAME_STEPS,
+        }
+    }
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.resampler = Resampler::new(self.resampler.clock_rate(), sample_rate);
+        self.resampler_clock = 0;
+    }
+
+    //Produces capture_samples for WAV and video captures. They take every channel at full gain
+    //whatever is muted for listening, at CAPTURE_SAMPLE_RATE with no dynamic rate control, so a
+    //capture keeps time with the emulation rather than the host's audio queue
+    pub fn set_capture(&mut self, enabled: bool) {
+        if enabled == self.capture_resampler.is_some() {
+            return;
+        }
+        self.capture_samples.clear();
+        self.capture_resampler = if enabled {
+            Some(Resampler::new(self.resampler.clock_rate(), CAPTURE_SAMPLE_RATE))
+        } else {
+            None
+        };
+        if !enabled {
+            self.set_channel_capture(false);
         }
     }
+
+    //Produces channel_samples alongside capture_samples, costs a resampler per channel so is off by
+    //default. They keep in step with the capture mix so every file gets the same number of samples
+    pub fn set_channel_capture(&mut self, enabled: bool) {
+        self.channel_resamplers.clear();
+        for samples in self.channel_samples.iter_mut() {
+            samples.clear();
+        }
+        if enabled {
+            self.set_capture(true);
+            if let Some(ref capture) = self.capture_resampler {
+                for _ in CHANNELS.iter() {
+                    let mut resampler = Resampler::new(capture.clock_rate(), CAPTURE_SAMPLE_RATE);
+                    resampler.align_with(capture);
+                    self.channel_resamplers.push(resampler);
+                }
+            }
+        }
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.resampler.sample_rate()
+    }
+
+    //Dynamic rate control, `fill` is the host queue level relative to its target (1.0 = on target)
+    pub fn set_rate_control(&mut self, fill: f64) {
+        self.resampler.set_rate_control(fill);
+    }
+
+    //https://wiki.nesdev.com/w/index.php/APU_Mixer#Lookup_Table
+    fn pulse_table() -> [f32; 31] {
+        let mut table = [0.0; 31];
+        for n in 1..31 {
+            table[n] = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+        table
+    }
+
+    fn tnd_table() -> [f32; 203] {
+        let mut table = [0.0; 203];
+        for n in 1..203 {
+            table[n] = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+        table
+    }
+
+    pub fn channel(&self, channel: Channel) -> ChannelMix {
+        self.mix[channel as usize]
+    }
+
+    pub fn set_channel_enabled(&mut self, channel: Channel, enabled: bool) {
+        self.mix[channel as usize].enabled = enabled;
+    }
+
+    pub fn set_channel_gain(&mut self, channel: Channel, gain: f32) {
+        self.mix[channel as usize].gain = gain.max(0.0);
+    }
+
+    //Enables only `channel`, soloing the channel that is already the only one enabled restores all
+    pub fn solo(&mut self, channel: Channel) {
+        let already_solo = CHANNELS
+            .iter()
+            .all(|&c| self.mix[c as usize].enabled == (c == channel));
+        for &c in CHANNELS.iter() {
+            self.mix[c as usize].enabled = already_solo || c == channel;
+        }
+    }
+
+    //Level of the APU's IRQ line, frame counter or DMC
+    pub fn irq_asserted(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    //Address the DMC memory reader wants filled by the bus, see dmc_fill
+    pub fn dmc_fetch_address(&self) -> Option<u16> {
+        if self.dmc.buffer.is_none() && self.dmc.bytes_remaining > 0 {
+            Some(self.dmc.current_address)
+        } else {
+            None
+        }
+    }
+
+    pub fn dmc_fill(&mut self, data: u8) {
+        self.dmc.fill(data);
+    }
+
+    //Status without acknowledging the frame IRQ
+    pub fn peek_cpu(&self, address: u16) -> u8 {
+        let mut data = 0x00;
+        if address == 0x4015 {
+            if self.pulse_0.length_counter.counter > 0 { data |= 0x01; }
+            if self.pulse_1.length_counter.counter > 0 { data |= 0x02; }
+            if self.triangle.length_counter.counter > 0 { data |= 0x04; }
+            if self.noise.length_counter.counter > 0 { data |= 0x08; }
+            if self.dmc.bytes_remaining > 0 { data |= 0x10; }
+            if self.frame_irq { data |= 0x40; }
+            if self.dmc.irq { data |= 0x80; }
+        }
+        return data;
+    }
+
+    pub fn cpu_read(&mut self, address: u16) -> u8 {
+        let data = self.peek_cpu(address);
+        if address == 0x4015 {
+            self.frame_irq = false;
+        }
+        return data;
+    }
+
     pub fn cpu_write(&mut self, address: u16, data: u8) {
         match address {
             0x4000 => {
@@ -126,23 +370,30 @@ impl APU {
             }
             0x4008 => 
             {
+                //The triangle's control flag doubles as its length counter halt
                 self.triangle.control_flag = data & 0x80 != 0;
-                self.triangle.length_counter.pending_stop = Some(data & 0x20 != 0);
+                self.triangle.length_counter.pending_stop = Some(data & 0x80 != 0);
                 self.triangle.linear_counter_period = data & 0x7F;
             }
-            0x4009 => 
-            {
-
-            }
+            0x4009 => {} //Unused on hardware
             0x4010 => {
-
+                self.dmc.irq_enabled = data & 0x80 != 0;
+                self.dmc.loop_flag = data & 0x40 != 0;
+                let periods = if self.region == Region::Pal { &PAL_PERIODS } else { &PERIODS };
+                self.dmc.period = periods[data as usize & 0xF] as u16 * 2;
+                if !self.dmc.irq_enabled {
+                    self.dmc.irq = false;
+                }
             }
             0x4011 => {
+                self.dmc.output_level = data & 0x7F;
             }
             0x4012 => {
+                self.dmc.sample_address = 0xC000 | ((data as u16) << 6);
             }
             0x4013 => {
-            }  
+                self.dmc.sample_length = ((data as u16) << 4) | 1;
+            }
             0x400A => 
             {
 
@@ -159,11 +410,12 @@ impl APU {
                 self.noise.length_counter.pending_stop = Some(data & 0x20 != 0);
                 self.noise.envelope.controller = EnvelopeRegister(data);
             },
-            0x400D => {},
+            0x400D => {}, //Unused on hardware
             0x400E => 
             {
                 self.noise.mode = data & 0x80 != 0;
-                self.noise.period = NOISE_TIMER[data as usize & 0xF];
+                let periods = if self.region == Region::Pal { &PAL_NOISE_TIMER } else { &NOISE_TIMER };
+                self.noise.period = periods[data as usize & 0xF];
             },
             0x400F => 
             {
@@ -171,158 +423,217 @@ impl APU {
                 self.noise.envelope.restart = true;
             },
             0x4015 => {
-                self.pulse_0.length_counter.enable(data & 0x1 != 0);
-                self.pulse_1.length_counter.enable(data & 0x2 != 0);
-                self.triangle.length_counter.enable(data & 0x3 != 0);
-                self.noise.length_counter.enable(data & 0x4 != 0);
+                self.pulse_0.length_counter.enable(data & 0x01 != 0);
+                self.pulse_1.length_counter.enable(data & 0x02 != 0);
+                self.triangle.length_counter.enable(data & 0x04 != 0);
+                self.noise.length_counter.enable(data & 0x08 != 0);
+                self.dmc.enable(data & 0x10 != 0);
             }
             0x4017 => {
-                self.irq = data & 0x40 == 0;
-                if !self.irq
-                {
-                    self.public_irq = false;
-                    self.private_irq = false;
+                self.irq_inhibit = data & 0x40 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
                 }
-        
+
                 self.counter_mode = if data & 0x80 == 0 {
                     CounterMode::Zero
                 } else {
                     CounterMode::One
                 };
-        
-                self.counter = if self.cycles & 1 == 0 { 0 } else { -1 };
-        
-                let frame = match self.counter_mode {
-                    CounterMode::Zero => Frame::None,
-                    CounterMode::One => Frame::Half,
-                };
-                self.match_frame(frame);
+
+                //The sequencer restarts 3 or 4 CPU cycles after the write depending on alignment
+                self.frame_reset_delay = if self.cycles & 1 == 0 { 3 } else { 4 };
             }
             _ => {},
         }
     }
+    //One CPU cycle. The triangle, noise and DMC timers run at CPU rate, the pulse timers at half
     pub fn clock(&mut self) 
     {
             self.triangle.clock_sequencer();
-            if self.global_time % 2 == 1 
+            self.noise.clock();
+            self.dmc.clock();
+            if self.cycles % 2 == 1 
             {
                 self.pulse_0.sequencer.clock(true);
                 self.pulse_1.sequencer.clock(true);
-                self.noise.clock();
             }
-            let frame = match self.counter_mode {
-                CounterMode::Zero => self.clock_zero(),
-                CounterMode::One => self.clock_one(),
-            };
-            self.counter += 1;
-            self.match_frame(frame);
+            self.clock_frame_counter();
 
             self.pulse_0.length_counter.update();();
             self.pulse_1.length_counter.update();();
             self.triangle.length_counter.update();
             self.noise.length_counter.update();
     
-            if self.global_time % 40 == 0 {
-                let sample = self.sample();
-                self.samples.push(sample);
-                self.samples.push(sample);
+            let level = self.mix_levels(&self.levels());
+            self.resampler.set_level(self.resampler_clock, level);
+            if self.capture_resampler.is_some() {
+                let outputs = self.outputs();
+                let level = self.mix_levels(&outputs);
+                if let Some(ref mut capture) = self.capture_resampler {
+                    capture.set_level(self.resampler_clock, level);
+                }
+                for (i, resampler) in self.channel_resamplers.iter_mut().enumerate() {
+                    let mut solo = [0.0; 5];
+                    solo[i] = outputs[i];
+                    resampler.set_level(self.resampler_clock, APU::mix(&self.pulse_table, &self.tnd_table, &solo));
+                }
             }
-        self.global_time += 1;
+            self.resampler_clock += 1;
+            if self.resampler_clock == FLUSH_CLOCKS {
+                self.resampler.end_frame(FLUSH_CLOCKS, &mut self.samples);
+                if let Some(ref mut capture) = self.capture_resampler {
+                    capture.end_frame(FLUSH_CLOCKS, &mut self.capture_samples);
+                }
+                for (resampler, samples) in self.channel_resamplers.iter_mut().zip(self.channel_samples.iter_mut()) {
+                    resampler.end_frame(FLUSH_CLOCKS, samples);
+                }
+                self.resampler_clock = 0;
+            }
+        self.cycles += 1;
     }
 
+    fn clock_frame_counter(&mut self) {
+        if self.frame_reset_delay > 0 {
+            self.frame_reset_delay -= 1;
+            if self.frame_reset_delay == 0 {
+                self.counter = 0;
+                //Writing with the 5 step mode selected clocks everything immediately
+                if let CounterMode::One = self.counter_mode {
+                    self.match_frame(Frame::Half);
+                }
+                return;
+            }
+        }
+        self.counter += 1;
+        let frame = match self.counter_mode {
+            CounterMode::Zero => self.clock_zero(),
+            CounterMode::One => self.clock_one(),
+        };
+        self.match_frame(frame);
+    }
+
+    fn set_frame_irq(&mut self) {
+        if !self.irq_inhibit {
+            self.frame_irq = true;
+        }
+    }
+
+    //4 step sequence, counted in CPU cycles since the last reset
     fn clock_zero(&mut self) -> Frame {
+        let steps = self.frame_steps();
         match self.counter {
-            7460 => Frame::Quarter,
-            14915 => Frame::Half,
-            22374 => Frame::Quarter,
-            29831 => {
-                if self.irq 
-                {
-                    self.private_irq = true;
-                }
+            c if c == steps[0] || c == steps[2] => Frame::Quarter,
+            c if c == steps[1] => Frame::Half,
+            c if c == steps[3] - 1 => {
+                self.set_frame_irq();
                 Frame::None
             }
-            29832 => {
-                if self.irq 
-                {
-                    self.private_irq = true;
-                    self.public_irq = true;
-                }else
-                {
-                    self.public_irq = false;
-                }
+            c if c == steps[3] => {
+                self.set_frame_irq();
                 Frame::Half
             }
-            29833 => {
-                if self.irq 
-                {
-                    self.private_irq = true;
-                    self.public_irq = true;
-                }else
-                {
-                    self.public_irq = false;
-                }
-                self.counter = 2;
+            c if c == steps[3] + 1 => {
+                self.set_frame_irq();
+                self.counter = 0;
                 Frame::None
             }
             _ => Frame::None,
         }
     }
+
+    //5 step sequence, never raises an IRQ
     fn clock_one(&mut self) -> Frame {
+        let steps = self.frame_steps();
         match self.counter {
-            74560 => Frame::Quarter,
-            14916 => Frame::Half,
-            22374 => Frame::Quarter,
-            37284 => {
-                self.counter = 1;
-                Frame::Half
+            c if c == steps[0] || c == steps[2] => Frame::Quarter,
+            c if c == steps[1] => Frame::Half,
+            c if c == steps[4] => Frame::Half,
+            c if c == steps[4] + 1 => {
+                self.counter = 0;
+                Frame::None
             }
             _ => Frame::None,
         }
     }
 
-    fn match_frame(&mut self, result: Frame) {
-        match result {
-            Frame::Quarter => 
-            {
-                self.pulse_0.envelope.clock();
-                self.pulse_1.envelope.clock();();
-                self.triangle.clock_quarter();
-            }
-            Frame::Half => 
-            {
-                self.pulse_0.envelope.clock();
-                self.pulse_0.length_counter.clock();
-                self.pulse_0.sweeper.clock(&mut self.pulse_0.sequencer);
+    //Envelopes and the triangle's linear counter
+    fn clock_quarter_frame(&mut self) {
+        self.pulse_0.envelope.clock();
+        self.pulse_1.envelope.clock();
+        self.triangle.clock_quarter();
+        self.noise.clock_quarter();
+    }
+
+    //Length counters and sweep units
+    fn clock_half_frame(&mut self) {
+        self.pulse_0.length_counter.clock();
+        self.pulse_0.sweeper.clock(&mut self.pulse_0.sequencer);
 
-                self.pulse_1.envelope.clock();
-                self.pulse_1.length_counter.clock();
-                self.pulse_1.sweeper.clock(&mut self.pulse_0.sequencer);
+        self.pulse_1.length_counter.clock();
+        self.pulse_1.sweeper.clock(&mut self.pulse_1.sequencer);
 
-                self.triangle.clock_quarter();
-                self.triangle.length_counter.clock();
+        self.triangle.length_counter.clock();
+        self.noise.clock_half();
+    }
 
-                self.noise.clock_quarter();
-                self.noise.clock_half();
+    fn match_frame(&mut self, result: Frame) {
+        match result {
+            Frame::Quarter => self.clock_quarter_frame(),
+            Frame::Half => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
             }
             Frame::None => (),
         }
     }
 
-    fn sample(&mut self) -> i16 {
-        let pulse_0 = self.pulse_0.sample() as f64;
-        let pulse_1 = self.pulse_1.sample() as f64;
-        let triangle = self.triangle.sample() as f64;
-        let noise = self.noise.sample() as f64;
+    fn channel_level(&self, channel: Channel, level: f32) -> f32 {
+        let mix = self.mix[channel as usize];
+        if mix.enabled {
+            level * mix.gain
+        } else {
+            0.0
+        }
+    }
+
+    //Linear interpolation so gains other than 1.0 still land between table entries
+    fn lookup(table: &[f32], index: f32) -> f32 {
+        let index = index.max(0.0).min((table.len() - 1) as f32);
+        let low = index as usize;
+        let high = (low + 1).min(table.len() - 1);
+        let fraction = index - low as f32;
+        table[low] + (table[high] - table[low]) * fraction
+    }
 
-        let pulse_out = 95.88 / ((8218.0 / (pulse_0 + pulse_1)) + 100.0);
-        let tnd_out = 159.79 / ((1.0 / (triangle / 8227.0 + noise / 12241.0 + 0.0 / 22638.0)) + 100.0);
-       //let tnd_out = 159.79 / ((1.0 / (0.0 / 8227.0 + 0.0 / 12241.0 + dmc / 22638.0)) + 100.0);
-        let mut output = (pulse_out + tnd_out) * 65535.0;
+    //Channel outputs before enable and gain, in CHANNELS order
+    fn outputs(&self) -> [f32; 5] {
+        [
+            self.pulse_0.sample() as f32,
+            self.pulse_1.sample() as f32,
+            self.triangle.sample() as f32,
+            self.noise.sample() as f32,
+            self.dmc.sample() as f32,
+        ]
+    }
 
+    //Channel outputs after enable and gain, in CHANNELS order
+    fn levels(&self) -> [f32; 5] {
+        let mut levels = self.outputs();
+        for (level, &channel) in levels.iter_mut().zip(CHANNELS.iter()) {
+            *level = self.channel_level(channel, *level);
+        }
+        levels
+    }
 
+    fn mix(pulse_table: &[f32], tnd_table: &[f32], levels: &[f32; 5]) -> f32 {
+        let pulse_out = APU::lookup(pulse_table, levels[0] + levels[1]);
+        let tnd_out = APU::lookup(tnd_table, 3.0 * levels[2] + 2.0 * levels[3] + levels[4]);
+        return pulse_out + tnd_out;
+    }
 
-        return output as i16;
+    fn mix_levels(&self, levels: &[f32; 5]) -> f32 {
+        APU::mix(&self.pulse_table, &self.tnd_table, levels)
     }
 }
 
@@ -345,18 +656,10 @@ impl PULSE {
         }
     }
 
-    #[allow(unused_assignments)]
     pub fn sample(&self) -> u8 {
-        let mut period = 0;
-        if self.sweeper.negate == false
-        {
-            period = self.sequencer.decay + (self.sequencer.decay >> self.sweeper.shift)
-        } else 
-        {
-            period = self.sequencer.decay - (self.sequencer.decay >> self.sweeper.shift) - self.sweeper.negation_mode as u16
-        }
-
-        if (self.length_counter.enabled && self.length_counter.counter > 0) && self.sequencer.decay >= 8 && period < 0x800
+        //Muted by the sweep unit even when sweeping is disabled
+        let muted = self.sequencer.decay < 8 || self.sweeper.target_period(self.sequencer.decay) > 0x7FF;
+        if (self.length_counter.enabled && self.length_counter.counter > 0) && !muted
         {
             return WAVEFORMS[self.duty_cycle][self.sequencer.current_step] * self.envelope.volume();
         } else {
@@ -420,22 +723,21 @@ impl SWEEPER {
         }
     }
 
-    #[allow(unused_assignments)]
+    //Pulse 1 negates with ones' complement (negation_mode), pulse 2 with two's complement
+    pub fn target_period(&self, period: u16) -> u16 {
+        let change = period >> self.shift;
+        if self.negate {
+            period.saturating_sub(change + self.negation_mode as u16)
+        } else {
+            period + change
+        }
+    }
+
     pub fn clock(&mut self, sequencer: &mut SEQUENCER) {
-        if self.counter == 0 && self.enabled && self.shift > 0 && sequencer.decay >= 8 
+        let target = self.target_period(sequencer.decay);
+        if self.counter == 0 && self.enabled && self.shift > 0 && sequencer.decay >= 8 && target < 0x800
         {
-            let mut period = 0;
-            if self.negate == false
-            {
-                period = sequencer.decay + (sequencer.decay >> self.shift)
-            } else 
-            {
-                period = sequencer.decay - (sequencer.decay >> self.shift) - self.negation_mode as u16
-            }
-            if period < 0x800 {
-                sequencer.decay = period;
-                sequencer.decay = period;
-            }
+            sequencer.decay = target;
         }
         if self.counter == 0 || self.reload 
         {
@@ -577,18 +879,23 @@ impl TRIANGLE {
         }
     }
 
+    //A stopped triangle holds its last step rather than dropping to zero. Running with a period
+    //under 2 is ultrasonic, that averages out to the middle of the sequence.
     pub fn sample(&self) -> u8 {
-        let active = (self.length_counter.enabled && self.length_counter.counter > 0) && self.linear_counter > 0;
-        if active && self.sequencer.decay > 2 
+        if self.sequencer.decay < 2 && self.active()
         {
-            return TRIANGLE_SEQUENCE[self.sequencer.current_step];
+            return 7;
         } else {
-            return 0;
+            return TRIANGLE_SEQUENCE[self.sequencer.current_step];
         }
     }
 
+    fn active(&self) -> bool {
+        return (self.length_counter.enabled && self.length_counter.counter > 0) && self.linear_counter > 0;
+    }
+
     pub fn clock_sequencer(&mut self) {
-        let active = (self.length_counter.enabled && self.length_counter.counter > 0) && self.linear_counter > 0;
+        let active = self.active();
         self.sequencer.clock(active);
     }
 
@@ -620,7 +927,7 @@ impl NOISE {
             envelope: ENVELOPE::new(),
             length_counter: LENGTH_COUNTER::new(),
             mode: false,
-            period: 0,
+            period: NOISE_TIMER[0],
             counter: 0,
             shift: 1,
         }
@@ -639,7 +946,7 @@ impl NOISE {
         if self.counter > 0 {
             self.counter -= 1;
         } else {
-            self.counter = self.period;
+            self.counter = self.period - 1;
             let bit1 = (self.shift >> (if self.mode { 6 } else { 1 })) & 1;
             let bit2 = self.shift & 1;
             self.shift = (self.shift >> 1) | (bit1 ^ bit2) << 14
@@ -655,7 +962,368 @@ impl NOISE {
     }
 }
 
+//https://wiki.nesdev.com/w/index.php/APU_DMC
+pub struct DMC {
+    irq_enabled: bool,
+    pub irq: bool,
+    loop_flag: bool,
+    period: u16,
+    timer: u16,
+
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+    output_level: u8,
+}
+
+impl DMC {
+    pub fn new() -> DMC {
+        DMC {
+            irq_enabled: false,
+            irq: false,
+            loop_flag: false,
+            period: PERIODS[0] as u16 * 2,
+            timer: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+            output_level: 0,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn enable(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    //Memory reader, the bus supplies the byte at dmc_fetch_address
+    pub fn fill(&mut self, data: u8) {
+        self.buffer = Some(data);
+        self.current_address = if self.current_address == 0xFFFF { 0x8000 } else { self.current_address + 1 };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    //Output unit
+    pub fn clock(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period - 1;
+
+        if !self.silence {
+            if self.shift & 1 == 1 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift = data;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub fn sample(&self) -> u8 {
+        return self.output_level;
+    }
+}
+
+#[cfg(test)]
+impl APU {
+    //The mixed level right now, without going through the resampler
+    fn sample(&self) -> f32 {
+        return self.mix_levels(&self.levels());
+    }
+}
+
+#[test]
+fn test_mixer_lookup() {
+    let mut apu = APU::new();
+    apu.solo(Channel::Dmc);
+    assert_eq!(apu.sample(), 0.0);
+
+    apu.cpu_write(0x4011, 0x7F);
+    let full = apu.sample();
+    assert!((full - 163.67 / (24329.0 / 127.0 + 100.0)).abs() < 1e-6);
+
+    apu.set_channel_gain(Channel::Dmc, 0.5);
+    let half = apu.sample();
+    assert!(half < full && half > 0.0);
+
+    apu.set_channel_enabled(Channel::Dmc, false);
+    assert_eq!(apu.sample(), 0.0);
+}
+
+#[test]
+fn test_solo() {
+    let mut apu = APU::new();
+    apu.solo(Channel::Triangle);
+    for &c in CHANNELS.iter() {
+        assert_eq!(apu.channel(c).enabled, c == Channel::Triangle);
+    }
+    apu.solo(Channel::Triangle);
+    assert!(CHANNELS.iter().all(|&c| apu.channel(c).enabled));
+}
 
+#[test]
+fn test_status_enable_bits() {
+    let mut apu = APU::new();
+    apu.cpu_write(0x4015, 0x0F);
+    for &address in [0x4003, 0x4007, 0x400B, 0x400F].iter() {
+        apu.cpu_write(address, 0x08);
+    }
+    apu.clock();
+    assert_eq!(apu.cpu_read(0x4015) & 0x0F, 0x0F);
+
+    //Bit 2 alone is the triangle, bit 3 the noise
+    apu.cpu_write(0x4015, 0x04);
+    assert_eq!(apu.cpu_read(0x4015) & 0x0F, 0x04);
+    apu.cpu_write(0x4015, 0x00);
+    assert_eq!(apu.cpu_read(0x4015) & 0x0F, 0x00);
+}
 
+#[test]
+fn test_triangle_halt_is_bit_7() {
+    let mut apu = APU::new();
+    apu.cpu_write(0x4015, 0x04);
+    apu.cpu_write(0x4008, 0x80);
+    apu.cpu_write(0x400B, 0x08);
+    apu.clock();
+    apu.clock_half_frame();
+    assert_eq!(apu.triangle.length_counter.counter, 254);
+
+    apu.cpu_write(0x4008, 0x20);
+    apu.clock();
+    apu.clock_half_frame();
+    assert_eq!(apu.triangle.length_counter.counter, 253);
+}
 
+#[test]
+fn test_sweep_uses_own_timer() {
+    let mut apu = APU::new();
+    apu.cpu_write(0x4002, 0x00);
+    apu.cpu_write(0x4003, 0x01);
+    apu.cpu_write(0x4006, 0x00);
+    apu.cpu_write(0x4007, 0x01);
+    //Both negate with a shift of 1, pulse 1 subtracts an extra one
+    apu.cpu_write(0x4001, 0x89);
+    apu.cpu_write(0x4005, 0x89);
+    apu.clock_half_frame();
+    assert_eq!(apu.pulse_0.sequencer.decay, 0x100 - 0x80 - 1);
+    assert_eq!(apu.pulse_1.sequencer.decay, 0x100 - 0x80);
+
+    apu.cpu_write(0x4005, 0x81);
+    apu.cpu_write(0x4006, 0x00);
+    apu.cpu_write(0x4007, 0x01);
+    apu.clock_half_frame();
+    assert_eq!(apu.pulse_1.sequencer.decay, 0x180);
+    assert_eq!(apu.pulse_0.sequencer.decay, 0x7F - (0x7F >> 1) - 1);
+}
 
+#[test]
+fn test_sweep_mutes_overflowing_target() {
+    let mut apu = APU::new();
+    apu.cpu_write(0x4015, 0x01);
+    apu.cpu_write(0x4000, 0x3F);
+    apu.cpu_write(0x4002, 0xFF);
+    apu.cpu_write(0x4003, 0x03);
+    apu.clock();
+    apu.pulse_0.sequencer.current_step = 1;
+    assert_eq!(apu.pulse_0.sample(), 15);
+
+    //Shift 0 targets twice the period, muted once that leaves 11 bits even with the sweep disabled
+    apu.cpu_write(0x4002, 0x00);
+    apu.cpu_write(0x4003, 0x04);
+    apu.clock();
+    apu.pulse_0.sequencer.current_step = 1;
+    assert_eq!(apu.pulse_0.sample(), 0);
+
+    apu.cpu_write(0x4002, 0x07);
+    apu.cpu_write(0x4003, 0x00);
+    apu.pulse_0.sequencer.current_step = 1;
+    assert_eq!(apu.pulse_0.sample(), 0);
+}
+
+#[test]
+fn test_frame_counter_4_step_irq() {
+    let mut apu = APU::new();
+    let mut cycles = 0;
+    while !apu.frame_irq {
+        apu.clock();
+        cycles += 1;
+    }
+    assert_eq!(cycles, 29828);
+    assert!(apu.irq_asserted());
+    assert_eq!(apu.cpu_read(0x4015) & 0x40, 0x40);
+    assert_eq!(apu.cpu_read(0x4015) & 0x40, 0x00);
+
+    apu.cpu_write(0x4017, 0x40);
+    for _ in 0..29830 * 2 {
+        apu.clock();
+    }
+    assert!(!apu.irq_asserted());
+}
+
+#[test]
+fn test_frame_counter_5_step() {
+    let mut apu = APU::new();
+    apu.cpu_write(0x4015, 0x01);
+    apu.cpu_write(0x4003, 0x08);
+    apu.clock();
+    assert_eq!(apu.pulse_0.length_counter.counter, 254);
+
+    //Clocked once straight away, then at 14913 and 37281
+    apu.cpu_write(0x4017, 0x80);
+    for _ in 0..4 {
+        apu.clock();
+    }
+    assert_eq!(apu.pulse_0.length_counter.counter, 253);
+    for _ in 0..37282 {
+        apu.clock();
+    }
+    assert_eq!(apu.pulse_0.length_counter.counter, 251);
+    assert!(!apu.frame_irq);
+}
+
+#[test]
+fn test_dmc_playback() {
+    let mut apu = APU::new();
+    apu.cpu_write(0x4010, 0x8F);
+    apu.cpu_write(0x4011, 0x40);
+    apu.cpu_write(0x4012, 0x01);
+    apu.cpu_write(0x4013, 0x00);
+    assert_eq!(apu.dmc_fetch_address(), None);
+
+    apu.cpu_write(0x4015, 0x10);
+    assert_eq!(apu.cpu_read(0x4015) & 0x10, 0x10);
+    assert_eq!(apu.dmc_fetch_address(), Some(0xC040));
+    apu.dmc_fill(0xFF);
+    assert_eq!(apu.dmc_fetch_address(), None);
+    assert_eq!(apu.cpu_read(0x4015) & 0x90, 0x80);
+    assert!(apu.irq_asserted());
+
+    //8 silent bits still in the shift register, then eight 1 bits each add 2
+    for _ in 0..54 * 30 {
+        apu.clock();
+    }
+    assert_eq!(apu.dmc.sample(), 0x40 + 16);
+}
+
+#[test]
+fn test_noise_period() {
+    let mut apu = APU::new();
+    apu.cpu_write(0x400E, 0x00);
+    for _ in 0..40 {
+        apu.clock();
+    }
+
+    let mut shift: u16 = 1;
+    for _ in 0..10 {
+        let feedback = (shift & 1) ^ ((shift >> 1) & 1);
+        shift = (shift >> 1) | (feedback << 14);
+    }
+    assert_eq!(apu.noise.shift, shift);
+}
+
+#[test]
+fn test_pal_timing() {
+    let mut apu = APU::new();
+    apu.set_region(Region::Pal);
+    let mut cycles = 0;
+    while !apu.frame_irq {
+        apu.clock();
+        cycles += 1;
+    }
+    assert_eq!(cycles, 33252);
+
+    apu.cpu_write(0x400E, 0x0F);
+    apu.cpu_write(0x4010, 0x0F);
+    assert_eq!(apu.noise.period, 3778);
+    assert_eq!(apu.dmc.period, 50);
+}
+
+#[test]
+fn test_silent_triangle_holds_step() {
+    let mut apu = APU::new();
+    assert_ne!(apu.triangle.sample(), 7);
+
+    //Running with an ultrasonic period averages out to the middle of the sequence
+    apu.cpu_write(0x4015, 0x04);
+    apu.cpu_write(0x4008, 0x7F);
+    apu.cpu_write(0x400A, 0x01);
+    apu.cpu_write(0x400B, 0x08);
+    apu.clock();
+    apu.clock_quarter_frame();
+    assert!(apu.triangle.active());
+    assert_eq!(apu.triangle.sample(), 7);
+
+    //Halted it holds whatever step it stopped on
+    apu.cpu_write(0x4015, 0x00);
+    assert!(!apu.triangle.active());
+    assert_eq!(apu.triangle.sample(), TRIANGLE_SEQUENCE[apu.triangle.sequencer.current_step]);
+}
+
+#[test]
+fn test_capture_ignores_mixer_and_rate_control() {
+    let mut apu = APU::new();
+    apu.set_channel_capture(true);
+    //Pulse 1 at full volume, everything muted for listening and the host queue running dry
+    apu.cpu_write(0x4015, 0x01);
+    apu.cpu_write(0x4000, 0xBF);
+    apu.cpu_write(0x4002, 0xFD);
+    apu.cpu_write(0x4003, 0x00);
+    for &channel in CHANNELS.iter() {
+        apu.set_channel_enabled(channel, false);
+    }
+    apu.set_rate_control(0.0);
+    let clocks = 10 * 29781;
+    for _ in 0..clocks {
+        apu.clock();
+    }
+    assert!(apu.samples.iter().all(|&s| s == 0));
+    assert!(apu.capture_samples.iter().any(|&s| s != 0));
+
+    //Captures run at their own rate and every channel file keeps in step with the mix
+    let expected = (clocks as f64 / FLUSH_CLOCKS as f64).floor() * FLUSH_CLOCKS as f64 * CAPTURE_SAMPLE_RATE / NTSC_CLOCK_RATE;
+    assert!((apu.capture_samples.len() as f64 - expected).abs() <= 1.0);
+    assert!(apu.channel_samples.iter().all(|samples| samples.len() == apu.capture_samples.len()));
+}
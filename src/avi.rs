@@ -0,0 +1,299 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::path::Path;
+
+//https://docs.microsoft.com/en-us/windows/win32/directshow/avi-riff-file-reference
+//Plain AVI 1.0 with one uncompressed video stream and one 16 bit PCM stream. The RIFF sizes are
+//32 bit so callers should start a new file well before 4GB, see MAX_SIZE.
+pub const MAX_SIZE: u64 = 1 << 30;
+
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+struct IndexEntry {
+    id: [u8; 4],
+    offset: u32,
+    size: u32,
+}
+
+pub struct AviWriter {
+    file: BufWriter<File>,
+    width: usize,
+    height: usize,
+    frames: u32,
+    audio_samples: u32,
+    index: Vec<IndexEntry>,
+    //Positions of the fields patched in by finish
+    total_frames_at: u64,
+    video_length_at: u64,
+    audio_length_at: u64,
+    movi_at: u64,
+    size: u64,
+    finished: bool,
+}
+
+fn fourcc(file: &mut BufWriter<File>, id: &[u8; 4]) -> io::Result<()> {
+    file.write_all(id)
+}
+
+fn u32le(file: &mut BufWriter<File>, value: u32) -> io::Result<()> {
+    file.write_all(&value.to_le_bytes())
+}
+
+fn u16le(file: &mut BufWriter<File>, value: u16) -> io::Result<()> {
+    file.write_all(&value.to_le_bytes())
+}
+
+impl AviWriter {
+    //Frame rate as a fraction, rate / scale frames per second
+    pub fn create(path: &Path, width: usize, height: usize, rate: u32, scale: u32, sample_rate: u32) -> io::Result<AviWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let frame_size = (Self::stride(width) * height) as u32;
+
+        fourcc(&mut file, b"RIFF")?;
+        u32le(&mut file, 0)?;
+        fourcc(&mut file, b"AVI ")?;
+
+        //4 + avih (8 + 56) + video strl (12 + 64 + 48) + audio strl (12 + 64 + 26)
+        fourcc(&mut file, b"LIST")?;
+        u32le(&mut file, 4 + 64 + 124 + 102)?;
+        fourcc(&mut file, b"hdrl")?;
+
+        fourcc(&mut file, b"avih")?;
+        u32le(&mut file, 56)?;
+        u32le(&mut file, (1_000_000u64 * scale as u64 / rate as u64) as u32)?;
+        u32le(&mut file, frame_size * (rate / scale + 1) + sample_rate * 2)?;
+        u32le(&mut file, 0)?;
+        u32le(&mut file, AVIF_HASINDEX)?;
+        let total_frames_at = file.seek(SeekFrom::Current(0))?;
+        u32le(&mut file, 0)?;
+        u32le(&mut file, 0)?;
+        u32le(&mut file, 2)?;
+        u32le(&mut file, frame_size)?;
+        u32le(&mut file, width as u32)?;
+        u32le(&mut file, height as u32)?;
+        for _ in 0..4 {
+            u32le(&mut file, 0)?;
+        }
+
+        fourcc(&mut file, b"LIST")?;
+        u32le(&mut file, 4 + 64 + 48)?;
+        fourcc(&mut file, b"strl")?;
+        fourcc(&mut file, b"strh")?;
+        u32le(&mut file, 56)?;
+        fourcc(&mut file, b"vids")?;
+        fourcc(&mut file, b"DIB ")?;
+        u32le(&mut file, 0)?; //Flags
+        u32le(&mut file, 0)?; //Priority and language
+        u32le(&mut file, 0)?; //Initial frames
+        u32le(&mut file, scale)?;
+        u32le(&mut file, rate)?;
+        u32le(&mut file, 0)?; //Start
+        let video_length_at = file.seek(SeekFrom::Current(0))?;
+        u32le(&mut file, 0)?;
+        u32le(&mut file, frame_size)?;
+        u32le(&mut file, u32::MAX)?; //Default quality
+        u32le(&mut file, 0)?; //Sample size, varies
+        u16le(&mut file, 0)?;
+        u16le(&mut file, 0)?;
+        u16le(&mut file, width as u16)?;
+        u16le(&mut file, height as u16)?;
+        //BITMAPINFOHEADER, positive height for bottom up rows
+        fourcc(&mut file, b"strf")?;
+        u32le(&mut file, 40)?;
+        u32le(&mut file, 40)?;
+        u32le(&mut file, width as u32)?;
+        u32le(&mut file, height as u32)?;
+        u16le(&mut file, 1)?;
+        u16le(&mut file, 24)?;
+        u32le(&mut file, 0)?; //BI_RGB
+        u32le(&mut file, frame_size)?;
+        for _ in 0..4 {
+            u32le(&mut file, 0)?;
+        }
+
+        fourcc(&mut file, b"LIST")?;
+        u32le(&mut file, 4 + 64 + 26)?;
+        fourcc(&mut file, b"strl")?;
+        fourcc(&mut file, b"strh")?;
+        u32le(&mut file, 56)?;
+        fourcc(&mut file, b"auds")?;
+        u32le(&mut file, 0)?;
+        u32le(&mut file, 0)?;
+        u32le(&mut file, 0)?;
+        u32le(&mut file, 0)?;
+        u32le(&mut file, 1)?; //Scale, one sample
+        u32le(&mut file, sample_rate)?;
+        u32le(&mut file, 0)?;
+        let audio_length_at = file.seek(SeekFrom::Current(0))?;
+        u32le(&mut file, 0)?;
+        u32le(&mut file, sample_rate * 2)?;
+        u32le(&mut file, u32::MAX)?;
+        u32le(&mut file, 2)?; //Block align
+        for _ in 0..4 {
+            u16le(&mut file, 0)?;
+        }
+        //WAVEFORMATEX, mono 16 bit PCM
+        fourcc(&mut file, b"strf")?;
+        u32le(&mut file, 18)?;
+        u16le(&mut file, 1)?;
+        u16le(&mut file, 1)?;
+        u32le(&mut file, sample_rate)?;
+        u32le(&mut file, sample_rate * 2)?;
+        u16le(&mut file, 2)?;
+        u16le(&mut file, 16)?;
+        u16le(&mut file, 0)?;
+
+        fourcc(&mut file, b"LIST")?;
+        u32le(&mut file, 0)?;
+        let movi_at = file.seek(SeekFrom::Current(0))?;
+        fourcc(&mut file, b"movi")?;
+        let size = movi_at + 4;
+
+        Ok(AviWriter {
+            file,
+            width,
+            height,
+            frames: 0,
+            audio_samples: 0,
+            index: Vec::new(),
+            total_frames_at,
+            video_length_at,
+            audio_length_at,
+            movi_at,
+            size,
+            finished: false,
+        })
+    }
+
+    //Rows are padded to 4 bytes
+    fn stride(width: usize) -> usize {
+        (width * 3 + 3) & !3
+    }
+
+    //Bytes written so far
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn chunk(&mut self, id: &[u8; 4], data: &[u8]) -> io::Result<()> {
+        self.index.push(IndexEntry {
+            id: *id,
+            offset: (self.size - self.movi_at) as u32,
+            size: data.len() as u32,
+        });
+        fourcc(&mut self.file, id)?;
+        u32le(&mut self.file, data.len() as u32)?;
+        self.file.write_all(data)?;
+        self.size += 8 + data.len() as u64;
+        if data.len() % 2 == 1 {
+            self.file.write_all(&[0])?;
+            self.size += 1;
+        }
+        Ok(())
+    }
+
+    //Top down RGB24 in, bottom up BGR out
+    pub fn write_frame(&mut self, rgb: &[u8]) -> io::Result<()> {
+        let stride = Self::stride(self.width);
+        let mut data = vec![0; stride * self.height];
+        for y in 0..self.height {
+            let source = &rgb[y * self.width * 3..(y + 1) * self.width * 3];
+            let row = &mut data[(self.height - 1 - y) * stride..];
+            for (out, pixel) in row.chunks_mut(3).zip(source.chunks(3)) {
+                out[0] = pixel[2];
+                out[1] = pixel[1];
+                out[2] = pixel[0];
+            }
+        }
+        self.chunk(b"00db", &data)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        let data: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes().to_vec()).collect();
+        self.chunk(b"01wb", &data)?;
+        self.audio_samples += samples.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        let movi_size = (self.size - self.movi_at) as u32;
+
+        fourcc(&mut self.file, b"idx1")?;
+        u32le(&mut self.file, self.index.len() as u32 * 16)?;
+        for entry in self.index.iter() {
+            self.file.write_all(&entry.id)?;
+            self.file.write_all(&AVIIF_KEYFRAME.to_le_bytes())?;
+            self.file.write_all(&entry.offset.to_le_bytes())?;
+            self.file.write_all(&entry.size.to_le_bytes())?;
+        }
+        let end = self.size + 8 + self.index.len() as u64 * 16;
+
+        self.file.seek(SeekFrom::Start(4))?;
+        u32le(&mut self.file, (end - 8) as u32)?;
+        self.file.seek(SeekFrom::Start(self.movi_at - 4))?;
+        u32le(&mut self.file, movi_size)?;
+        self.file.seek(SeekFrom::Start(self.total_frames_at))?;
+        u32le(&mut self.file, self.frames)?;
+        self.file.seek(SeekFrom::Start(self.video_length_at))?;
+        u32le(&mut self.file, self.frames)?;
+        self.file.seek(SeekFrom::Start(self.audio_length_at))?;
+        u32le(&mut self.file, self.audio_samples)?;
+        self.file.flush()
+    }
+}
+
+impl Drop for AviWriter {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+#[test]
+fn test_avi_layout() {
+    let path = std::env::temp_dir().join("nes_test_avi_layout.avi");
+    {
+        let mut writer = AviWriter::create(&path, 2, 2, 60, 1, 44100).unwrap();
+        writer.write_frame(&[255, 0, 0, 0, 255, 0, 0, 0, 255, 1, 2, 3]).unwrap();
+        writer.write_samples(&[1, -1, 3]).unwrap();
+        writer.write_frame(&[0; 12]).unwrap();
+    }
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let u32_at = |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+    let find = |id: &[u8]| bytes.windows(4).position(|w| w == id).unwrap();
+
+    assert_eq!(&bytes[0..4], b"RIFF");
+    assert_eq!(u32_at(4) as usize, bytes.len() - 8);
+    assert_eq!(&bytes[8..12], b"AVI ");
+    //hdrl LIST size covers everything up to the movi LIST
+    assert_eq!(12 + 8 + u32_at(16) as usize, find(b"movi") - 8);
+
+    let avih = find(b"avih");
+    assert_eq!(u32_at(avih + 8), 16666);
+    assert_eq!(u32_at(avih + 8 + 16), 2);
+
+    //First frame, bottom row first in BGR, rows padded to 8 bytes
+    let frame = find(b"00db");
+    assert_eq!(u32_at(frame + 4), 16);
+    assert_eq!(&bytes[frame + 8..frame + 14], &[255, 0, 0, 3, 2, 1]);
+    assert_eq!(&bytes[frame + 16..frame + 22], &[0, 0, 255, 0, 255, 0]);
+
+    let audio = find(b"01wb");
+    assert_eq!(u32_at(audio + 4), 6);
+    let index = find(b"idx1");
+    assert_eq!(u32_at(index + 4), 3 * 16);
+    assert_eq!(&bytes[index + 8..index + 12], b"00db");
+    assert_eq!(u32_at(index + 16) as usize, frame - find(b"movi"));
+}
@@ -1,6 +1,9 @@
 use crate::apu::APU;
 use crate::cartridge::Cartridge;
+use crate::debugger::{Access, Debugger, Space};
+use crate::power::RamInit;
 use crate::ppu::PPU;
+use crate::region::Region;
 use std::cell::RefCell;
 use std::rc::Rc;
 pub struct Bus {
@@ -20,6 +23,12 @@ pub struct Bus {
   pub dma_data: u8,
   pub dma_transfer: bool,
   pub dma_buffer: bool,
+  //Contents of memory after a power cycle
+  pub ram_init: RamInit,
+  pub debugger: Debugger,
+  region: Region,
+  //PPU dots owed to the PPU in fifths, 3 or 3.2 are run per CPU cycle
+  ppu_dot_fifths: u32,
 }
 
 impl Bus {
@@ -41,12 +50,34 @@ impl Bus {
       dma_data: 0x00,
       dma_transfer: false,
       dma_buffer: true,
+      ram_init: RamInit::Zero,
+      debugger: Debugger::new(),
+      region: Region::Ntsc,
+      ppu_dot_fifths: 0,
     }
   }
 
+  pub fn region(&self) -> Region {
+    return self.region;
+  }
+
+  pub fn set_region(&mut self, region: Region) {
+    self.region = region;
+    self.ppu.set_region(region);
+    self.apu.set_region(region);
+    self.ppu_dot_fifths = 0;
+  }
+
   //Function to write to RAM
   #[allow(unused_comparisons)]
   pub fn cpu_write(&mut self, address: u16, data: &mut u8) {
+    if self.debugger.watching() {
+      self.debugger.access(Space::Cpu, address, Access::WRITE, *data);
+      if address >= 0x2000 && address <= 0x3FFF && address & 0x0007 == 0x0007 {
+        let vram_address = self.ppu.scroll_registers().0 & 0x3FFF;
+        self.debugger.access(Space::Ppu, vram_address, Access::WRITE, *data);
+      }
+    }
     if let Some(ref c) = self.cartridge 
     {
       if c.borrow_mut().cpu_write(address, data) == true
@@ -59,6 +90,10 @@ impl Bus {
       } else if address >= 0x2000 && address <= 0x3FFF 
       {
         self.ppu.cpu_write(address & 0x0007, *data);
+        if self.ppu.nmi_suppressed {
+          self.ppu.nmi_suppressed = false;
+          self.nmi_required = false;
+        }
       } else if (address >= 0x4000 && address <= 0x4013) || address == 0x4015 || address == 0x4017 
       {
         self.apu.cpu_write(address, *data);
@@ -77,8 +112,20 @@ impl Bus {
   }
 
   //Function to read from RAM
-  #[allow(unused_comparisons)]
   pub fn cpu_read(&mut self, address: u16, read_only: bool) -> u8 {
+    if read_only {
+      return self.peek_cpu(address);
+    }
+    return self.read(address, true);
+  }
+
+  //DMC and OAM DMA fetches act like any other read but no instruction made them, so watchpoints ignore them
+  pub fn dma_read(&mut self, address: u16) -> u8 {
+    return self.read(address, false);
+  }
+
+  #[allow(unused_comparisons)]
+  fn read(&mut self, address: u16, watch: bool) -> u8 {
     let mut data: u8 = 0x00;
 
     if let Some(ref c) = self.cartridge {
@@ -92,29 +139,89 @@ impl Bus {
       } 
       else if address >= 0x2000 && address <= 0x3FFF 
       {
-        data = self.ppu.cpu_read(address & 0x0007, read_only);
+        let vram_address = self.ppu.scroll_registers().0 & 0x3FFF;
+        data = self.ppu.cpu_read(address & 0x0007, false);
+        if watch && address & 0x0007 == 0x0007 && self.debugger.watching() {
+          self.debugger.access(Space::Ppu, vram_address, Access::READ, data);
+        }
+        //A $2002 read racing the start of vblank can cancel an NMI the PPU already raised
+        if self.ppu.nmi_suppressed {
+          self.ppu.nmi_suppressed = false;
+          self.nmi_required = false;
+        }
+      } else if address == 0x4015 
+      {
+        data = self.apu.cpu_read(address);
       } else if address >= 0x4016 && address <= 0x4017 
       {
-        data = ((self.controller_state[0] & 0x80) > 0) as u8;
+        data = self.peek_controller();
         self.controller_state[0] <<= 1;
       }
     }else
     {
       data = self.ram[(address & 0x07FF) as usize];
     }
+    if watch && self.debugger.watching() {
+      self.debugger.access(Space::Cpu, address, Access::READ, data);
+    }
     return data;
   }
 
+  //Next bit the controller will shift out
+  fn peek_controller(&self) -> u8 {
+    return ((self.controller_state[0] & 0x80) > 0) as u8;
+  }
+
+  //What cpu_read would return, without any side effects, for debuggers, memory viewers and cheat search
+  #[allow(unused_comparisons)]
+  pub fn peek_cpu(&self, address: u16) -> u8 {
+    if let Some(ref c) = self.cartridge {
+      let mut data: u8 = 0x00;
+      if c.borrow().peek_cpu(address, &mut data) {
+        return data;
+      }
+      if address >= 0x0000 && address <= 0x1FFF {
+        return self.ram[(address & 0x07FF) as usize];
+      } else if address >= 0x2000 && address <= 0x3FFF {
+        return self.ppu.peek_cpu(address & 0x0007);
+      } else if address == 0x4015 {
+        return self.apu.peek_cpu(address);
+      } else if address >= 0x4016 && address <= 0x4017 {
+        return self.peek_controller();
+      }
+      return 0x00;
+    }
+    return self.ram[(address & 0x07FF) as usize];
+  }
+
+  //PPU address space, without moving v or disturbing the mapper
+  pub fn peek_ppu(&self, address: u16) -> u8 {
+    return self.ppu.peek_ppu(address);
+  }
+
+  pub fn prg_offset(&self, address: u16) -> Option<usize> {
+    return self.cartridge.as_ref().and_then(|c| c.borrow().prg_offset(address));
+  }
+
   pub fn connect_cartridge(&mut self, cartridge: Rc<RefCell<Cartridge>>) {
     self.cartridge = Some(cartridge.clone());
     self.ppu.connect_cartridge(cartridge.clone());
   }
   pub fn clock(&mut self) {
-    self.ppu.clock();
-    self.ppu.clock();
-    self.ppu.clock();
+    self.ppu_dot_fifths += self.region.ppu_dots_per_5_cycles();
+    while self.ppu_dot_fifths >= 5 {
+      self.ppu.clock();
+      self.ppu_dot_fifths -= 5;
+    }
 
     self.apu.clock();
+    if let Some(address) = self.apu.dmc_fetch_address() {
+      let data = self.dma_read(address);
+      self.apu.dmc_fill(data);
+    }
+    //IRQ is level triggered and shared, the line stays asserted until every source is acknowledged
+    let cartridge_irq = self.cartridge.as_ref().map_or(false, |c| c.borrow().irq_asserted());
+    self.irq_required = self.apu.irq_asserted() || cartridge_irq;
     
     if self.ppu.nmi_enabled {
       self.ppu.nmi_enabled = false;
@@ -122,9 +229,33 @@ impl Bus {
     }
   }
 
+  //Power cycle, memory is refilled with ram_init, unlike reset which leaves it alone
+  pub fn power_on(&mut self) {
+    let mut filler = self.ram_init.filler();
+    filler.fill(&mut self.ram);
+    self.ppu.power_on(&mut filler);
+    self.apu.power_on();
+    self.dma_transfer = false;
+    self.dma_buffer = true;
+    self.nmi_required = false;
+    self.irq_required = false;
+    self.system_clock = 0;
+
+    if let Some(ref c) = self.cartridge 
+    {
+      c.borrow_mut().reset();
+    }
+  }
+
   pub fn reset(&mut self) {
     self.system_clock = 0;
     self.ppu.reset();
+    self.apu.reset();
+    //Anything pending before the button was pressed is dropped
+    self.dma_transfer = false;
+    self.dma_buffer = true;
+    self.nmi_required = false;
+    self.irq_required = false;
 
     if let Some(ref c) = self.cartridge 
     {
@@ -132,3 +263,78 @@ impl Bus {
     }
   }
 }
+
+#[test]
+fn test_pal_clock_ratio() {
+  let mut bus = Bus::new();
+  bus.set_region(Region::Pal);
+  for _ in 0..5 {
+    bus.clock();
+  }
+  assert_eq!(bus.ppu.position(), (0, 16));
+}
+
+#[test]
+fn test_peek_has_no_side_effects() {
+  let mut bus = Bus::new();
+  bus.connect_cartridge(Rc::new(RefCell::new(Cartridge::new("src/test/nestest.nes".to_string()).unwrap())));
+  for (address, data) in [(0x2006, 0x21), (0x2006, 0x00), (0x2007, 0x42), (0x2006, 0x21), (0x2006, 0x00)] {
+    bus.cpu_write(address, &mut (data as u8));
+  }
+  let v = bus.ppu.scroll_registers().0;
+  assert_eq!(bus.peek_cpu(0x2007), bus.peek_cpu(0x2007));
+  assert_eq!(bus.ppu.scroll_registers().0, v);
+  assert_eq!(bus.peek_ppu(0x2100), 0x42);
+
+  while bus.peek_cpu(0x2002) & 0x80 == 0 {
+    bus.clock();
+  }
+  assert_eq!(bus.peek_cpu(0x2002) & 0x80, 0x80);
+  assert_eq!(bus.cpu_read(0x2002, false) & 0x80, 0x80);
+  assert_eq!(bus.peek_cpu(0x2002) & 0x80, 0);
+
+  bus.apu.frame_irq = true;
+  assert_eq!(bus.peek_cpu(0x4015) & 0x40, 0x40);
+  assert!(bus.apu.frame_irq);
+
+  bus.controller[0] = 0x80;
+  bus.cpu_write(0x4016, &mut 1);
+  assert_eq!(bus.peek_cpu(0x4016), 1);
+  assert_eq!(bus.cpu_read(0x4016, true), 1);
+  assert_eq!(bus.cpu_read(0x4016, false), 1);
+  assert_eq!(bus.peek_cpu(0x4016), 0);
+}
+
+#[test]
+fn test_dma_reads_skip_watchpoints() {
+  let mut bus = Bus::new();
+  bus.debugger.command("watch r $0200-$02FF").unwrap();
+  bus.dma_read(0x0210);
+  assert!(bus.debugger.break_reason().is_none());
+  bus.cpu_read(0x0210, false);
+  assert!(bus.debugger.break_reason().is_some());
+}
+
+#[test]
+fn test_irq_sources_are_combined() {
+  use crate::Mappers::mapper::{Mapper, Mirroring};
+
+  struct IrqMapper;
+  impl Mapper for IrqMapper {
+    fn cpu_mapper_peek(&self, _address: u16, _mapped_address: &mut i32, _data: &mut u8) -> bool { return false; }
+    fn ppu_mapper_peek(&self, _address: u16, _mapped_address: &mut u32) -> bool { return false; }
+    fn cpu_mapper_write(&mut self, _address: u16, _mapped_address: &mut i32, _data: &mut u8) -> bool { return false; }
+    fn ppu_mapper_write(&mut self, _address: u16, _mapped_address: &mut u32) -> bool { return false; }
+    fn reset(&mut self) {}
+    fn mirror(&self) -> Mirroring { return Mirroring::Hardware; }
+    fn irq_asserted(&self) -> bool { return true; }
+  }
+
+  let mut cartridge = Cartridge::new("src/test/nestest.nes".to_string()).unwrap();
+  cartridge.mapper = Box::new(IrqMapper);
+  let mut bus = Bus::new();
+  bus.connect_cartridge(Rc::new(RefCell::new(cartridge)));
+  bus.clock();
+  assert!(!bus.apu.irq_asserted());
+  assert!(bus.irq_required);
+}
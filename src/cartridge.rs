@@ -10,6 +10,7 @@ use crate::Mappers::mapper::Mirroring;
 use crate::Mappers::mapper_0::Mapper0;
 use crate::Mappers::mapper_1::Mapper1;
 use crate::Mappers::mapper_2::Mapper2;
+use crate::region::Region;
 
 
 #[allow(dead_code)]
@@ -31,6 +32,8 @@ pub struct CartridgeHeader {
     pub mapper_1: u8,
     pub mapper_2: u8,
     pub prg_ram_size: u8,
+    pub tv_system: u8,
+    pub timing: u8,
 }
 
 impl CartridgeHeader{
@@ -52,8 +55,26 @@ impl CartridgeHeader{
             mapper_1: data[6],
             mapper_2: data[7],
             prg_ram_size: data[8],
+            tv_system: data[9],
+            timing: data[12],
         }
     }
+
+    //https://wiki.nesdev.com/w/index.php/NES_2.0
+    pub fn is_nes2(&self) -> bool {
+        return self.mapper_2 & 0x0C == 0x08;
+    }
+
+    //The NES 2.0 timing field, or the rarely set iNES PAL bit
+    pub fn region(&self) -> Region {
+        if self.is_nes2() {
+            return Region::from_timing(self.timing);
+        }
+        if self.tv_system & 0x01 > 0 {
+            return Region::Pal;
+        }
+        return Region::Ntsc;
+    }
 }
 
 impl Cartridge {
@@ -193,6 +214,28 @@ impl Cartridge {
         }
     }
 
+    //Same as cpu_read but leaves the mapper alone
+    pub fn peek_cpu(&self, address: u16, data: &mut u8) -> bool {
+        let mut mapped_address: i32 = 0;
+        if self.mapper.cpu_mapper_peek(address, &mut mapped_address, data) {
+            if mapped_address >= 0 {
+                *data = self.vec_prg_memory[mapped_address as usize];
+            }
+            return true;
+        }
+        return false;
+    }
+
+    //Where in PRG ROM the mapper currently has a CPU address, for bank aware debugging
+    pub fn prg_offset(&self, address: u16) -> Option<usize> {
+        let mut mapped_address: i32 = 0;
+        let mut data: u8 = 0;
+        if address >= 0x8000 && self.mapper.cpu_mapper_peek(address, &mut mapped_address, &mut data) && mapped_address >= 0 {
+            return Some(mapped_address as usize);
+        }
+        return None;
+    }
+
     pub fn ppu_read(&mut self, address: u16, data: &mut u8) -> bool {
         let mut mapped_address: u32 = 0;
         if self.mapper.ppu_mapper_read(address, &mut mapped_address){
@@ -203,6 +246,15 @@ impl Cartridge {
         }
     }
 
+    pub fn peek_ppu(&self, address: u16, data: &mut u8) -> bool {
+        let mut mapped_address: u32 = 0;
+        if self.mapper.ppu_mapper_peek(address, &mut mapped_address) {
+            *data = self.vec_chr_memory[mapped_address as usize];
+            return true;
+        }
+        return false;
+    }
+
     pub fn ppu_write(&mut self, address: u16, data: u8) -> bool {
         let mut mapped_address: u32 = 0;
         if self.mapper.ppu_mapper_write(address, &mut mapped_address){
@@ -213,7 +265,11 @@ impl Cartridge {
         }
     }
 
-    pub fn mirror(&mut self) -> Mirroring{
+    pub fn irq_asserted(&self) -> bool {
+        return self.mapper.irq_asserted();
+    }
+
+    pub fn mirror(&self) -> Mirroring{
         let mirror = self.mapper.mirror();
         if mirror == Mirroring::Hardware
         {
@@ -243,3 +299,16 @@ fn test_new()
     assert_eq!(car.c_chr_banks, 1);
     assert_eq!(car.c_prg_banks, 1);
 }
+
+#[test]
+fn test_header_region() {
+    let mut header = [0u8; 16];
+    header[0..4].copy_from_slice(b"NES\x1a");
+    assert_eq!(CartridgeHeader::new(&header).region(), Region::Ntsc);
+    header[9] = 0x01;
+    assert_eq!(CartridgeHeader::new(&header).region(), Region::Pal);
+    //NES 2.0 ignores the old PAL bit
+    header[7] = 0x08;
+    header[12] = 0x03;
+    assert_eq!(CartridgeHeader::new(&header).region(), Region::Dendy);
+}
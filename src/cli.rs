@@ -0,0 +1,122 @@
+use crate::region::Region;
+
+pub const USAGE: &str = "usage: source [ROM] [--headless FRAMES] [--wav FILE] [--split-channels]
+                    [--palette NAME|FILE] [--screenshot FILE]
+                    [--record FILE.avi|DIR] [--region ntsc|pal|dendy]
+                    [--symbols FILE.dbg|FILE.nl]
+
+  ROM                 path to an iNES file, prompted for when missing
+  --headless FRAMES   run FRAMES frames without opening a window, then exit
+  --wav FILE          with --headless, record the audio output to a 16 bit wav file
+  --split-channels    with --wav, also write every APU channel to FILE.<channel>.wav
+  --palette NAME|FILE one of default, 2c02 or greyscale, or a 192/1536 byte .pal file
+  --screenshot FILE   with --headless, save the last frame as a PNG
+  --record PATH       with --headless, record video and audio to an AVI, or to numbered PNGs
+                      and audio.wav in the directory PATH
+  --region REGION     ntsc, pal or dendy, instead of the one in the ROM header
+  --symbols FILE      labels for the debugger from a ca65 .dbg or FCEUX .nl file, as well as
+                      ROM.dbg and ROM.nes.*.nl found next to the ROM";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+    pub rom: Option<String>,
+    pub headless: Option<u32>,
+    pub wav: Option<String>,
+    pub split_channels: bool,
+    pub palette: Option<String>,
+    pub screenshot: Option<String>,
+    pub record: Option<String>,
+    pub region: Option<Region>,
+    pub symbols: Option<String>,
+}
+
+impl Options {
+    //`args` excludes the program name
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+        let mut options = Options::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--headless" => {
+                    let frames = Options::value(&mut args, &arg)?;
+                    options.headless =
+                        Some(frames.parse().map_err(|_| format!("--headless expects a frame count, got '{}'", frames))?);
+                }
+                "--wav" => options.wav = Some(Options::value(&mut args, &arg)?),
+                "--split-channels" => options.split_channels = true,
+                "--palette" => options.palette = Some(Options::value(&mut args, &arg)?),
+                "--screenshot" => options.screenshot = Some(Options::value(&mut args, &arg)?),
+                "--record" => options.record = Some(Options::value(&mut args, &arg)?),
+                "--region" => {
+                    let name = Options::value(&mut args, &arg)?;
+                    options.region = Some(Region::from_name(&name).ok_or(format!("--region expects ntsc, pal or dendy, got '{}'", name))?);
+                }
+                "--symbols" => options.symbols = Some(Options::value(&mut args, &arg)?),
+                "-h" | "--help" => return Err(USAGE.to_string()),
+                _ if arg.starts_with("--") => return Err(format!("unknown option {}\n\n{}", arg, USAGE)),
+                _ => {
+                    if options.rom.is_some() {
+                        return Err(format!("unexpected argument {}\n\n{}", arg, USAGE));
+                    }
+                    options.rom = Some(arg);
+                }
+            }
+        }
+        if options.headless.is_some() && options.rom.is_none() {
+            return Err(format!("--headless needs a ROM\n\n{}", USAGE));
+        }
+        //The window has its own hotkeys for these
+        if options.headless.is_none() {
+            let headless_only = [
+                ("--wav", options.wav.is_some()),
+                ("--split-channels", options.split_channels),
+                ("--screenshot", options.screenshot.is_some()),
+                ("--record", options.record.is_some()),
+            ];
+            if let Some(&(option, _)) = headless_only.iter().find(|&&(_, given)| given) {
+                return Err(format!("{} only works with --headless\n\n{}", option, USAGE));
+            }
+        }
+        if options.split_channels && options.wav.is_none() {
+            return Err(format!("--split-channels needs --wav\n\n{}", USAGE));
+        }
+        Ok(options)
+    }
+
+    fn value<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<String, String> {
+        args.next().ok_or(format!("{} expects a value\n\n{}", option, USAGE))
+    }
+}
+
+#[test]
+fn test_parse_options() {
+    let args = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>().into_iter();
+
+    assert_eq!(Options::parse(args(&[])).unwrap(), Options::default());
+
+    let options = Options::parse(args(&["game.nes", "--headless", "600", "--wav", "out.wav", "--split-channels"])).unwrap();
+    assert_eq!(options.rom, Some("game.nes".to_string()));
+    assert_eq!(options.headless, Some(600));
+    assert_eq!(options.wav, Some("out.wav".to_string()));
+    assert!(options.split_channels);
+
+    let options = Options::parse(args(&["--palette", "2c02", "game.nes", "--headless", "1", "--screenshot", "a.png"])).unwrap();
+    assert_eq!(options.palette, Some("2c02".to_string()));
+    assert_eq!(options.screenshot, Some("a.png".to_string()));
+    assert_eq!(options.record, None);
+
+    let options = Options::parse(args(&["game.nes", "--region", "pal"])).unwrap();
+    assert_eq!(options.region, Some(Region::Pal));
+    assert!(Options::parse(args(&["game.nes", "--region", "secam"])).is_err());
+    let options = Options::parse(args(&["game.nes", "--symbols", "game.dbg"])).unwrap();
+    assert_eq!(options.symbols, Some("game.dbg".to_string()));
+
+    assert!(Options::parse(args(&["--headless", "10"])).is_err());
+    assert!(Options::parse(args(&["game.nes", "--headless", "ten"])).is_err());
+    assert!(Options::parse(args(&["game.nes", "--bogus"])).is_err());
+
+    //Captures without --headless would be silently ignored by the window
+    assert!(Options::parse(args(&["game.nes", "--wav", "out.wav"])).is_err());
+    assert!(Options::parse(args(&["game.nes", "--record", "out.avi"])).is_err());
+    assert!(Options::parse(args(&["game.nes", "--screenshot", "a.png"])).is_err());
+    assert!(Options::parse(args(&["game.nes", "--headless", "1", "--split-channels"])).is_err());
+}
@@ -0,0 +1,153 @@
+use crate::apu::{APU, CHANNELS};
+use crate::power::RamInit;
+use crate::scale::Overscan;
+use std::collections::HashMap;
+use std::fs;
+
+pub const CONFIG_PATH: &str = "config.toml";
+
+//Flat view of a small toml subset: `[section]` headers and `key = value` pairs, looked up as
+//"section.key". Values may be quoted, anything after a # is a comment.
+pub struct Config {
+    values: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            values: HashMap::new(),
+        }
+    }
+
+    //A missing file is not an error, every setting has a default
+    pub fn load(path: &str) -> Config {
+        match fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text),
+            Err(_) => Config::new(),
+        }
+    }
+
+    pub fn parse(text: &str) -> Config {
+        let mut values = HashMap::new();
+        let mut section = String::new();
+        for line in text.lines() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                section = line[1..line.len() - 1].trim().to_string();
+                continue;
+            }
+            if let Some(i) = line.find('=') {
+                let key = line[..i].trim();
+                let value = line[i + 1..].trim().trim_matches('"');
+                let full_key = if section.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", section, key)
+                };
+                values.insert(full_key, value.to_string());
+            } else {
+                println!("config: ignoring line '{}'", line);
+            }
+        }
+        Config { values }
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|v| v.as_str())
+    }
+
+    pub fn get_bool(&self, key: &str, default: bool) -> bool {
+        match self.get_str(key) {
+            Some("true") => true,
+            Some("false") => false,
+            Some(other) => {
+                println!("config: {} expects true or false, got '{}'", key, other);
+                default
+            }
+            None => default,
+        }
+    }
+
+    pub fn get_f64(&self, key: &str, default: f64) -> f64 {
+        match self.get_str(key) {
+            Some(value) => value.parse().unwrap_or_else(|_| {
+                println!("config: {} expects a number, got '{}'", key, value);
+                default
+            }),
+            None => default,
+        }
+    }
+
+    //[audio] pulse_0 = true, pulse_0_volume = 1.0 ... for every channel
+    pub fn apply_audio(&self, apu: &mut APU) {
+        for &channel in CHANNELS.iter() {
+            let enabled = self.get_bool(&format!("audio.{}", channel.name()), true);
+            let gain = self.get_f64(&format!("audio.{}_volume", channel.name()), 1.0);
+            apu.set_channel_enabled(channel, enabled);
+            apu.set_channel_gain(channel, gain as f32);
+        }
+    }
+
+    //[video] overscan_top = 8 ... in PPU pixels
+    pub fn overscan(&self) -> Overscan {
+        let edge = |name: &str| self.get_f64(&format!("video.overscan_{}", name), 0.0).max(0.0) as usize;
+        Overscan::new(edge("top"), edge("bottom"), edge("left"), edge("right"))
+    }
+
+    //[system] ram_init = "seeded", ram_seed = 1234
+    pub fn ram_init(&self) -> Result<RamInit, String> {
+        let name = self.get_str("system.ram_init").unwrap_or("zero");
+        let seed = self.get_f64("system.ram_seed", 0.0) as u64;
+        RamInit::from_name(name, seed).ok_or(format!("config: unknown system.ram_init '{}'", name))
+    }
+}
+
+//A # starts a comment unless it's inside a quoted value
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    return line;
+}
+
+#[test]
+fn test_parse() {
+    use crate::apu::Channel;
+
+    let config = Config::parse(
+        "profiler = true\n\
+         [audio]\n\
+         sample_rate = 48000 # comment\n\
+         triangle = false\n\
+         noise_volume = \"0.5\"\n\
+         [video]\n\
+         overscan_top = 8\n\
+         overscan_bottom = 8\n\
+         palette = \"pals/#2.pal\" # comment\n\
+         [system]\n\
+         ram_init = seeded\n\
+         ram_seed = 7\n",
+    );
+    assert_eq!(config.get_bool("profiler", false), true);
+    assert_eq!(config.get_f64("audio.sample_rate", 44100.0), 48000.0);
+    assert_eq!(config.get_f64("audio.missing", 1.5), 1.5);
+
+    let mut apu = APU::new();
+    config.apply_audio(&mut apu);
+    assert_eq!(apu.channel(Channel::Triangle).enabled, false);
+    assert_eq!(apu.channel(Channel::Pulse0).enabled, true);
+    assert_eq!(apu.channel(Channel::Noise).gain, 0.5);
+
+    assert_eq!(config.overscan(), Overscan::new(8, 8, 0, 0));
+    assert_eq!(config.ram_init(), Ok(RamInit::Seeded(7)));
+    assert_eq!(config.get_str("video.palette"), Some("pals/#2.pal"));
+    assert!(Config::parse("[system]\nram_init = sometimes\n").ram_init().is_err());
+}
@@ -1,6 +1,5 @@
 use crate::bus::Bus;
-use std::collections::HashMap;
-use std::convert::TryFrom;
+use crate::debugger::{BreakReason, CpuState};
 use std::fs::OpenOptions;
 use std::io::prelude::*;
 use std::num::Wrapping;
@@ -135,19 +134,34 @@ impl CPU6502 {
         self.bus.cpu_write(address, data);
     }
 
+    //https://wiki.nesdev.com/w/index.php/CPU_power_up_state
+    //Power cycle, registers and memory start over and then the reset sequence runs
+    pub fn power_on(&mut self) {
+        self.bus.power_on();
+        self.a = 0;
+        self.x = 0;
+        self.y = 0;
+        self.sptr = 0x00;
+        self.sr = 0x00;
+        self.start();
+    }
+
     // Reset Interrupt
+    //The reset button, A, X, Y and memory are kept
     pub fn reset(&mut self) {
         self.bus.reset();
+        self.start();
+    }
+
+    //Reset sequence, three stack pushes with writes suppressed and interrupts disabled
+    fn start(&mut self) {
         self.address_absolute = 0xFFFC;
         let low = self.read(self.address_absolute) as u16;
         let high = self.read(self.address_absolute + 1) as u16;
 
         self.pc = (high << 8) | low;
-        self.a = 0;
-        self.x = 0;
-        self.y = 0;
-        self.sptr = 0xFD;
-        self.sr = 0x00;
+        self.sptr = self.sptr.wrapping_sub(3);
+        self.set_flag(Flags::I, true);
 
         self.address_absolute = 0x0000;
         self.address_relative = 0x0000;
@@ -158,7 +172,7 @@ impl CPU6502 {
 
     // Interrupt Request
     pub fn irq(&mut self) {
-        if self.get_flag(Flags::I) == 1 {
+        if self.get_flag(Flags::I) == 0 {
             let mut val = (self.pc >> 8) as u8 & 0x00FF;
             self.write(0x0100 + self.sptr as u16, &mut val);
             self.subtract_stack();
@@ -167,13 +181,14 @@ impl CPU6502 {
             self.write(0x0100 + self.sptr as u16, &mut val);
             self.subtract_stack();
 
+            //Status is pushed before I is set so RTI restores interrupts
             self.set_flag(Flags::B, false);
             self.set_flag(Flags::U, true);
-            self.set_flag(Flags::I, true);
 
             let mut sr = self.sr;
             self.write(0x0100 + self.sptr as u16, &mut sr);
             self.subtract_stack();
+            self.set_flag(Flags::I, true);
 
             self.address_absolute = 0xFFFE;
             let low = self.read(self.address_absolute) as u16;
@@ -197,11 +212,11 @@ impl CPU6502 {
 
         self.set_flag(Flags::B, false);
         self.set_flag(Flags::U, true);
-        self.set_flag(Flags::I, true);
 
         let mut sr = self.sr;
         self.write(0x0100 + self.sptr as u16, &mut sr);
         self.subtract_stack();
+        self.set_flag(Flags::I, true);
 
         self.address_absolute = 0xFFFA;
         let low = self.read(self.address_absolute) as u16;
@@ -217,10 +232,15 @@ impl CPU6502 {
         if self.bus.nmi_required == true {
             self.bus.nmi_required = false;
             self.nmi();
+            self.bus.debugger.interrupt(BreakReason::Nmi);
         }
         if self.bus.irq_required == true {
             self.bus.irq_required = false;
+            let taken = self.get_flag(Flags::I) == 0;
             self.irq();
+            if taken {
+                self.bus.debugger.interrupt(BreakReason::Irq);
+            }
         }
         if self.cycles == 0 {
             self.set_flag(Flags::U, true);
@@ -239,6 +259,72 @@ impl CPU6502 {
         self.cycles = self.cycles - 1;
     }
 
+    //One CPU cycle of the whole system, the CPU sits out OAM DMA while it copies a byte every two
+    //cycles. Nothing runs when the debugger stops before the next instruction
+    pub fn clock_system(&mut self) {
+        if self.cycles == 0 && !self.bus.dma_transfer && self.bus.debugger.active() && self.debugger_stops() {
+            return;
+        }
+        self.bus.system_clock = self.bus.system_clock.wrapping_add(1);
+        self.bus.clock();
+        if self.bus.dma_transfer == true {
+            if self.bus.dma_buffer == true {
+                if self.bus.system_clock % 2 == 1 {
+                    self.bus.dma_buffer = false;
+                }
+            } else {
+                if self.bus.system_clock % 2 == 0 {
+                    let page = (self.bus.dma_page as u16) << 8;
+                    let address = self.bus.dma_address as u16;
+                    self.bus.dma_data = self.bus.dma_read(page | address);
+                } else {
+                    self.bus.ppu.oam_ram[self.bus.dma_address as usize] = self.bus.dma_data;
+                    if self.bus.dma_address != 255 {
+                        self.bus.dma_address += 1;
+                    } else {
+                        self.bus.dma_address = 0x00;
+                        self.bus.dma_transfer = false;
+                        self.bus.dma_buffer = true;
+                    }
+                }
+            }
+        } else {
+            self.clock();
+        }
+    }
+
+    fn debugger_stops(&mut self) -> bool {
+        let opcode = self.bus.peek_cpu(self.pc);
+        let (scanline, _) = self.bus.ppu.position();
+        let state = CpuState {
+            pc: self.pc,
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            sp: self.sptr,
+            p: self.sr,
+            opcode,
+            last_opcode: self.opcode,
+            illegal: self.lookup[opcode as usize].name == "???",
+            scanline,
+            frame: self.bus.ppu.frame_count(),
+        };
+        return self.bus.debugger.before_instruction(state);
+    }
+
+    //Runs to the end of the frame, or until the debugger stops and returns why. The next call
+    //carries on from there once the debugger is resumed
+    pub fn run_frame(&mut self) -> Option<BreakReason> {
+        while self.bus.ppu.frame_complete == false {
+            self.clock_system();
+            if let Some(reason) = self.bus.debugger.break_reason() {
+                return Some(reason.clone());
+            }
+        }
+        self.bus.ppu.frame_complete = false;
+        return None;
+    }
+
     pub fn complete(&mut self) -> bool {
         return self.cycles == 0;
     }
@@ -250,134 +336,10 @@ impl CPU6502 {
         return self.fetched;
     }
 
-    #[allow(unused_assignments)]
-    pub fn disassemble(&mut self, start: u16, stop: u16) -> HashMap<u32, String> {
-        let mut address: u32 = start as u32;
-        let mut value: u8 = 0x00;
-        let mut lo: u8 = 0x00;
-        let mut hi: u8 = 0x00;
-        let mut line_address: u32 = 0;
-        let mut lines = HashMap::new();
-
-        while address<= stop as u32 {
-            line_address= address;
-            let mut format = format!("{:X}", address);
-            let mut d = "$".to_owned();
-            d.push_str(&format);
-            d.push_str(": ");
-            //println!("{}", d);
-            let mut val = u16::try_from(address).unwrap();
-            let opcode: u8 = self.bus.cpu_read(val, true);
-            address= address+ 1;
-
-            let v = &self.lookup[opcode as usize].name;
-            d.push_str(v);
-            d.push_str(" ");
-
-            if self.lookup[opcode as usize].address_name == "IMP" {
-                d.push_str(" {IMP}");
-            } else if self.lookup[opcode as usize].address_name == "IMM" {
-                address= address+ 1;
-                d.push_str(" {IMM}");
-            } else if self.lookup[opcode as usize].address_name == "ZP0" {
-                lo = self.bus.cpu_read(val, true);
-                address= address+ 1;
-                d.push_str("$");
-                format = format!("{:X}", lo);
-                d.push_str(&format);
-                d.push_str(" {ZP0}");
-            } else if self.lookup[opcode as usize].address_name == "ZPX" {
-                lo = self.bus.cpu_read(val, true);
-                address= address+ 1;
-                d.push_str("$");
-                format = format!("{:X}", lo);
-                d.push_str(&format);
-                d.push_str(" X {ZPX}");
-            } else if self.lookup[opcode as usize].address_name == "ZPY" {
-                lo = self.bus.cpu_read(val, true);
-                address= address+ 1;
-                d.push_str("$");
-                format = format!("{:X}", lo);
-                d.push_str(&format);
-                d.push_str(" Y {ZPY}");
-            } else if self.lookup[opcode as usize].address_name == "IZX" {
-                lo = self.bus.cpu_read(val, true);
-                address= address+ 1;
-                d.push_str("$");
-                format = format!("{:X}", lo);
-                d.push_str(&format);
-                d.push_str(" X {IZX}");
-            } else if self.lookup[opcode as usize].address_name == "IZY" {
-                lo = self.bus.cpu_read(val, true);
-                address= address+ 1;
-                d.push_str("$");
-                format = format!("{:X}", lo);
-                d.push_str(&format);
-                d.push_str(" X {IZY}");
-            } else if self.lookup[opcode as usize].address_name == "ABS" {
-                lo = self.bus.cpu_read(val, true);
-                val = val + 1;
-                address= address+ 1;
-                hi = self.bus.cpu_read(val, true);
-                val = val + 1;
-                address= address+ 1;
-                d.push_str("$");
-                let hex_val = ((hi as u16) << 8) | lo as u16;
-                format = format!("{:X}", hex_val);
-                d.push_str(&format);
-                d.push_str(" {ABS}");
-            } else if self.lookup[opcode as usize].address_name == "ABX" {
-                lo = self.bus.cpu_read(val, true);
-                val = val + 1;
-                address= address+ 1;
-                hi = self.bus.cpu_read(val, true);
-                val = val + 1;
-                address= address+ 1;
-                d.push_str("$");
-                let hex_val = ((hi as u16) << 8) | lo as u16;
-                format = format!("{:X}", hex_val);
-                d.push_str(&format);
-                d.push_str(" X {ABX}");
-            } else if self.lookup[opcode as usize].address_name == "ABY" {
-                lo = self.bus.cpu_read(val, true);
-                val = val + 1;
-                address= address+ 1;
-                hi = self.bus.cpu_read(val, true);
-                val = val + 1;
-                address= address+ 1;
-                d.push_str("$");
-                let hex_val = ((hi as u16) << 8) | lo as u16;
-                format = format!("{:X}", hex_val);
-                d.push_str(&format);
-                d.push_str(" Y {ABY}");
-            } else if self.lookup[opcode as usize].address_name == "IND" {
-                lo = self.bus.cpu_read(val, true);
-                val = val + 1;
-                address= address+ 1;
-                hi = self.bus.cpu_read(val, true);
-                val = val + 1;
-                address= address+ 1;
-                d.push_str("($");
-                let hex_val = ((hi as u16) << 8) | lo as u16;
-                format = format!("{:X}", hex_val);
-                d.push_str(&format);
-                d.push_str(" {IND})");
-            } else if self.lookup[opcode as usize].address_name == "REL" {
-                value = self.bus.cpu_read(val, true);
-                address= address+ 1;
-                d.push_str("$");
-                format = format!("{:X}", value);
-                d.push_str(&format);
-                d.push_str(" [&");
-
-                format = format!("{:X}", (address+ value as u32));
-                d.push_str(&format);
-                d.push_str("] {REL}");
-            }
-            lines.insert(line_address, d);
-        }
-        println!("");
-        return lines;
+    //Mnemonic and addressing mode of an opcode, "???" for the illegal ones
+    pub fn instruction(&self, opcode: u8) -> (&str, &str) {
+        let instruction = &self.lookup[opcode as usize];
+        return (&instruction.name, &instruction.address_name);
     }
 }
 
@@ -3071,6 +3033,56 @@ fn test_reset() {
     assert_eq!(nes.cycles, 8);
 }
 
+#[test]
+fn test_power_on_and_reset() {
+    use crate::power::RamInit;
+
+    let mut nes = CPU6502::new();
+    nes.bus.ram_init = RamInit::Ones;
+    nes.power_on();
+    assert_eq!(nes.bus.ram[0x0123], 0xFF);
+    assert_eq!(nes.sptr, 0xFD);
+    assert_eq!(nes.get_flag(Flags::I), 1);
+
+    //Reset keeps memory and registers and pushes three times without writing
+    nes.a = 0x42;
+    nes.bus.ram[0x0010] = 0x07;
+    nes.set_flag(Flags::I, false);
+    nes.reset();
+    assert_eq!(nes.a, 0x42);
+    assert_eq!(nes.bus.ram[0x0010], 0x07);
+    assert_eq!(nes.sptr, 0xFA);
+    assert_eq!(nes.get_flag(Flags::I), 1);
+
+    nes.power_on();
+    assert_eq!((nes.a, nes.sptr), (0, 0xFD));
+    assert_eq!(nes.bus.ram[0x0010], 0xFF);
+
+    //Reset silences the APU and clears its IRQ but keeps the 5 step mode, which never raises one
+    for (address, data) in [(0x4015, 0x01), (0x4003, 0x08), (0x4017, 0x80)] {
+        nes.bus.apu.cpu_write(address, data);
+    }
+    nes.bus.apu.clock();
+    nes.bus.apu.frame_irq = true;
+    nes.bus.dma_transfer = true;
+    nes.bus.nmi_required = true;
+    assert_eq!(nes.bus.apu.peek_cpu(0x4015), 0x41);
+    nes.reset();
+    assert_eq!(nes.bus.apu.peek_cpu(0x4015), 0);
+    assert!(!nes.bus.dma_transfer && !nes.bus.nmi_required);
+    for _ in 0..40000 {
+        nes.bus.apu.clock();
+    }
+    assert!(!nes.bus.apu.frame_irq);
+
+    //Power on goes back to the 4 step mode
+    nes.power_on();
+    for _ in 0..40000 {
+        nes.bus.apu.clock();
+    }
+    assert!(nes.bus.apu.frame_irq);
+}
+
 #[test]
 fn test_add_stack() {
     let mut nes = CPU6502::new();
@@ -3319,7 +3331,7 @@ fn test_bmi() {
     let mut nes = CPU6502::new();
 
     //Test if negative set
-    nes.reset();
+    nes.power_on();
     nes.address_absolute = 0xFF;
     nes.address_relative = 0x03;
     nes.pc = 0x10;
@@ -3333,7 +3345,7 @@ fn test_bmi() {
     assert_ne!(pre_cycle, nes.cycles);
 
     //Test if negative clear
-    nes.reset();
+    nes.power_on();
     nes.address_absolute = 0xFF;
     nes.address_relative = 0x03;
     nes.pc = 0x10;
@@ -3980,13 +3992,15 @@ fn test_php()
 {
     //Test with all flags off
     let mut nes = CPU6502::new();
-    nes.reset();
+    nes.power_on();
+    //Power on leaves interrupts disabled
+    nes.set_flag(Flags::I, false);
     nes.PHP();
     let stack = nes.read(0x0100 + nes.sptr as u16 + 1);
     assert_eq!(stack, 48);  //As final result is OR'd with 16 and 32
 
     //Test with all flags on 
-    nes.reset();
+    nes.power_on();
     nes.set_flag(Flags::C, true);
     nes.set_flag(Flags::Z, true);
     nes.set_flag(Flags::I, true);
@@ -4001,7 +4015,7 @@ fn test_php()
     assert_eq!(stack, 255);  
 
     //Test with just Negative and Clear
-    nes.reset();
+    nes.power_on();
     nes.set_flag(Flags::C, true);
     nes.set_flag(Flags::Z, false);
     nes.set_flag(Flags::I, false);
@@ -4067,14 +4081,14 @@ fn test_rol(){
 #[test]
 fn test_ror(){
     let mut nes = CPU6502::new();
-    nes.reset();
+    nes.power_on();
     nes.bus.ram[0x001] = 10;
     nes.address_absolute = 0x001;
     nes.ROR();
     assert_eq!(nes.bus.ram[0x001], 5);
 
     //Test Zero flag is set
-    nes.reset();
+    nes.power_on();
     nes.bus.ram[0x001] = 1;
     nes.address_absolute = 0x001;
     nes.ROR();
@@ -4082,7 +4096,7 @@ fn test_ror(){
     assert_eq!(nes.get_flag(Flags::Z), 1);  
     
     //Test Negative flag is never set
-    nes.reset();
+    nes.power_on();
     nes.bus.ram[0x001] = 255;
     nes.address_absolute = 0x001;
     nes.ROR();
@@ -4111,7 +4125,8 @@ fn test_sed(){
 #[test]
 fn test_sei(){
     let mut nes = CPU6502::new();
-    nes.reset();
+    nes.power_on();
+    nes.set_flag(Flags::I, false);
     assert_eq!(nes.get_flag(Flags::I), 0);  
     nes.SEI();
     assert_eq!(nes.get_flag(Flags::I), 1);  
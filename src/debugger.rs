@@ -0,0 +1,677 @@
+use std::fmt;
+
+//Breakpoints, watchpoints and stepping. The bus reports memory accesses and the CPU asks before
+//every instruction whether to stop, CPU6502::run_frame returns early with the reason when it does
+
+bitflags! {
+    //Kinds of access a watchpoint stops on
+    pub struct Access: u8 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXECUTE = 1 << 2;
+    }
+}
+
+//Which address space a watchpoint covers, PPU accesses are the ones made through $2007
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Space {
+    Cpu,
+    Ppu,
+}
+
+//The CPU just before an instruction runs
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CpuState {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub p: u8,
+    pub opcode: u8,
+    //Opcode of the instruction that ran before this one
+    pub last_opcode: u8,
+    pub illegal: bool,
+    pub scanline: i32,
+    pub frame: u32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    Sp,
+    P,
+    Pc,
+    Scanline,
+    //The byte read or written, for watchpoints
+    Value,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Op {
+    Or,
+    And,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+//Conditions like `A == $10 && P & $80`. Numbers are decimal, or hex after $ or 0x, and anything
+//non zero is true
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Register(Register),
+    Binary(Box<Expr>, Op, Box<Expr>),
+}
+
+pub fn parse_number(text: &str) -> Result<i64, String> {
+    let parsed = if let Some(hex) = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")) {
+        i64::from_str_radix(hex, 16)
+    } else {
+        text.parse()
+    };
+    return parsed.map_err(|_| format!("expected a number, got '{}'", text));
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = text.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_alphanumeric() || c == '$' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '$') {
+                i += 1;
+            }
+            tokens.push(chars[start..i].iter().collect());
+        } else {
+            let pair: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+            if ["==", "!=", "<=", ">=", "&&", "||"].contains(&pair.as_str()) {
+                tokens.push(pair);
+                i += 2;
+            } else {
+                tokens.push(c.to_string());
+                i += 1;
+            }
+        }
+    }
+    return tokens;
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        return self.tokens.get(self.position).map(|t| t.as_str());
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        let token = self.tokens.get(self.position).cloned().ok_or("unexpected end of condition")?;
+        self.position += 1;
+        return Ok(token);
+    }
+
+    //Each level binds tighter than the one before: ||, &&, comparisons, &
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        const LEVELS: [&[(&str, Op)]; 4] = [
+            &[("||", Op::Or)],
+            &[("&&", Op::And)],
+            &[("==", Op::Eq), ("!=", Op::Ne), ("<=", Op::Le), (">=", Op::Ge), ("<", Op::Lt), (">", Op::Gt)],
+            &[("&", Op::BitAnd)],
+        ];
+        if level == LEVELS.len() {
+            return self.operand();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(&(_, op)) = LEVELS[level].iter().find(|(token, _)| Some(*token) == self.peek()) {
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            left = Expr::Binary(Box::new(left), op, Box::new(right));
+        }
+        return Ok(left);
+    }
+
+    fn operand(&mut self) -> Result<Expr, String> {
+        let token = self.next()?;
+        if token == "(" {
+            let inner = self.binary(0)?;
+            if self.next()? != ")" {
+                return Err("expected )".to_string());
+            }
+            return Ok(inner);
+        }
+        let register = match token.to_ascii_lowercase().as_str() {
+            "a" => Register::A,
+            "x" => Register::X,
+            "y" => Register::Y,
+            "sp" | "s" => Register::Sp,
+            "p" => Register::P,
+            "pc" => Register::Pc,
+            "scanline" => Register::Scanline,
+            "value" => Register::Value,
+            _ => return Ok(Expr::Number(parse_number(&token)?)),
+        };
+        return Ok(Expr::Register(register));
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let mut parser = Parser { tokens: tokenize(text), position: 0 };
+        let expr = parser.binary(0)?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected '{}' in condition", token));
+        }
+        return Ok(expr);
+    }
+
+    pub fn evaluate(&self, state: &CpuState, value: u8) -> i64 {
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Register(register) => match register {
+                Register::A => state.a as i64,
+                Register::X => state.x as i64,
+                Register::Y => state.y as i64,
+                Register::Sp => state.sp as i64,
+                Register::P => state.p as i64,
+                Register::Pc => state.pc as i64,
+                Register::Scanline => state.scanline as i64,
+                Register::Value => value as i64,
+            },
+            Expr::Binary(left, op, right) => {
+                let (l, r) = (left.evaluate(state, value), right.evaluate(state, value));
+                let result = match op {
+                    Op::Or => l != 0 || r != 0,
+                    Op::And => l != 0 && r != 0,
+                    Op::BitAnd => return l & r,
+                    Op::Eq => l == r,
+                    Op::Ne => l != r,
+                    Op::Lt => l < r,
+                    Op::Le => l <= r,
+                    Op::Gt => l > r,
+                    Op::Ge => l >= r,
+                };
+                result as i64
+            }
+        }
+    }
+}
+
+fn condition_met(condition: &Option<Expr>, state: &CpuState, value: u8) -> bool {
+    return condition.as_ref().map_or(true, |c| c.evaluate(state, value) != 0);
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub address: u16,
+    pub condition: Option<Expr>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub space: Space,
+    pub start: u16,
+    pub end: u16,
+    pub access: Access,
+    pub condition: Option<Expr>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Step {
+    //Stop before the next instruction
+    Into,
+    //Like Into, but a JSR runs until it returns
+    Over,
+    //Run until the current subroutine or interrupt handler returns
+    Out,
+    //Run until the PPU next reaches this scanline
+    Scanline(i32),
+    Frame,
+}
+
+//A step with what it needs from when it started
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Stepping {
+    Into,
+    Over { return_address: u16, stack: u8 },
+    Out { stack: u8 },
+    Scanline { line: i32, last: i32 },
+    Frame { start: u32 },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BreakReason {
+    Breakpoint(u16),
+    Watchpoint { space: Space, address: u16, access: Access, value: u8 },
+    Nmi,
+    Irq,
+    IllegalOpcode { address: u16, opcode: u8 },
+    Step,
+    Scanline(i32),
+    Frame,
+}
+
+impl fmt::Display for BreakReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BreakReason::Breakpoint(address) => write!(f, "breakpoint at ${:04X}", address),
+            BreakReason::Watchpoint { space, address, access, value } => {
+                let kind = if access.contains(Access::WRITE) {
+                    "write"
+                } else if access.contains(Access::READ) {
+                    "read"
+                } else {
+                    "execute"
+                };
+                let space = if *space == Space::Ppu { "PPU " } else { "" };
+                write!(f, "{} of ${:02X} at {}${:04X}", kind, value, space, address)
+            }
+            BreakReason::Nmi => write!(f, "NMI"),
+            BreakReason::Irq => write!(f, "IRQ"),
+            BreakReason::IllegalOpcode { address, opcode } => write!(f, "illegal opcode ${:02X} at ${:04X}", opcode, address),
+            BreakReason::Step => write!(f, "step"),
+            BreakReason::Scanline(line) => write!(f, "scanline {}", line),
+            BreakReason::Frame => write!(f, "frame"),
+        }
+    }
+}
+
+//What a console command asks the front end to do
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    Message(String),
+    //Stepping or continuing, emulation should run again
+    Run,
+}
+
+pub const HELP: &str = "break ADDR [if COND], delete ADDR, watch r|w|x|rw|rwx [ppu] START[-END] [if COND], \
+unwatch START, clear, nmi, irq, ill, step, over, out, scanline N, frame, continue, list";
+
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
+    pub break_on_nmi: bool,
+    pub break_on_irq: bool,
+    pub break_on_illegal: bool,
+    step: Option<Step>,
+    stepping: Option<Stepping>,
+    //The first instruction after resuming runs without stopping at whatever stopped it
+    resuming: bool,
+    //The instruction in flight, for watchpoint conditions
+    state: CpuState,
+    break_reason: Option<BreakReason>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            break_on_nmi: false,
+            break_on_irq: false,
+            break_on_illegal: false,
+            step: None,
+            stepping: None,
+            resuming: false,
+            state: CpuState::default(),
+            break_reason: None,
+        }
+    }
+
+    //Anything that needs the CPU to check in before each instruction
+    pub fn active(&self) -> bool {
+        return !self.breakpoints.is_empty()
+            || !self.watchpoints.is_empty()
+            || self.break_on_illegal
+            || self.step.is_some()
+            || self.stepping.is_some()
+            || self.resuming
+            || self.break_reason.is_some();
+    }
+
+    pub fn watching(&self) -> bool {
+        return !self.watchpoints.is_empty();
+    }
+
+    pub fn break_reason(&self) -> Option<&BreakReason> {
+        return self.break_reason.as_ref();
+    }
+
+    pub fn stop(&mut self, reason: BreakReason) {
+        if self.break_reason.is_none() {
+            self.break_reason = Some(reason);
+            self.stepping = None;
+        }
+    }
+
+    pub fn resume(&mut self) {
+        self.break_reason = None;
+        self.step = None;
+        self.stepping = None;
+        self.resuming = true;
+    }
+
+    pub fn step(&mut self, step: Step) {
+        self.resume();
+        self.step = Some(step);
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16, condition: Option<Expr>) {
+        self.breakpoints.retain(|b| b.address != address);
+        self.breakpoints.push(Breakpoint { address, condition });
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|b| b.address != address);
+        return self.breakpoints.len() != count;
+    }
+
+    //Removes a breakpoint at address or adds one, returns whether there is one now
+    pub fn toggle_breakpoint(&mut self, address: u16) -> bool {
+        if self.remove_breakpoint(address) {
+            return false;
+        }
+        self.add_breakpoint(address, None);
+        return true;
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    //Called by the CPU before each instruction while active, true to stop before running it
+    pub fn before_instruction(&mut self, state: CpuState) -> bool {
+        self.state = state;
+        if self.break_reason.is_some() {
+            return true;
+        }
+        if self.resuming {
+            self.resuming = false;
+            self.stepping = self.step.take().map(|step| match step {
+                Step::Into => Stepping::Into,
+                //JSR is 3 bytes and pushes 2, it has returned when the stack is back where it was
+                Step::Over if state.opcode == 0x20 => Stepping::Over {
+                    return_address: state.pc.wrapping_add(3),
+                    stack: state.sp,
+                },
+                Step::Over => Stepping::Into,
+                Step::Out => Stepping::Out { stack: state.sp },
+                Step::Scanline(line) => Stepping::Scanline { line, last: state.scanline },
+                Step::Frame => Stepping::Frame { start: state.frame },
+            });
+            return false;
+        }
+
+        if let Some(stepping) = self.stepping {
+            let reason = match stepping {
+                Stepping::Into => Some(BreakReason::Step),
+                Stepping::Over { return_address, stack } if state.pc == return_address && state.sp >= stack => Some(BreakReason::Step),
+                //RTS and RTI
+                Stepping::Out { stack } if (state.last_opcode == 0x60 || state.last_opcode == 0x40) && state.sp > stack => {
+                    Some(BreakReason::Step)
+                }
+                Stepping::Scanline { line, last } => {
+                    self.stepping = Some(Stepping::Scanline { line, last: state.scanline });
+                    if state.scanline == line && last != line {
+                        Some(BreakReason::Scanline(line))
+                    } else {
+                        None
+                    }
+                }
+                Stepping::Frame { start } if state.frame != start => Some(BreakReason::Frame),
+                _ => None,
+            };
+            if let Some(reason) = reason {
+                self.stop(reason);
+                return true;
+            }
+        }
+
+        if let Some(breakpoint) = self.breakpoints.iter().find(|b| b.address == state.pc) {
+            if condition_met(&breakpoint.condition, &state, state.opcode) {
+                self.stop(BreakReason::Breakpoint(state.pc));
+                return true;
+            }
+        }
+        self.access(Space::Cpu, state.pc, Access::EXECUTE, state.opcode);
+        if state.illegal && self.break_on_illegal {
+            self.stop(BreakReason::IllegalOpcode { address: state.pc, opcode: state.opcode });
+        }
+        return self.break_reason.is_some();
+    }
+
+    //Called by the bus for reads and writes that aren't read_only. The instruction making the
+    //access finishes before the emulation stops
+    pub fn access(&mut self, space: Space, address: u16, access: Access, value: u8) {
+        let state = self.state;
+        let hit = self.watchpoints.iter().any(|w| {
+            w.space == space
+                && w.access.intersects(access)
+                && address >= w.start
+                && address <= w.end
+                && condition_met(&w.condition, &state, value)
+        });
+        if hit {
+            self.stop(BreakReason::Watchpoint { space, address, access, value });
+        }
+    }
+
+    pub fn interrupt(&mut self, reason: BreakReason) {
+        let wanted = match reason {
+            BreakReason::Nmi => self.break_on_nmi,
+            BreakReason::Irq => self.break_on_irq,
+            _ => true,
+        };
+        if wanted {
+            self.stop(reason);
+        }
+    }
+
+    //Splits `... if CONDITION` off the end of a command
+    fn split_condition(line: &str) -> Result<(&str, Option<Expr>), String> {
+        match line.find(" if ") {
+            Some(i) => Ok((&line[..i], Some(Expr::parse(&line[i + 4..])?))),
+            None => Ok((line, None)),
+        }
+    }
+
+    fn parse_address(text: &str) -> Result<u16, String> {
+        let number = parse_number(text)?;
+        if number < 0 || number > 0xFFFF {
+            return Err(format!("address out of range: {}", text));
+        }
+        return Ok(number as u16);
+    }
+
+    fn toggle(flag: &mut bool, name: &str) -> Response {
+        *flag = !*flag;
+        return Response::Message(format!("break on {}: {}", name, if *flag { "on" } else { "off" }));
+    }
+
+    //The debug console's commands, see HELP
+    pub fn command(&mut self, line: &str) -> Result<Response, String> {
+        let (line, condition) = Debugger::split_condition(line.trim())?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let argument = |i: usize| words.get(i).copied().ok_or(format!("missing argument, {}", HELP));
+        let message = match words.first().copied().unwrap_or("") {
+            "break" | "b" => {
+                let address = Debugger::parse_address(argument(1)?)?;
+                self.add_breakpoint(address, condition);
+                format!("breakpoint at ${:04X}", address)
+            }
+            "delete" | "d" => {
+                let address = Debugger::parse_address(argument(1)?)?;
+                if !self.remove_breakpoint(address) {
+                    return Err(format!("no breakpoint at ${:04X}", address));
+                }
+                format!("deleted ${:04X}", address)
+            }
+            "watch" | "w" => {
+                let mut access = Access::empty();
+                for c in argument(1)?.chars() {
+                    access |= match c {
+                        'r' => Access::READ,
+                        'w' => Access::WRITE,
+                        'x' => Access::EXECUTE,
+                        _ => return Err(format!("watch access is r, w and/or x, got '{}'", c)),
+                    };
+                }
+                let (space, range) = if argument(2)? == "ppu" { (Space::Ppu, argument(3)?) } else { (Space::Cpu, argument(2)?) };
+                let (start, end) = match range.find('-') {
+                    Some(i) => (Debugger::parse_address(&range[..i])?, Debugger::parse_address(&range[i + 1..])?),
+                    None => (Debugger::parse_address(range)?, Debugger::parse_address(range)?),
+                };
+                self.add_watchpoint(Watchpoint { space, start, end, access, condition });
+                format!("watching ${:04X}-${:04X}", start, end)
+            }
+            "unwatch" => {
+                let start = Debugger::parse_address(argument(1)?)?;
+                self.watchpoints.retain(|w| w.start != start);
+                format!("unwatched ${:04X}", start)
+            }
+            "clear" => {
+                self.breakpoints.clear();
+                self.watchpoints.clear();
+                "cleared breakpoints and watchpoints".to_string()
+            }
+            "nmi" => return Ok(Debugger::toggle(&mut self.break_on_nmi, "NMI")),
+            "irq" => return Ok(Debugger::toggle(&mut self.break_on_irq, "IRQ")),
+            "ill" => return Ok(Debugger::toggle(&mut self.break_on_illegal, "illegal opcodes")),
+            "step" | "s" => {
+                self.step(Step::Into);
+                return Ok(Response::Run);
+            }
+            "over" | "o" => {
+                self.step(Step::Over);
+                return Ok(Response::Run);
+            }
+            "out" => {
+                self.step(Step::Out);
+                return Ok(Response::Run);
+            }
+            "scanline" => {
+                let line = parse_number(argument(1)?)?;
+                self.step(Step::Scanline(line as i32));
+                return Ok(Response::Run);
+            }
+            "frame" => {
+                self.step(Step::Frame);
+                return Ok(Response::Run);
+            }
+            "continue" | "c" => {
+                self.resume();
+                return Ok(Response::Run);
+            }
+            "list" | "l" => {
+                let breakpoints: Vec<String> = self.breakpoints.iter().map(|b| format!("${:04X}", b.address)).collect();
+                let watchpoints: Vec<String> = self.watchpoints.iter().map(|w| format!("${:04X}-${:04X}", w.start, w.end)).collect();
+                format!("break: {} watch: {}", breakpoints.join(" "), watchpoints.join(" "))
+            }
+            "" | "help" | "h" => HELP.to_string(),
+            other => return Err(format!("unknown command '{}', {}", other, HELP)),
+        };
+        return Ok(Response::Message(message));
+    }
+}
+
+#[test]
+fn test_conditions() {
+    let state = CpuState { a: 0x10, x: 3, p: 0x80, ..CpuState::default() };
+    assert!(Expr::parse("A == $10 && X > 2").unwrap().evaluate(&state, 0) != 0);
+    assert!(Expr::parse("a == 0x11 || (x >= 4)").unwrap().evaluate(&state, 0) == 0);
+    assert!(Expr::parse("P & $80").unwrap().evaluate(&state, 0) != 0);
+    assert!(Expr::parse("value != 7").unwrap().evaluate(&state, 7) == 0);
+    assert!(Expr::parse("A == ").is_err());
+    assert!(Expr::parse("A B").is_err());
+}
+
+#[test]
+fn test_commands() {
+    let mut debugger = Debugger::new();
+    assert!(!debugger.active());
+    debugger.command("break $C000 if X == 2").unwrap();
+    assert_eq!(debugger.breakpoints[0].address, 0xC000);
+    assert!(debugger.breakpoints[0].condition.is_some());
+    debugger.command("watch rw ppu $2000-$23FF").unwrap();
+    assert_eq!(
+        debugger.watchpoints[0],
+        Watchpoint { space: Space::Ppu, start: 0x2000, end: 0x23FF, access: Access::READ | Access::WRITE, condition: None }
+    );
+    assert_eq!(debugger.command("step"), Ok(Response::Run));
+    assert!(debugger.command("delete $8000").is_err());
+    assert!(debugger.command("watch q 10").is_err());
+    assert!(debugger.command("jump").is_err());
+    debugger.command("clear").unwrap();
+    assert!(debugger.breakpoints.is_empty() && debugger.watchpoints.is_empty());
+}
+
+#[test]
+fn test_stepping() {
+    use crate::cpu_6502::CPU6502;
+
+    let mut nes = CPU6502::new();
+    nes.power_on();
+    //LDA #$05, JSR $0310, STA $10, JMP $0307 and at $0310 INX, RTS
+    let program = [0xA9, 0x05, 0x20, 0x10, 0x03, 0x85, 0x10, 0x4C, 0x07, 0x03];
+    nes.bus.ram[0x0300..0x030A].copy_from_slice(&program);
+    nes.bus.ram[0x0310..0x0312].copy_from_slice(&[0xE8, 0x60]);
+    nes.pc = 0x0300;
+
+    nes.bus.debugger.add_breakpoint(0x0302, None);
+    assert_eq!(nes.run_frame(), Some(BreakReason::Breakpoint(0x0302)));
+    assert_eq!((nes.pc, nes.a), (0x0302, 0x05));
+
+    nes.bus.debugger.step(Step::Over);
+    assert_eq!(nes.run_frame(), Some(BreakReason::Step));
+    assert_eq!((nes.pc, nes.x), (0x0305, 1));
+
+    //The write finishes its instruction before stopping
+    nes.bus.debugger.command("watch w $10").unwrap();
+    nes.bus.debugger.resume();
+    let write = BreakReason::Watchpoint { space: Space::Cpu, address: 0x0010, access: Access::WRITE, value: 0x05 };
+    assert_eq!(nes.run_frame(), Some(write));
+    assert_eq!(nes.pc, 0x0307);
+
+    nes.bus.debugger.command("clear").unwrap();
+    nes.bus.debugger.add_breakpoint(0x0310, None);
+    nes.pc = 0x0302;
+    nes.bus.debugger.resume();
+    assert_eq!(nes.run_frame(), Some(BreakReason::Breakpoint(0x0310)));
+    nes.bus.debugger.step(Step::Out);
+    assert_eq!(nes.run_frame(), Some(BreakReason::Step));
+    assert_eq!((nes.pc, nes.x), (0x0305, 2));
+
+    //Spinning on the JMP with a condition that never holds runs the frame out
+    nes.bus.debugger.command("clear").unwrap();
+    nes.bus.debugger.command("break $0307 if X == 3").unwrap();
+    nes.bus.debugger.resume();
+    assert_eq!(nes.run_frame(), None);
+    nes.bus.debugger.command("break $0307 if X == 2").unwrap();
+    assert_eq!(nes.run_frame(), Some(BreakReason::Breakpoint(0x0307)));
+
+    nes.bus.debugger.command("clear").unwrap();
+    assert_eq!(nes.bus.debugger.command("scanline 100"), Ok(Response::Run));
+    assert_eq!(nes.run_frame(), Some(BreakReason::Scanline(100)));
+    assert_eq!(nes.bus.ppu.position().0, 100);
+
+    //NOP then an illegal opcode
+    nes.bus.ram[0x0320..0x0322].copy_from_slice(&[0xEA, 0x02]);
+    nes.pc = 0x0320;
+    nes.bus.debugger.command("ill").unwrap();
+    nes.bus.debugger.resume();
+    assert_eq!(nes.run_frame(), Some(BreakReason::IllegalOpcode { address: 0x0321, opcode: 0x02 }));
+}
@@ -0,0 +1,355 @@
+use crate::cpu_6502::CPU6502;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+//Symbol file labels are per 16KB PRG bank, as FCEUX numbers them
+const BANK_SIZE: usize = 0x4000;
+//Granularity the mapper's banking is checked at
+const PAGE_SIZE: usize = 0x1000;
+
+#[derive(Default)]
+pub struct Labels {
+    //Labels in a PRG bank, and the ones that apply wherever (RAM, registers, unbanked symbols)
+    banked: HashMap<(usize, u16), String>,
+    global: HashMap<u16, String>,
+}
+
+impl Labels {
+    pub fn new() -> Labels {
+        return Labels::default();
+    }
+
+    pub fn insert(&mut self, bank: Option<usize>, address: u16, name: &str) {
+        match bank {
+            Some(bank) => self.banked.insert((bank, address), name.to_string()),
+            None => self.global.insert(address, name.to_string()),
+        };
+    }
+
+    pub fn get(&self, bank: Option<usize>, address: u16) -> Option<&str> {
+        return bank
+            .and_then(|bank| self.banked.get(&(bank, address)))
+            .or_else(|| self.global.get(&address))
+            .map(|name| name.as_str());
+    }
+
+    pub fn len(&self) -> usize {
+        return self.banked.len() + self.global.len();
+    }
+
+    //FCEUX name lists, `$C000#name#comment` with an optional `/size` after the address. There is
+    //one file per PRG bank (game.nes.0.nl) and game.nes.ram.nl for everything below $8000
+    //http://www.fceux.com/web/help/fceux.html?NLFilesFormat.html
+    pub fn parse_nl(&mut self, text: &str, bank: Option<usize>) -> usize {
+        let mut count = 0;
+        for line in text.lines() {
+            let mut fields = line.trim().splitn(3, '#');
+            let address = fields.next().unwrap_or("");
+            let name = fields.next().unwrap_or("").trim();
+            if !address.starts_with('$') || name.is_empty() {
+                continue;
+            }
+            let address = address[1..].split('/').next().unwrap_or("");
+            if let Ok(address) = u16::from_str_radix(address, 16) {
+                self.insert(bank, address, name);
+                count += 1;
+            }
+        }
+        return count;
+    }
+
+    //ld65 --dbgfile output. Labels in segments written to the ROM are banked by where the segment
+    //sits in the file, which is assumed to start with the 16 byte iNES header
+    //https://cc65.github.io/doc/ld65.html#ss2.2
+    pub fn parse_dbg(&mut self, text: &str) -> usize {
+        let fields = |line: &str| -> HashMap<String, String> {
+            line.splitn(2, char::is_whitespace)
+                .nth(1)
+                .unwrap_or("")
+                .split(',')
+                .filter_map(|field| {
+                    let mut pair = field.splitn(2, '=');
+                    Some((pair.next()?.trim().to_string(), pair.next()?.trim_matches('"').to_string()))
+                })
+                .collect()
+        };
+        let number = |value: Option<&String>| -> Option<i64> {
+            let value = value?;
+            match value.strip_prefix("0x") {
+                Some(hex) => i64::from_str_radix(hex, 16).ok(),
+                None => value.parse().ok(),
+            }
+        };
+
+        //Segment id to (CPU start address, ROM file offset)
+        let mut segments = HashMap::new();
+        for line in text.lines().filter(|l| l.starts_with("seg")) {
+            let seg = fields(line);
+            if let (Some(id), Some(start)) = (seg.get("id"), number(seg.get("start"))) {
+                segments.insert(id.clone(), (start, number(seg.get("ooffs"))));
+            }
+        }
+
+        let mut count = 0;
+        for line in text.lines().filter(|l| l.starts_with("sym")) {
+            let sym = fields(line);
+            if sym.get("type").map(|t| t.as_str()) != Some("lab") {
+                continue;
+            }
+            let (name, address) = match (sym.get("name"), number(sym.get("val"))) {
+                (Some(name), Some(address)) if address >= 0 && address <= 0xFFFF => (name, address),
+                _ => continue,
+            };
+            let bank = match sym.get("seg").and_then(|id| segments.get(id)) {
+                Some(&(start, Some(offset))) if address >= 0x8000 => Some((offset - 16 + address - start).max(0) as usize / BANK_SIZE),
+                _ => None,
+            };
+            self.insert(bank, address as u16, name);
+            count += 1;
+        }
+        return count;
+    }
+
+    pub fn load(&mut self, path: &Path) -> Result<usize, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if name.ends_with(".dbg") {
+            return Ok(self.parse_dbg(&text));
+        }
+        if name.ends_with(".nl") {
+            //game.nes.A.nl is bank 10, FCEUX numbers banks in hex. game.nes.ram.nl isn't banked
+            let bank = name.trim_end_matches(".nl").rsplit('.').next().and_then(|b| usize::from_str_radix(b, 16).ok());
+            return Ok(self.parse_nl(&text, bank));
+        }
+        return Err(format!("{}: symbols should be a ca65 .dbg or FCEUX .nl file", path.display()));
+    }
+
+    //Symbol files next to the ROM: game.dbg, game.nes.ram.nl and game.nes.0.nl, game.nes.1.nl ...
+    pub fn find(rom: &str) -> Labels {
+        let mut labels = Labels::new();
+        let mut paths = vec![Path::new(rom).with_extension("dbg"), Path::new(&format!("{}.ram.nl", rom)).to_path_buf()];
+        paths.extend((0..256).map(|bank| Path::new(&format!("{}.{:X}.nl", rom, bank)).to_path_buf()));
+        for path in paths.iter().filter(|p| p.exists()) {
+            if let Err(e) = labels.load(path) {
+                println!("{}", e);
+            }
+        }
+        return labels;
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line {
+    pub address: u16,
+    pub size: u16,
+    pub label: Option<String>,
+    pub text: String,
+}
+
+//Decodes instructions on demand through Bus::peek_cpu, so it never disturbs the emulation. Lines
+//from ROM are kept until the mapper banks something else in, RAM is decoded fresh every time
+pub struct Disassembler {
+    pub labels: Labels,
+    //PRG offset of $8000, $9000 ... $F000 when the cached lines were decoded
+    pages: [Option<usize>; 8],
+    cache: HashMap<u16, Line>,
+}
+
+impl Disassembler {
+    pub fn new(labels: Labels) -> Disassembler {
+        Disassembler {
+            labels,
+            pages: [None; 8],
+            cache: HashMap::new(),
+        }
+    }
+
+    //Lines name their branch and jump targets, which may be in a different bank, so any switch
+    //starts the cache over
+    fn check_banks(&mut self, nes: &mut CPU6502) {
+        let mut pages = [None; 8];
+        for (page, offset) in pages.iter_mut().enumerate() {
+            *offset = nes.bus.prg_offset((0x8000 + page * PAGE_SIZE) as u16);
+        }
+        if pages != self.pages {
+            self.pages = pages;
+            self.cache.clear();
+        }
+    }
+
+    fn bank(nes: &mut CPU6502, address: u16) -> Option<usize> {
+        return nes.bus.prg_offset(address).map(|offset| offset / BANK_SIZE);
+    }
+
+    fn label(&self, nes: &mut CPU6502, address: u16) -> Option<String> {
+        let bank = Disassembler::bank(nes, address);
+        return self.labels.get(bank, address).map(|name| name.to_string());
+    }
+
+    //Label for an operand address, or the address in hex
+    fn operand(&self, nes: &mut CPU6502, address: u16, zero_page: bool) -> String {
+        match self.label(nes, address) {
+            Some(name) => name,
+            None if zero_page => format!("${:02X}", address),
+            None => format!("${:04X}", address),
+        }
+    }
+
+    fn decode(&self, nes: &mut CPU6502, address: u16) -> Line {
+        let opcode = nes.bus.peek_cpu(address);
+        let low = nes.bus.peek_cpu(address.wrapping_add(1));
+        let high = nes.bus.peek_cpu(address.wrapping_add(2));
+        let word = ((high as u16) << 8) | low as u16;
+        let (name, mode) = {
+            let (name, mode) = nes.instruction(opcode);
+            (name.to_string(), mode.to_string())
+        };
+        let (size, operand) = match mode.as_str() {
+            _ if name == "???" => (1, String::new()),
+            "IMP" if opcode & 0x9F == 0x0A => (1, "A".to_string()),
+            "IMP" => (1, String::new()),
+            "IMM" => (2, format!("#${:02X}", low)),
+            "ZP0" => (2, self.operand(nes, low as u16, true)),
+            "ZPX" => (2, format!("{},X", self.operand(nes, low as u16, true))),
+            "ZPY" => (2, format!("{},Y", self.operand(nes, low as u16, true))),
+            "IZX" => (2, format!("({},X)", self.operand(nes, low as u16, true))),
+            "IZY" => (2, format!("({}),Y", self.operand(nes, low as u16, true))),
+            "REL" => {
+                let target = address.wrapping_add(2).wrapping_add(low as i8 as u16);
+                (2, self.operand(nes, target, false))
+            }
+            "ABS" => (3, self.operand(nes, word, false)),
+            "ABX" => (3, format!("{},X", self.operand(nes, word, false))),
+            "ABY" => (3, format!("{},Y", self.operand(nes, word, false))),
+            "IND" => (3, format!("({})", self.operand(nes, word, false))),
+            _ => (1, String::new()),
+        };
+        let text = if name == "???" {
+            format!(".byte ${:02X}", opcode)
+        } else if operand.is_empty() {
+            name
+        } else {
+            format!("{} {}", name, operand)
+        };
+        Line {
+            address,
+            size,
+            label: self.label(nes, address),
+            text,
+        }
+    }
+
+    pub fn line(&mut self, nes: &mut CPU6502, address: u16) -> Line {
+        self.check_banks(nes);
+        if let Some(line) = self.cache.get(&address) {
+            return line.clone();
+        }
+        let line = self.decode(nes, address);
+        if address >= 0x8000 && self.pages[(address as usize - 0x8000) / PAGE_SIZE].is_some() {
+            self.cache.insert(address, line.clone());
+        }
+        return line;
+    }
+
+    //`count` instructions from `start` on
+    pub fn lines(&mut self, nes: &mut CPU6502, start: u16, count: usize) -> Vec<Line> {
+        let mut lines = Vec::with_capacity(count);
+        let mut address = start;
+        for _ in 0..count {
+            let line = self.line(nes, address);
+            address = address.wrapping_add(line.size);
+            lines.push(line);
+        }
+        return lines;
+    }
+}
+
+#[test]
+fn test_disassemble_with_labels() {
+    let mut nes = CPU6502::new();
+    //JSR sub, LDA #$05, STA $10,X, LDA ($20),Y, ASL A, BNE start, JMP ($0400), then an illegal opcode
+    let program = [0x20, 0x20, 0x03, 0xA9, 0x05, 0x95, 0x10, 0xB1, 0x20, 0x0A, 0xD0, 0xF4, 0x6C, 0x00, 0x04, 0x02];
+    nes.bus.ram[0x0300..0x0310].copy_from_slice(&program);
+    let mut labels = Labels::new();
+    labels.parse_nl("$0300#start#entry point\n$0320#sub#\n$0010/2#position#\nnot a label\n", None);
+    let mut disassembler = Disassembler::new(labels);
+
+    let lines: Vec<(u16, String)> = disassembler.lines(&mut nes, 0x0300, 8).into_iter().map(|l| (l.address, l.text)).collect();
+    assert_eq!(
+        lines,
+        vec![
+            (0x0300, "JSR sub".to_string()),
+            (0x0303, "LDA #$05".to_string()),
+            (0x0305, "STA position,X".to_string()),
+            (0x0307, "LDA ($20),Y".to_string()),
+            (0x0309, "ASL A".to_string()),
+            (0x030A, "BNE start".to_string()),
+            (0x030C, "JMP ($0400)".to_string()),
+            (0x030F, ".byte $02".to_string()),
+        ]
+    );
+    assert_eq!(disassembler.line(&mut nes, 0x0300).label, Some("start".to_string()));
+
+    //RAM is never cached
+    nes.bus.ram[0x0303] = 0xEA;
+    assert_eq!(disassembler.line(&mut nes, 0x0303).text, "NOP");
+}
+
+#[test]
+fn test_parse_dbg() {
+    let text = "version\tmajor=2,minor=0\n\
+        seg\tid=0,name=\"CODE\",start=0x00C000,size=0x0100,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16400\n\
+        seg\tid=1,name=\"BSS\",start=0x000300,size=0x0100,addrsize=absolute,type=rw\n\
+        sym\tid=0,name=\"reset\",addrsize=absolute,scope=0,def=1,val=0xC010,seg=0,type=lab\n\
+        sym\tid=1,name=\"buffer\",addrsize=absolute,scope=0,def=2,val=0x300,seg=1,type=lab\n\
+        sym\tid=2,name=\"SPEED\",addrsize=zeropage,scope=0,def=3,val=0x3,type=equ\n";
+    let mut labels = Labels::new();
+    assert_eq!(labels.parse_dbg(text), 2);
+    //16400 - 16 is the start of the second 16KB bank
+    assert_eq!(labels.get(Some(1), 0xC010), Some("reset"));
+    assert_eq!(labels.get(Some(0), 0xC010), None);
+    assert_eq!(labels.get(Some(0), 0x0300), Some("buffer"));
+    assert_eq!(labels.get(None, 0x0003), None);
+}
+
+#[test]
+fn test_bank_switch() {
+    use crate::cartridge::Cartridge;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    //UxROM with NOP filling bank 0 and INX filling bank 1, both labelled at $8000
+    let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 2, 1, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    rom.extend(vec![0xEA; BANK_SIZE]);
+    rom.extend(vec![0xE8; BANK_SIZE]);
+    rom.extend(vec![0; 8192]);
+    let path = std::env::temp_dir().join("disassembler_bank_switch.nes");
+    fs::write(&path, &rom).unwrap();
+    let mut nes = CPU6502::new();
+    nes.bus.connect_cartridge(Rc::new(RefCell::new(Cartridge::new(path.to_str().unwrap().to_string()).unwrap())));
+    fs::remove_file(&path).unwrap();
+    nes.power_on();
+
+    let mut labels = Labels::new();
+    labels.insert(Some(0), 0x8000, "nops");
+    labels.insert(Some(1), 0x8000, "inxs");
+    let mut disassembler = Disassembler::new(labels);
+    let line = disassembler.line(&mut nes, 0x8000);
+    assert_eq!((line.text.as_str(), line.label), ("NOP", Some("nops".to_string())));
+
+    nes.bus.cpu_write(0x8000, &mut 1);
+    let line = disassembler.line(&mut nes, 0x8000);
+    assert_eq!((line.text.as_str(), line.label), ("INX", Some("inxs".to_string())));
+}
+
+#[test]
+fn test_find_hex_banks() {
+    let rom = std::env::temp_dir().join("labels_hex_banks.nes");
+    let path = std::env::temp_dir().join("labels_hex_banks.nes.A.nl");
+    fs::write(&path, "$8000#reset#\n").unwrap();
+    let labels = Labels::find(rom.to_str().unwrap());
+    fs::remove_file(&path).unwrap();
+    assert_eq!(labels.get(Some(10), 0x8000), Some("reset"));
+    assert_eq!(labels.get(Some(0), 0x8000), None);
+}
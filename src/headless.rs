@@ -0,0 +1,67 @@
+use crate::cli::Options;
+use crate::cpu_6502::CPU6502;
+use crate::record::{RecordFormat, Recorder};
+use crate::scale::Overscan;
+use crate::screenshot;
+use crate::wav::AudioCapture;
+use std::path::Path;
+
+//Runs the requested number of frames as fast as possible without a window, for scripted captures
+//and golden images
+pub fn run(nes: &mut CPU6502, options: &Options, overscan: &Overscan) -> Result<(), String> {
+    let frames = options.headless.unwrap_or(0);
+
+    let mut capture = match options.wav {
+        Some(ref path) => Some(
+            AudioCapture::start(Path::new(path), &mut nes.bus.apu, options.split_channels)
+                .map_err(|e| format!("{}: {}", path, e))?,
+        ),
+        None => None,
+    };
+
+    let mut recorder = match options.record {
+        Some(ref path) => {
+            let format = if path.ends_with(".avi") { RecordFormat::Avi } else { RecordFormat::Png };
+            nes.bus.apu.set_capture(true);
+            Some(
+                Recorder::start(Path::new(path), format, overscan.width(), overscan.height(), nes.bus.region())
+                    .map_err(|e| format!("{}: {}", path, e))?,
+            )
+        }
+        None => None,
+    };
+
+    nes.power_on();
+    let mut image = screenshot::Image::new();
+    for _ in 0..frames {
+        nes.run_frame();
+        if let Some(ref mut capture) = capture {
+            capture.capture(&mut nes.bus.apu).map_err(|e| e.to_string())?;
+        }
+        if let Some(ref mut recording) = recorder {
+            screenshot::native_image_into(&nes.bus.ppu, overscan, &mut image);
+            recording.frame(&mut image, &nes.bus.apu.capture_samples)?;
+        }
+        nes.bus.apu.samples.clear();
+        nes.bus.apu.capture_samples.clear();
+    }
+
+    if let Some(capture) = capture {
+        let path = capture.path.clone();
+        capture.stop(&mut nes.bus.apu).map_err(|e| e.to_string())?;
+        println!("wrote {}", path.display());
+    }
+    if let Some(recording) = recorder {
+        let path = recording.path.clone();
+        let frames = recording.frames();
+        recording.stop().map_err(|e| e.to_string())?;
+        println!("wrote {} ({} frames)", path.display(), frames);
+    }
+    if let Some(ref path) = options.screenshot {
+        let mut image = screenshot::native_image(&nes.bus.ppu, overscan);
+        screenshot::save_png(&mut image, Path::new(path))?;
+        println!("wrote {}", path);
+    }
+    println!("ran {} frames", frames);
+    Ok(())
+}
@@ -3,7 +3,6 @@ extern crate sdl2;
 extern crate bitflags;
 #[macro_use]
 extern crate bitfield;
-use ppu::*;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
@@ -11,32 +10,79 @@ use sdl2::pixels::PixelFormatEnum;
 use sdl2::rect::Rect;
 use sdl2::render::Texture;
 use sdl2::render::TextureAccess;
+use sdl2::render::TextureCreator;
+use sdl2::video::WindowContext;
 
 use sdl2::render::WindowCanvas;
 use std::cell::RefCell;
 use std::fs::OpenOptions;
-use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::time::Instant;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub mod apu;
+pub mod avi;
 pub mod bus;
 pub mod cartridge;
+pub mod cli;
+pub mod config;
 pub mod cpu_6502;
+pub mod debugger;
+pub mod disassembler;
+pub mod headless;
 pub mod Mappers;
+pub mod ntsc;
+pub mod pacer;
+pub mod palette;
+pub mod power;
 pub mod ppu;
+pub mod record;
+pub mod region;
+pub mod resampler;
+pub mod scale;
+pub mod screenshot;
+pub mod viewer;
+pub mod wav;
 use sdl2::audio::{AudioSpecDesired, AudioQueue};
 
 static mut NES: Option<cpu_6502::CPU6502> = None;
 
+//Seconds of audio kept queued on the host, dynamic rate control steers towards this
+const AUDIO_LATENCY: f64 = 0.05;
+
+//Where the nametable viewer sits in the debug window
+const NAMETABLE_VIEW_X: i32 = 1030;
+const NAMETABLE_VIEW_Y: i32 = 10;
+//Palette RAM swatches, two palettes to a row with the sprite palettes in the bottom two rows
+const PALETTE_VIEW_X: i32 = 1030;
+const PALETTE_VIEW_Y: i32 = 660;
+const SWATCH_WIDTH: i32 = 62;
+const SWATCH_HEIGHT: i32 = 70;
+
+//Palette 0-7 of the swatch at a point in the debug window
+fn palette_at(x: i32, y: i32) -> Option<u8> {
+    let (column, row) = ((x - PALETTE_VIEW_X) / SWATCH_WIDTH, (y - PALETTE_VIEW_Y) / SWATCH_HEIGHT);
+    if x < PALETTE_VIEW_X || y < PALETTE_VIEW_Y || column >= 8 || row >= 4 {
+        return None;
+    }
+    return Some((row * 2 + column / 4) as u8);
+}
+
+fn audio_queued(audio: &AudioQueue<i16>) -> f64 {
+    let spec = audio.spec();
+    audio.size() as f64 / (2.0 * spec.channels as f64 * spec.freq as f64)
+}
+
 fn queue_audio(audio: &AudioQueue<i16>, nes: &mut cpu_6502::CPU6502) {
-    let samples = &nes.bus.apu.samples;
-    let sample = samples.as_slice();
-    if audio.size() as usize <= 2 * 8 
-    {
-        audio.queue(&sample);
+    let queued = audio_queued(audio);
+    if queued > AUDIO_LATENCY * 4.0 {
+        //Far too much latency built up (e.g. after a stall), start over rather than lag behind
+        audio.clear();
     }
+    nes.bus.apu.set_rate_control(audio_queued(audio) / AUDIO_LATENCY);
+
+    let samples = &nes.bus.apu.samples;
+    audio.queue(samples.as_slice());
     nes.bus.apu.samples.clear();
 }
 
@@ -47,7 +93,32 @@ macro_rules! rect(
     )
 );
 
-fn validate_rom() -> cartridge::Cartridge
+//Timestamped file next to the ROM, e.g. roms/mario-1613491380.wav, numbered when several are
+//made in the same second
+fn capture_path(rom: &str, extension: &str) -> PathBuf {
+    let rom = Path::new(rom);
+    let stem = rom.file_stem().and_then(|s| s.to_str()).unwrap_or("capture");
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut path = rom.with_file_name(format!("{}-{}.{}", stem, time, extension));
+    let mut count = 1;
+    while path.exists() {
+        path = rom.with_file_name(format!("{}-{}-{}.{}", stem, time, count, extension));
+        count += 1;
+    }
+    return path;
+}
+
+fn streaming_texture(creator: &TextureCreator<WindowContext>, width: usize, height: usize) -> Box<Texture> {
+    let tex = creator
+        .create_texture(PixelFormatEnum::RGB24, TextureAccess::Streaming, width as u32, height as u32)
+        .unwrap();
+    unsafe { Box::new(std::mem::transmute(tex)) }
+}
+
+fn validate_rom() -> (cartridge::Cartridge, String)
 {
     use std::io::{stdin};
     print!("{}[2J", 27 as char);
@@ -62,18 +133,65 @@ fn validate_rom() -> cartridge::Cartridge
         {
             println!("valid");
         }
-        let cartridge = cartridge::Cartridge::new(rom);
+        let cartridge = cartridge::Cartridge::new(rom.clone());
         match cartridge {
-            Ok(file) => return file,
+            Ok(file) => return (file, rom),
             Err(error) => println!("\n{}", error),
         };
     }
 }
 
 fn main() -> Result<(), String> {
+    let options = cli::Options::parse(std::env::args().skip(1))?;
+    let config = config::Config::load(config::CONFIG_PATH);
     let mut nes = cpu_6502::CPU6502::new();
-    let cartridge = validate_rom();
+    config.apply_audio(&mut nes.bus.apu);
+    let palette_setting = match options.palette {
+        Some(ref palette) => palette.clone(),
+        None => config.get_str("video.palette").unwrap_or("default").to_string(),
+    };
+    let output_palette = palette::resolve(&palette_setting)?;
+    nes.bus.ppu.set_output_palette(output_palette);
+    let mut palettes = palette::PaletteCycle::new(&palette_setting, output_palette);
+    let mut ntsc_filter = match config.get_str("video.filter").unwrap_or("none") {
+        "none" => None,
+        name => match ntsc::NtscPreset::from_name(name) {
+            Some(preset) => Some(ntsc::NtscFilter::new(preset)),
+            None => return Err(format!("config: unknown video.filter '{}'", name)),
+        },
+    };
+    let mut scaler = match scale::ScaleFilter::from_name(config.get_str("video.scaler").unwrap_or("none")) {
+        Some(filter) => scale::Scaler::new(filter),
+        None => return Err(format!("config: unknown video.scaler '{}'", config.get_str("video.scaler").unwrap_or(""))),
+    };
+    let mut window_scale = (config.get_f64("video.scale", 4.0) as u32).max(1).min(scale::MAX_SCALE);
+    let aspect_correct = config.get_bool("video.aspect_correct", true);
+    let overscan = config.overscan();
+    let screenshot_scaled = config.get_bool("video.screenshot_scaled", false);
+    let record_format = match record::RecordFormat::from_name(config.get_str("video.record_format").unwrap_or("avi")) {
+        Some(format) => format,
+        None => return Err(format!("config: unknown video.record_format '{}'", config.get_str("video.record_format").unwrap_or(""))),
+    };
+    let (cartridge, rom_path) = match options.rom {
+        Some(ref rom) => (
+            cartridge::Cartridge::new(rom.clone()).map_err(|e| format!("{}: {}", rom, e))?,
+            rom.clone(),
+        ),
+        None => validate_rom(),
+    };
+    let region = options.region.unwrap_or(cartridge.header.region());
+    println!("region: {}", region.name());
     nes.bus.connect_cartridge(Rc::new(RefCell::new(cartridge)));
+    nes.bus.set_region(region);
+    nes.bus.ppu.sprite_limit = config.get_bool("video.sprite_limit", true);
+    nes.bus.ram_init = config.ram_init()?;
+
+    if options.headless.is_some() {
+        nes.bus
+            .apu
+            .set_sample_rate(config.get_f64("audio.sample_rate", apu::DEFAULT_SAMPLE_RATE));
+        return headless::run(&mut nes, &options, &overscan);
+    }
 
     let sdl_context = sdl2::init()?;
     let video_subsys = sdl_context.video()?;
@@ -84,7 +202,7 @@ fn main() -> Result<(), String> {
     let audio_subsystem = sdl_context.audio().unwrap();
 
     let desired_spec = AudioSpecDesired {
-        freq: Some(44100),
+        freq: Some(config.get_f64("audio.sample_rate", apu::DEFAULT_SAMPLE_RATE) as i32),
         channels: Some(1), // mono
         samples: None,     // default sample size
     };
@@ -114,15 +232,17 @@ fn main() -> Result<(), String> {
 
     let device = audio_subsystem.open_queue::<i16, _>(None, &desired_spec)?;
     device.resume();
+    nes.bus.apu.set_sample_rate(device.spec().freq as f64);
 
     let debug_window = video_subsys
-        .window("Debug Window", 1024, 960)
+        .window("Debug Window", 1552, 960)
         .position_centered()
         .opengl()
         .build()
         .map_err(|e| e.to_string())?;
+    let (window_width, window_height) = scale::display_size(window_scale, aspect_correct, &overscan);
     let main_window = video_subsys
-        .window("NES Emulator", 1024, 960)
+        .window("NES Emulator", window_width, window_height)
         .opengl()
         .build()
         .map_err(|e| e.to_string())?;
@@ -138,16 +258,12 @@ fn main() -> Result<(), String> {
         .map_err(|e| e.to_string())?;
 
     let tx1 = main_canvas.texture_creator();
-    let mut screen_texture: Box<sdl2::render::Texture> = {
-        let tex = tx1
-            .create_texture(
-                PixelFormatEnum::RGB24,
-                TextureAccess::Streaming,
-                RENDER_WIDTH as u32,
-                RENDER_HEIGHT as u32,
-            )
-            .unwrap();
-        unsafe { Box::new(std::mem::transmute(tex)) }
+    let mut screen_texture = streaming_texture(&tx1, ppu::RENDER_WIDTH, ppu::RENDER_HEIGHT);
+    let (ntsc_width, ntsc_height) = overscan.cropped_size(ntsc::NTSC_WIDTH, ntsc::NTSC_HEIGHT);
+    let mut ntsc_texture = streaming_texture(&tx1, ntsc_width, ntsc_height);
+    let mut scaled_texture = {
+        let (width, height) = scaler.output_size(overscan.width(), overscan.height());
+        streaming_texture(&tx1, width, height)
     };
 
     let tx2 = debug_canvas.texture_creator();
@@ -176,16 +292,50 @@ fn main() -> Result<(), String> {
         unsafe { Box::new(std::mem::transmute(tex3)) }
     };
 
+    let tx4 = debug_canvas.texture_creator();
+    let mut nametable_texture = streaming_texture(&tx4, viewer::NAMETABLE_VIEW_WIDTH, viewer::NAMETABLE_VIEW_HEIGHT);
+    let mut sprite_texture = streaming_texture(&tx4, 64 * 8, 16);
+    let debug_window_id = debug_canvas.window().id();
+    //Point in the nametable view under the mouse
+    let mut nametable_hover: Option<(usize, usize)> = None;
+
     // Load a font
     let mut font = ttf_context.load_font(font_path, 128)?;
     font.set_style(sdl2::ttf::FontStyle::BOLD);
 
-    nes.reset();
-    let disassembly = nes.disassemble(0x0000, 0xFFFF);
+    nes.power_on();
+    let mut labels = disassembler::Labels::find(&rom_path);
+    if let Some(ref path) = options.symbols {
+        if let Err(e) = labels.load(Path::new(path)) {
+            println!("{}", e);
+        }
+    }
+    if labels.len() > 0 {
+        println!("{} labels", labels.len());
+    }
+    let mut disassembler = disassembler::Disassembler::new(labels);
     let mut emulation_run = true;
-    let mut time: f32 = 0.0;
+    let mut frame_advance = false;
+    let mut wav_capture: Option<wav::AudioCapture> = None;
+    let mut recorder: Option<record::Recorder> = None;
+    //Reused every frame by the recorder and the filtered display
+    let mut record_image = screenshot::Image::new();
+    let mut display_image = screenshot::Image::new();
+    let mut pacer = pacer::FramePacer::new(nes.bus.region().frame_rate());
+    pacer.audio_target = AUDIO_LATENCY;
 
     let mut debug = false;
+    //Outlines every sprite over the game screen
+    let mut sprite_boxes = false;
+    //Palette the pattern tables are drawn with, 0-3 background and 4-7 sprites
+    let mut pattern_palette: u8 = 0;
+    //Debugger command being typed into the debug window, and the last thing it said
+    let mut console = String::new();
+    let mut console_message = String::from(debugger::HELP);
+    //Return in the debug window starts typing a command, Escape goes back to the hotkeys
+    let mut console_focus = false;
+    //The NTSC filter only runs on new frames, each run moves the dot crawl on a frame
+    let mut frame_ready = true;
     debug_canvas.window_mut().hide();
 
     let mut a_pressed = false;
@@ -200,7 +350,6 @@ fn main() -> Result<(), String> {
     unsafe {
         NES = Some(nes);
     }
-    let mut now = Instant::now();
     'mainloop: loop {
         let mut global_nes = unsafe { NES.as_mut().unwrap() };
         global_nes.bus.controller[0] = 0x00;
@@ -232,6 +381,71 @@ fn main() -> Result<(), String> {
         for event in sdl_context.event_pump()?.poll_iter() {
             use sdl2::controller::Button;
             match event {
+                Event::KeyDown {
+                    window_id,
+                    keycode: Some(Keycode::Return | Keycode::KpEnter),
+                    ..
+                } if window_id == debug_window_id && !console_focus => {
+                    console_focus = true;
+                }
+                //While the console has focus typing goes to it rather than the hotkeys
+                Event::TextInput { window_id, text, .. } if window_id == debug_window_id && console_focus => {
+                    console.push_str(&text);
+                }
+                Event::KeyDown {
+                    window_id,
+                    keycode: Some(key),
+                    ..
+                } if window_id == debug_window_id && console_focus && !matches!(key, Keycode::F6 | Keycode::F7 | Keycode::F8 | Keycode::F9) => {
+                    match key {
+                        Keycode::Return | Keycode::KpEnter => {
+                            match global_nes.bus.debugger.command(&console) {
+                                Ok(debugger::Response::Message(message)) => console_message = message,
+                                Ok(debugger::Response::Run) => {
+                                    console_message = String::new();
+                                    console_focus = false;
+                                    emulation_run = true;
+                                }
+                                Err(e) => console_message = e,
+                            }
+                            console.clear();
+                        }
+                        Keycode::Backspace => {
+                            console.pop();
+                        }
+                        Keycode::Escape => {
+                            console.clear();
+                            console_focus = false;
+                        }
+                        _ => {}
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(key @ (Keycode::F6 | Keycode::F7 | Keycode::F8)), //Step into, over and out
+                    ..
+                } => {
+                    let step = match key {
+                        Keycode::F6 => debugger::Step::Into,
+                        Keycode::F7 => debugger::Step::Over,
+                        _ => debugger::Step::Out,
+                    };
+                    global_nes.bus.debugger.step(step);
+                    emulation_run = true;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9), //Continue, with shift toggle a breakpoint at PC
+                    keymod,
+                    ..
+                } => {
+                    if keymod.intersects(sdl2::keyboard::Mod::LSHIFTMOD | sdl2::keyboard::Mod::RSHIFTMOD) {
+                        let pc = global_nes.pc;
+                        let set = global_nes.bus.debugger.toggle_breakpoint(pc);
+                        console_message = format!("breakpoint at ${:04X} {}", pc, if set { "set" } else { "removed" });
+                    } else {
+                        global_nes.bus.debugger.resume();
+                        emulation_run = true;
+                    }
+                }
                 Event::KeyDown {
                     keycode: Some(Keycode::X), //B
                     ..
@@ -390,16 +604,230 @@ fn main() -> Result<(), String> {
                 }
 
                 Event::KeyDown {
-                    keycode: Some(Keycode::R),
+                    keycode: Some(Keycode::R), //Reset, with shift power cycle
+                    keymod,
                     ..
                 } => {
-                    global_nes.reset();
+                    if keymod.intersects(sdl2::keyboard::Mod::LSHIFTMOD | sdl2::keyboard::Mod::RSHIFTMOD) {
+                        global_nes.power_on();
+                    } else {
+                        global_nes.reset();
+                    }
                 }
                 Event::KeyDown {
                     keycode: Some(Keycode::Space),
                     ..
                 } => {
                     emulation_run = !emulation_run;
+                    if emulation_run {
+                        global_nes.bus.debugger.resume();
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::N), //Frame advance
+                    ..
+                } => {
+                    if !emulation_run {
+                        global_nes.bus.debugger.resume();
+                        frame_advance = true;
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Tab), //Fast forward while held
+                    repeat: false,
+                    ..
+                } => {
+                    pacer.fast_forward = true;
+                }
+                Event::KeyUp {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => {
+                    pacer.fast_forward = false;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F), //Cycle fast forward speed
+                    ..
+                } => {
+                    pacer.fast_forward_speed = pacer.fast_forward_speed.next();
+                    println!("fast forward: {:?}", pacer.fast_forward_speed);
+                }
+                Event::KeyDown {
+                    keycode: Some(key @ (Keycode::F1 | Keycode::F2 | Keycode::F3 | Keycode::F4 | Keycode::F5)),
+                    keymod,
+                    ..
+                } => {
+                    //Toggle, or with shift solo, a channel
+                    let channel = match key {
+                        Keycode::F1 => apu::Channel::Pulse0,
+                        Keycode::F2 => apu::Channel::Pulse1,
+                        Keycode::F3 => apu::Channel::Triangle,
+                        Keycode::F4 => apu::Channel::Noise,
+                        _ => apu::Channel::Dmc,
+                    };
+                    let apu = &mut global_nes.bus.apu;
+                    if keymod.intersects(sdl2::keyboard::Mod::LSHIFTMOD | sdl2::keyboard::Mod::RSHIFTMOD) {
+                        apu.solo(channel);
+                    } else {
+                        let enabled = apu.channel(channel).enabled;
+                        apu.set_channel_enabled(channel, !enabled);
+                    }
+                    for &c in apu::CHANNELS.iter() {
+                        print!("{}: {}  ", c.name(), if apu.channel(c).enabled { "on" } else { "off" });
+                    }
+                    println!();
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::W), //Start/stop wav capture, with shift split per channel
+                    keymod,
+                    ..
+                } => {
+                    match wav_capture.take() {
+                        Some(capture) => {
+                            let path = capture.path.clone();
+                            match capture.stop(&mut global_nes.bus.apu) {
+                                Ok(()) => println!("wrote {}", path.display()),
+                                Err(e) => println!("{}: {}", path.display(), e),
+                            }
+                        }
+                        None => {
+                            let split = keymod.intersects(sdl2::keyboard::Mod::LSHIFTMOD | sdl2::keyboard::Mod::RSHIFTMOD);
+                            let path = capture_path(&rom_path, "wav");
+                            match wav::AudioCapture::start(&path, &mut global_nes.bus.apu, split) {
+                                Ok(capture) => {
+                                    println!("recording audio to {}", path.display());
+                                    wav_capture = Some(capture);
+                                }
+                                Err(e) => println!("{}: {}", path.display(), e),
+                            }
+                        }
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::P), //Cycle built in palettes and any loaded .pal file
+                    ..
+                } => {
+                    let (name, output_palette) = palettes.next();
+                    global_nes.bus.ppu.set_output_palette(output_palette);
+                    println!("palette: {}", name);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::O), //Toggle the 8 sprites per line limit
+                    ..
+                } => {
+                    global_nes.bus.ppu.sprite_limit = !global_nes.bus.ppu.sprite_limit;
+                    println!("sprite limit: {}", if global_nes.bus.ppu.sprite_limit { "on" } else { "off" });
+                }
+                Event::MouseButtonDown { window_id, x, y, .. } if window_id == debug_window_id => {
+                    if let Some(palette) = palette_at(x, y) {
+                        pattern_palette = palette;
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::C), //Cycle the pattern table palette, shift goes back
+                    keymod,
+                    ..
+                } => {
+                    if keymod.intersects(sdl2::keyboard::Mod::LSHIFTMOD | sdl2::keyboard::Mod::RSHIFTMOD) {
+                        pattern_palette = (pattern_palette + 7) % 8;
+                    } else {
+                        pattern_palette = (pattern_palette + 1) % 8;
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::B), //Sprite bounding boxes
+                    ..
+                } => {
+                    sprite_boxes = !sprite_boxes;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::V), //Cycle NTSC filter presets, then off
+                    ..
+                } => {
+                    let next = match ntsc_filter {
+                        None => Some(ntsc::NTSC_PRESETS[0]),
+                        Some(ref filter) => ntsc::NTSC_PRESETS
+                            .iter()
+                            .position(|&p| p == filter.preset)
+                            .and_then(|i| ntsc::NTSC_PRESETS.get(i + 1).copied()),
+                    };
+                    ntsc_filter = next.map(ntsc::NtscFilter::new);
+                    frame_ready = true;
+                    println!("ntsc filter: {}", next.map(|p| p.name()).unwrap_or("none"));
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::G), //Cycle scaling filters
+                    ..
+                } => {
+                    scaler.filter = scaler.filter.next();
+                    let (width, height) = scaler.output_size(overscan.width(), overscan.height());
+                    scaled_texture = streaming_texture(&tx1, width, height);
+                    println!("scaler: {}", scaler.filter.name());
+                }
+                Event::KeyDown {
+                    keycode: Some(key @ (Keycode::Equals | Keycode::KpPlus | Keycode::Minus | Keycode::KpMinus)), //Window size
+                    ..
+                } => {
+                    window_scale = match key {
+                        Keycode::Equals | Keycode::KpPlus => (window_scale + 1).min(scale::MAX_SCALE),
+                        _ => (window_scale - 1).max(1),
+                    };
+                    let (width, height) = scale::display_size(window_scale, aspect_correct, &overscan);
+                    if let Err(e) = main_canvas.window_mut().set_size(width, height) {
+                        println!("{}", e);
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12), //Screenshot
+                    ..
+                } => {
+                    let path = capture_path(&rom_path, "png");
+                    let mut image = screenshot::native_image(&global_nes.bus.ppu, &overscan);
+                    match screenshot::save_png(&mut image, &path) {
+                        Ok(()) => println!("wrote {}", path.display()),
+                        Err(e) => println!("{}", e),
+                    }
+                    if screenshot_scaled {
+                        let path = screenshot::scaled_path(&path);
+                        let mut image = screenshot::display_image(&global_nes.bus.ppu, &mut scaler, &ntsc_filter, &overscan);
+                        match screenshot::save_png(&mut image, &path) {
+                            Ok(()) => println!("wrote {}", path.display()),
+                            Err(e) => println!("{}", e),
+                        }
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F10), //Start/stop video recording
+                    ..
+                } => {
+                    match recorder.take() {
+                        Some(recording) => {
+                            let path = recording.path.clone();
+                            let frames = recording.frames();
+                            match recording.stop() {
+                                Ok(()) => println!("wrote {} ({} frames)", path.display(), frames),
+                                Err(e) => println!("{}: {}", path.display(), e),
+                            }
+                        }
+                        None => {
+                            let path = capture_path(&rom_path, record_format.extension());
+                            let region = global_nes.bus.region();
+                            match record::Recorder::start(&path, record_format, overscan.width(), overscan.height(), region) {
+                                Ok(recording) => {
+                                    println!("recording video to {}", path.display());
+                                    global_nes.bus.apu.set_capture(true);
+                                    recorder = Some(recording);
+                                }
+                                Err(e) => println!("{}: {}", path.display(), e),
+                            }
+                        }
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::L), //Slow motion
+                    ..
+                } => {
+                    pacer.slow_motion = !pacer.slow_motion;
                 }
                 Event::KeyDown {
                     keycode: Some(Keycode::M),
@@ -413,6 +841,18 @@ fn main() -> Result<(), String> {
                         debug_canvas.window_mut().hide();
                     }
                 }
+                Event::MouseMotion { window_id, x, y, .. } if window_id == debug_window_id => {
+                    let (x, y) = (x - NAMETABLE_VIEW_X, y - NAMETABLE_VIEW_Y);
+                    nametable_hover = if x >= 0
+                        && y >= 0
+                        && (x as usize) < viewer::NAMETABLE_VIEW_WIDTH
+                        && (y as usize) < viewer::NAMETABLE_VIEW_HEIGHT
+                    {
+                        Some((x as usize, y as usize))
+                    } else {
+                        None
+                    };
+                }
                 Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
@@ -421,27 +861,49 @@ fn main() -> Result<(), String> {
                 _ => {}
             }
         }
-        //clock_nes(global_nes);
         main_canvas.clear();
         debug_canvas.clear();
-        if time > 0.0 {
-            time = time - (now.elapsed().as_secs_f32());
-        } 
-        else 
-        {
-            time = time + (0.16666) - now.elapsed().as_secs_f32();
-            clock_nes(global_nes);
-            queue_audio(&device, global_nes);
-            now = Instant::now();
+        if emulation_run || frame_advance {
+            frame_advance = false;
+            frame_ready = true;
+            //A frame the debugger stopped part way through is captured once it finishes
+            if let Some(reason) = global_nes.run_frame() {
+                emulation_run = false;
+                console_message = format!("stopped: {}", reason);
+                println!("{} (PC ${:04X})", console_message, global_nes.pc);
+            } else {
+                if let Some(ref mut capture) = wav_capture {
+                    if let Err(e) = capture.capture(&mut global_nes.bus.apu) {
+                        println!("{}: {}", capture.path.display(), e);
+                        wav_capture = None;
+                    }
+                }
+                if let Some(ref mut recording) = recorder {
+                    screenshot::native_image_into(&global_nes.bus.ppu, &overscan, &mut record_image);
+                    if let Err(e) = recording.frame(&mut record_image, &global_nes.bus.apu.capture_samples) {
+                        println!("{}: {}", recording.path.display(), e);
+                        recorder = None;
+                    }
+                }
+                global_nes.bus.apu.capture_samples.clear();
+                //Off once neither needs it, including when a write error dropped one
+                global_nes.bus.apu.set_capture(wav_capture.is_some() || recorder.is_some());
+                if emulation_run && pacer.is_realtime() {
+                    queue_audio(&device, global_nes);
+                } else {
+                    global_nes.bus.apu.samples.clear();
+                }
+            }
         }
         if debug == true {
-            draw_debug(&mut debug_canvas, &mut global_nes, &font, &disassembly);
+            draw_debug(&mut debug_canvas, &mut global_nes, &font, &mut disassembler);
             render_pattern_table(
                 &mut debug_canvas,
                 &mut global_nes,
                 rect!(10, 694, 256, 256),
                 &mut pattern_one,
                 0,
+                pattern_palette,
             );
             render_pattern_table(
                 &mut debug_canvas,
@@ -449,16 +911,33 @@ fn main() -> Result<(), String> {
                 rect!(276, 694, 256, 256),
                 &mut pattern_two,
                 1,
+                pattern_palette,
             );
+            render_nametables(&mut debug_canvas, &mut global_nes, &mut nametable_texture, nametable_hover, &font);
+            render_sprites(&mut debug_canvas, &mut global_nes, &mut sprite_texture, &font);
+            render_palettes(&mut debug_canvas, &mut global_nes, pattern_palette, &font);
+            render_console(&mut debug_canvas, &global_nes, emulation_run, console_focus.then(|| console.as_str()), &console_message, &font);
         }
         render_frame(
             &mut main_canvas,
             &mut global_nes,
-            rect!(0, 0, RENDER_WIDTH * 4, RENDER_HEIGHT * 4),
             &mut screen_texture,
+            &mut scaler,
+            &mut scaled_texture,
+            &mut ntsc_filter,
+            &mut ntsc_texture,
+            &overscan,
+            &mut display_image,
+            frame_ready,
         );
+        frame_ready = false;
+        if sprite_boxes {
+            draw_sprite_boxes(&mut main_canvas, &global_nes, &overscan);
+        }
         main_canvas.present();
         debug_canvas.present();
+
+        pacer.wait(Some(audio_queued(&device)));
     }
     Ok(())
 }
@@ -483,7 +962,7 @@ fn draw_debug(
     debug_canvas: &mut WindowCanvas,
     nes: &mut cpu_6502::CPU6502,
     font: &sdl2::ttf::Font,
-    disassembly: &HashMap<u32, String>,
+    disassembler: &mut disassembler::Disassembler,
 ) {
     let pc = nes.pc;
     {
@@ -632,87 +1111,54 @@ fn draw_debug(
         &font,
         Color::WHITE,
     );
-    let mut i = 0;
-
-    for x in 0..20 {
-        let val = nes.pc as u32 + x as u32;
-        let end = disassembly.capacity() as u32;
-        if val <= end {
-            let iteration = disassembly.get(&(val));
-            let text = String::from("Error");
-            let val = iteration.unwrap_or(&text);
-            if val != "Error" {
-                i = i + 1;
-                draw_line(
-                    rect!(10, 170 + (i * 50), 300, 40),
-                    &val,
-                    debug_canvas,
-                    &font,
-                    Color::WHITE,
-                );
-            }
-        }
-    }
-
-    for x in 0..10 {
-        let mut sprite_debug = "".to_owned();
-        sprite_debug.push_str(&(format!("{}: ", x)));
-        sprite_debug.push_str(&(format!("({}", nes.bus.ppu.oam_ram[x * 4 + 3])));
-        sprite_debug.push_str(&(format!(", {})", nes.bus.ppu.oam_ram[x * 4 + 0])));
-        sprite_debug.push_str(&(format!(" ID:{}", nes.bus.ppu.oam_ram[x * 4 + 1])));
-        sprite_debug.push_str(&(format!(" AT:{}", nes.bus.ppu.oam_ram[x * 4 + 2])));
-
+    for (i, line) in disassembler.lines(nes, pc, 20).iter().enumerate() {
+        //Lines with a breakpoint are red
+        let breakpoint = nes.bus.debugger.breakpoints.iter().any(|b| b.address == line.address);
+        let text = match line.label {
+            Some(ref label) => format!("${:04X} {}: {}", line.address, label, line.text),
+            None => format!("${:04X}: {}", line.address, line.text),
+        };
         draw_line(
-            rect!(400, 170 + (x * 50), 300, 40),
-            &sprite_debug,
+            rect!(10, 220 + (i * 50), 300, 40),
+            &text,
             debug_canvas,
             &font,
-            Color::WHITE,
-        )
+            if breakpoint { Color::RED } else { Color::WHITE },
+        );
     }
 }
 
-fn clock_nes(global_nes: &mut cpu_6502::CPU6502){
-    let mut clock_count = 0;
-    while global_nes.bus.ppu.frame_complete == false 
-    {
-        clock_count += 1;
-        global_nes.bus.clock();
-        if global_nes.bus.dma_transfer == true {
-            if global_nes.bus.dma_buffer == true {
-                if clock_count % 2 == 1 {
-                    global_nes.bus.dma_buffer = false;
-                }
-            } else {
-                if clock_count % 2 == 0 {
-                    let page = (global_nes.bus.dma_page as u16) << 8;
-                    let address = global_nes.bus.dma_address as u16;
-                    global_nes.bus.dma_data = global_nes.bus.cpu_read(page | address, false);
-                } else {
-                    global_nes.bus.ppu.oam_ram[global_nes.bus.dma_address as usize] = global_nes.bus.dma_data;
-                    if global_nes.bus.dma_address != 255 {
-                        global_nes.bus.dma_address += 1;
-                    } else {
-                        global_nes.bus.dma_address = 0x00;
-                        global_nes.bus.dma_transfer = false;
-                        global_nes.bus.dma_buffer = true;
-                    }
-                }
-            }
-        } else {
-            global_nes.clock();
-        }
-    }
-    global_nes.bus.ppu.frame_complete = false;
-}
 fn render_frame(
     canvas: &mut WindowCanvas,
     nes: &mut cpu_6502::CPU6502,
-    rect: sdl2::rect::Rect,
     tex: &mut Texture,
+    scaler: &mut scale::Scaler,
+    scaled_tex: &mut Texture,
+    ntsc_filter: &mut Option<ntsc::NtscFilter>,
+    ntsc_tex: &mut Texture,
+    overscan: &scale::Overscan,
+    image: &mut screenshot::Image,
+    frame_ready: bool,
 ) {
-    let frame_data = nes.bus.ppu.render();
-    tex.update(None, &frame_data, 256 * 3).unwrap();
+    //Fills the window, which is sized to the integer scale and aspect
+    let (width, height) = canvas.output_size().unwrap();
+    let rect = rect!(0, 0, width, height);
+    if ntsc_filter.is_none() && scaler.filter == scale::ScaleFilter::None {
+        //Straight from the PPU's buffer, overscan is left out by the source rect
+        let ppu = &nes.bus.ppu;
+        tex.update(None, ppu.frame_buffer(), ppu.frame_pitch()).unwrap();
+        let source = rect!(overscan.left, overscan.top, overscan.width(), overscan.height());
+        canvas.copy(&tex, Some(source), Some(rect)).unwrap();
+        return;
+    }
+    if let Some(filter) = ntsc_filter {
+        if frame_ready {
+            filter.filter(nes.bus.ppu.raw_frame());
+        }
+    }
+    screenshot::display_image_into(&nes.bus.ppu, scaler, ntsc_filter, overscan, image);
+    let tex = if ntsc_filter.is_some() { ntsc_tex } else { scaled_tex };
+    tex.update(None, &image.pixels, image.width * 3).unwrap();
     canvas.copy(&tex, None, Some(rect)).unwrap();
 }
 
@@ -722,10 +1168,174 @@ fn render_pattern_table(
     rect: sdl2::rect::Rect,
     tex: &mut Texture,
     index: u8,
+    palette: u8,
 ) {
-    let frame_data = nes.bus.ppu.get_pattern_table(index, 0);
-    tex.update(None, &frame_data, 128 * 3).unwrap();
+    let frame_data = nes.bus.ppu.get_pattern_table(index, palette);
+    tex.update(None, frame_data, 128 * 3).unwrap();
     canvas.copy(&tex, None, Some(rect)).unwrap();
 }
 
+//All four nametables with the scroll for the next frame (t) outlined and the current VRAM
+//address (v) marked, plus details of the tile under the mouse
+fn render_nametables(
+    canvas: &mut WindowCanvas,
+    nes: &mut cpu_6502::CPU6502,
+    tex: &mut Texture,
+    hover: Option<(usize, usize)>,
+    font: &sdl2::ttf::Font,
+) {
+    let ppu = &nes.bus.ppu;
+    let image = viewer::nametables(ppu);
+    tex.update(None, &image.pixels, image.width * 3).unwrap();
+    canvas
+        .copy(&tex, None, Some(rect!(NAMETABLE_VIEW_X, NAMETABLE_VIEW_Y, image.width, image.height)))
+        .unwrap();
+
+    let (v, t, fine_x) = ppu.scroll_registers();
+    let (scroll_x, scroll_y) = viewer::scroll_position(t, fine_x);
+    canvas.set_draw_color(Color::YELLOW);
+    for (x, y, w, h) in viewer::viewport_rects(scroll_x, scroll_y) {
+        canvas
+            .draw_rect(rect!(NAMETABLE_VIEW_X + x as i32, NAMETABLE_VIEW_Y + y as i32, w, h))
+            .unwrap();
+    }
+    let (vram_x, vram_y) = viewer::scroll_position(v, fine_x);
+    canvas.set_draw_color(Color::RED);
+    canvas
+        .draw_rect(rect!(NAMETABLE_VIEW_X + vram_x as i32 - 2, NAMETABLE_VIEW_Y + vram_y as i32 - 2, 5, 5))
+        .unwrap();
+
+    if let Some((x, y)) = hover {
+        let info = viewer::nametable_tile_at(ppu, x, y);
+        canvas.set_draw_color(Color::WHITE);
+        canvas
+            .draw_rect(rect!(NAMETABLE_VIEW_X + (x as i32 & !7), NAMETABLE_VIEW_Y + (y as i32 & !7), 8, 8))
+            .unwrap();
+        let lines = [
+            format!("NT:{} ({}, {}) ${:04X}", info.nametable, info.column, info.row, info.address),
+            format!("Tile:${:02X}", info.tile),
+            format!("AT:${:02X} @ ${:04X} PAL:{}", info.attribute, info.attribute_address, info.palette),
+        ];
+        for (i, line) in lines.iter().enumerate() {
+            draw_line(
+                rect!(NAMETABLE_VIEW_X, NAMETABLE_VIEW_Y + 490 + i as i32 * 50, line.len() * 20, 40),
+                line,
+                canvas,
+                font,
+                Color::WHITE,
+            );
+        }
+    }
+    canvas.set_draw_color(Color::BLACK);
+}
+
+//All 64 OAM entries, 4 to a row, with a preview of each sprite. Sprite 0 is outlined in red and
+//sprites on the PPU's current scanline in yellow
+fn render_sprites(canvas: &mut WindowCanvas, nes: &mut cpu_6502::CPU6502, tex: &mut Texture, font: &sdl2::ttf::Font) {
+    let ppu = &nes.bus.ppu;
+    let sheet = viewer::sprite_sheet(ppu);
+    tex.update(None, &sheet.pixels, sheet.width * 3).unwrap();
+    let height = ppu.sprite_height();
+    let (scanline, _) = ppu.position();
+    for index in 0..64 {
+        let info = viewer::sprite(ppu, index);
+        let x = 400 + (index % 4) as i32 * 150;
+        let y = 170 + (index / 4) as i32 * 32;
+        canvas
+            .copy(&tex, Some(rect!(index * 8, 0, 8, height)), Some(rect!(x, y, 16, height * 2)))
+            .unwrap();
+        let outline = if index == 0 {
+            Some(Color::RED)
+        } else if viewer::sprite_on_scanline(ppu, &info, scanline) {
+            Some(Color::YELLOW)
+        } else {
+            None
+        };
+        if let Some(colour) = outline {
+            canvas.set_draw_color(colour);
+            canvas.draw_rect(rect!(x - 2, y - 1, 148, 32)).unwrap();
+        }
+        let position = format!("{:02} X:{:03} Y:{:03}", index, info.x, info.y);
+        let pattern = format!("T:${:02X} A:${:02X}", info.tile, info.attribute);
+        draw_line(rect!(x + 20, y, position.len() * 8, 15), &position, canvas, font, Color::WHITE);
+        draw_line(rect!(x + 20, y + 15, pattern.len() * 8, 15), &pattern, canvas, font, Color::WHITE);
+    }
+    canvas.set_draw_color(Color::BLACK);
+}
+
+//Sprite outlines over the game screen, scaled to the window like the picture is
+fn draw_sprite_boxes(canvas: &mut WindowCanvas, nes: &cpu_6502::CPU6502, overscan: &scale::Overscan) {
+    let (width, height) = canvas.output_size().unwrap();
+    let scale_x = width as f64 / overscan.width() as f64;
+    let scale_y = height as f64 / overscan.height() as f64;
+    let ppu = &nes.bus.ppu;
+    for index in 0..64 {
+        let info = viewer::sprite(ppu, index);
+        //Y of $EF and above never shows
+        if info.y >= 0xEF {
+            continue;
+        }
+        let x = (info.x as f64 - overscan.left as f64) * scale_x;
+        let y = (info.y as f64 + 1.0 - overscan.top as f64) * scale_y;
+        canvas.set_draw_color(if index == 0 { Color::RED } else { Color::GREEN });
+        canvas
+            .draw_rect(rect!(x, y, 8.0 * scale_x, ppu.sprite_height() as f64 * scale_y))
+            .unwrap();
+    }
+    canvas.set_draw_color(Color::BLACK);
+}
+
+//Every palette RAM entry with its address and colour index, the palette used for the pattern
+//tables is outlined
+fn render_palettes(canvas: &mut WindowCanvas, nes: &mut cpu_6502::CPU6502, selected: u8, font: &sdl2::ttf::Font) {
+    let ppu = &nes.bus.ppu;
+    for (i, (address, colour)) in viewer::palette_entries(ppu).into_iter().enumerate() {
+        let x = PALETTE_VIEW_X + (i as i32 % 8) * SWATCH_WIDTH;
+        let y = PALETTE_VIEW_Y + (i as i32 / 8) * SWATCH_HEIGHT;
+        let (r, g, b) = ppu.output_colour(colour as u16);
+        canvas.set_draw_color(Color::RGB(r, g, b));
+        canvas.fill_rect(rect!(x + 2, y, SWATCH_WIDTH - 4, 36)).unwrap();
+        draw_line(rect!(x + 2, y + 38, 40, 15), &format!("{:04X}", address), canvas, font, Color::WHITE);
+        draw_line(rect!(x + 2, y + 53, 30, 15), &format!("${:02X}", colour), canvas, font, Color::WHITE);
+    }
+    let x = PALETTE_VIEW_X + (selected as i32 % 2) * SWATCH_WIDTH * 4;
+    let y = PALETTE_VIEW_Y + (selected as i32 / 2) * SWATCH_HEIGHT;
+    canvas.set_draw_color(Color::WHITE);
+    canvas.draw_rect(rect!(x, y - 2, SWATCH_WIDTH * 4, SWATCH_HEIGHT)).unwrap();
+    canvas.set_draw_color(Color::BLACK);
+}
+
+//Debugger state and the command line, typed into while the debug window has focus
+fn render_console(
+    canvas: &mut WindowCanvas,
+    nes: &cpu_6502::CPU6502,
+    running: bool,
+    console: Option<&str>,
+    message: &str,
+    font: &sdl2::ttf::Font,
+) {
+    let debugger = &nes.bus.debugger;
+    let (scanline, cycle) = nes.bus.ppu.position();
+    let status = match debugger.break_reason() {
+        Some(reason) => format!("Stopped: {}", reason),
+        None if running => "Running".to_string(),
+        None => "Paused".to_string(),
+    };
+    let status = format!("{} - line {} dot {}", status, scanline, cycle);
+    let breakpoints: Vec<String> = debugger.breakpoints.iter().map(|b| format!("${:04X}", b.address)).collect();
+    let points = format!("BP: {} WP: {}", breakpoints.join(" "), debugger.watchpoints.len());
+    let prompt = match console {
+        Some(console) => format!("> {}_", console),
+        None => "Return to type a command".to_string(),
+    };
+    let lines = [(status, Color::YELLOW), (points, Color::WHITE), (message.to_string(), Color::WHITE), (prompt, Color::GREEN)];
+    for (i, (line, colour)) in lines.iter().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let width = (line.len() * 10).min(620);
+        draw_line(rect!(400, 10 + i * 38, width, 30), line, canvas, font, *colour);
+    }
+}
+
 //#[cfg(test)]
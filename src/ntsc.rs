@@ -0,0 +1,181 @@
+use crate::palette::{chroma_angle, normalised_signal, yiq_to_rgb};
+use crate::ppu::{RENDER_HEIGHT, RENDER_WIDTH};
+
+//https://wiki.nesdev.com/w/index.php/NTSC_video
+//Every PPU dot is 8 master clocks and the colour subcarrier is 12 master clocks long, so the
+//signal is sampled at 8 points per pixel and a pixel covers two thirds of a colour cycle.
+const SAMPLES_PER_PIXEL: usize = 8;
+const LINE_SAMPLES: usize = RENDER_WIDTH * SAMPLES_PER_PIXEL;
+//Blank signal either side of the picture so the filters have something to read, a whole
+//number of colour cycles to keep the phase lined up
+const PADDING: usize = 24;
+//A scanline is 341 * 8 master clocks, 4 more than a multiple of 12, so each line starts a
+//third of a cycle later than the one above
+const LINE_PHASE_STEP: usize = 4;
+
+//Output is wider than the PPU picture, which gives room for the artifacts between pixels and
+//is already 4:3 once each line is doubled
+pub const NTSC_WIDTH: usize = 640;
+pub const NTSC_HEIGHT: usize = RENDER_HEIGHT;
+pub const NTSC_FULL: usize = NTSC_WIDTH * NTSC_HEIGHT * 3;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NtscPreset {
+    Rf,
+    Composite,
+    SVideo,
+}
+
+pub const NTSC_PRESETS: [NtscPreset; 3] = [NtscPreset::Rf, NtscPreset::Composite, NtscPreset::SVideo];
+
+impl NtscPreset {
+    pub fn name(&self) -> &'static str {
+        match self {
+            NtscPreset::Rf => "rf",
+            NtscPreset::Composite => "composite",
+            NtscPreset::SVideo => "svideo",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<NtscPreset> {
+        NTSC_PRESETS.iter().find(|p| p.name() == name).copied()
+    }
+
+    //Samples averaged for luma, anything under a full cycle lets chroma through as dot crawl
+    fn luma_width(&self) -> usize {
+        match self {
+            NtscPreset::Rf => 4,
+            NtscPreset::Composite => 6,
+            NtscPreset::SVideo => 12,
+        }
+    }
+
+    //Samples averaged for I and Q, wider smears colour sideways
+    fn chroma_width(&self) -> usize {
+        match self {
+            NtscPreset::Rf => 36,
+            NtscPreset::Composite => 24,
+            NtscPreset::SVideo => 12,
+        }
+    }
+
+    fn noise(&self) -> f64 {
+        match self {
+            NtscPreset::Rf => 0.03,
+            _ => 0.0,
+        }
+    }
+}
+
+//Encodes the raw 9 bit pixels (colour index and emphasis) into a composite signal and decodes
+//it again with the chosen preset's filters
+pub struct NtscFilter {
+    pub preset: NtscPreset,
+    frame_phase: usize,
+    noise_seed: u32,
+    //Running sums of the level, level * cos and level * sin for box filtering
+    sums: Vec<(f64, f64, f64)>,
+    output: Vec<u8>,
+}
+
+impl NtscFilter {
+    pub fn new(preset: NtscPreset) -> NtscFilter {
+        NtscFilter {
+            preset,
+            frame_phase: 0,
+            noise_seed: 1,
+            sums: vec![(0.0, 0.0, 0.0); LINE_SAMPLES + PADDING * 2 + 1],
+            output: vec![0; NTSC_FULL],
+        }
+    }
+
+    fn noise(&mut self) -> f64 {
+        //xorshift, only needs to look random
+        self.noise_seed ^= self.noise_seed << 13;
+        self.noise_seed ^= self.noise_seed >> 17;
+        self.noise_seed ^= self.noise_seed << 5;
+        return (self.noise_seed as f64 / u32::MAX as f64 - 0.5) * 2.0 * self.preset.noise();
+    }
+
+    pub fn filter(&mut self, pixels: &[u16]) -> &[u8] {
+        for y in 0..NTSC_HEIGHT {
+            let line_phase = self.frame_phase + y * LINE_PHASE_STEP;
+            let row = &pixels[y * RENDER_WIDTH..(y + 1) * RENDER_WIDTH];
+
+            let mut total = (0.0, 0.0, 0.0);
+            for s in 0..LINE_SAMPLES + PADDING * 2 {
+                let phase = (line_phase + s) % 12;
+                let mut level = 0.0;
+                if s >= PADDING && s < PADDING + LINE_SAMPLES {
+                    let pixel = row[(s - PADDING) / SAMPLES_PER_PIXEL] as usize;
+                    level = normalised_signal(pixel & 0x3F, pixel >> 6, phase);
+                }
+                level += self.noise();
+                let angle = chroma_angle(phase);
+                total.0 += level;
+                total.1 += level * angle.cos();
+                total.2 += level * angle.sin();
+                self.sums[s + 1] = total;
+            }
+
+            let luma = self.preset.luma_width();
+            let chroma = self.preset.chroma_width();
+            for x in 0..NTSC_WIDTH {
+                let centre = PADDING + ((2 * x + 1) * LINE_SAMPLES) / (2 * NTSC_WIDTH);
+                let luma_sum = self.sums[centre + luma - luma / 2].0 - self.sums[centre - luma / 2].0;
+                let chroma_start = &self.sums[centre - chroma / 2];
+                let chroma_end = &self.sums[centre + chroma - chroma / 2];
+
+                let (r, g, b) = yiq_to_rgb(
+                    luma_sum / luma as f64,
+                    2.0 * (chroma_end.1 - chroma_start.1) / chroma as f64,
+                    2.0 * (chroma_end.2 - chroma_start.2) / chroma as f64,
+                );
+                let i = (y * NTSC_WIDTH + x) * 3;
+                self.output[i] = r;
+                self.output[i + 1] = g;
+                self.output[i + 2] = b;
+            }
+        }
+
+        //A frame is 4 master clocks past a whole number of cycles, but with rendering on odd frames
+        //are a dot (8 clocks) shorter, so the pattern alternates between two phases
+        self.frame_phase = if self.frame_phase == 0 { 4 } else { 0 };
+        return &self.output;
+    }
+
+    //The last filtered frame, for screenshots without moving the phase on
+    pub fn output(&self) -> &[u8] {
+        return &self.output;
+    }
+}
+
+#[test]
+fn test_svideo_matches_palette() {
+    let palette = crate::palette::generate_2c02();
+    let mut filter = NtscFilter::new(NtscPreset::SVideo);
+    for &colour in [0x16u16, 0x1A, 0x12, 0x30, 0x0F].iter() {
+        let pixels = vec![colour; RENDER_WIDTH * RENDER_HEIGHT];
+        let frame = filter.filter(&pixels).to_vec();
+        let i = (100 * NTSC_WIDTH + NTSC_WIDTH / 2) * 3;
+        let expected = palette[colour as usize];
+        let close = |a: u8, b: u8| (a as i32 - b as i32).abs() <= 2;
+        assert!(close(frame[i], expected.0) && close(frame[i + 1], expected.1) && close(frame[i + 2], expected.2));
+    }
+}
+
+#[test]
+fn test_composite_dot_crawl() {
+    //Thin stripes of grey and white, which composite turns into colour that changes each frame
+    let pixels: Vec<u16> = (0..RENDER_WIDTH * RENDER_HEIGHT).map(|i| if i % 2 == 0 { 0x00 } else { 0x30 }).collect();
+    let mut filter = NtscFilter::new(NtscPreset::Composite);
+    let first = filter.filter(&pixels).to_vec();
+    let second = filter.filter(&pixels).to_vec();
+    assert!(first != second);
+    assert!(filter.output() == &second[..]);
+    let third = filter.filter(&pixels).to_vec();
+    assert_eq!(first, third);
+
+    let i = (100 * NTSC_WIDTH + NTSC_WIDTH / 2) * 3;
+    assert!(first[i] != first[i + 1] || first[i + 1] != first[i + 2]);
+}
@@ -0,0 +1,160 @@
+use std::time::{Duration, Instant};
+
+//https://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+pub const NTSC_FRAME_RATE: f64 = 60.0988;
+
+//Sleep granularity on most hosts is around a millisecond, the rest is spun off
+const SPIN_THRESHOLD: Duration = Duration::from_micros(1500);
+//If the host falls this far behind we stop trying to catch up
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Speed {
+    Double,
+    Quadruple,
+    Uncapped,
+}
+
+impl Speed {
+    pub fn next(self) -> Speed {
+        match self {
+            Speed::Double => Speed::Quadruple,
+            Speed::Quadruple => Speed::Uncapped,
+            Speed::Uncapped => Speed::Double,
+        }
+    }
+
+    pub fn multiplier(self) -> Option<f64> {
+        match self {
+            Speed::Double => Some(2.0),
+            Speed::Quadruple => Some(4.0),
+            Speed::Uncapped => None,
+        }
+    }
+}
+
+pub struct FramePacer {
+    frame_rate: f64,
+    next_frame: Instant,
+
+    pub fast_forward: bool,
+    pub fast_forward_speed: Speed,
+    pub slow_motion: bool,
+    pub slow_motion_factor: f64,
+
+    //Seconds of audio we aim to keep queued when audio drives the clock
+    pub audio_target: f64,
+}
+
+impl FramePacer {
+    pub fn new(frame_rate: f64) -> FramePacer {
+        FramePacer {
+            frame_rate,
+            next_frame: Instant::now(),
+            fast_forward: false,
+            fast_forward_speed: Speed::Double,
+            slow_motion: false,
+            slow_motion_factor: 0.25,
+            audio_target: 0.05,
+        }
+    }
+
+    pub fn set_frame_rate(&mut self, frame_rate: f64) {
+        self.frame_rate = frame_rate;
+    }
+
+    //True when emulation runs at the real console rate, audio is only queued in this case
+    pub fn is_realtime(&self) -> bool {
+        !self.fast_forward && !self.slow_motion
+    }
+
+    //Length of one emulated frame on the host, None when uncapped
+    pub fn frame_duration(&self) -> Option<Duration> {
+        let mut rate = self.frame_rate;
+        if self.fast_forward {
+            match self.fast_forward_speed.multiplier() {
+                Some(m) => rate *= m,
+                None => return None,
+            }
+        } else if self.slow_motion {
+            rate *= self.slow_motion_factor;
+        }
+        Some(Duration::from_secs_f64(1.0 / rate))
+    }
+
+    //Blocks until the next frame is due. When the amount of queued audio is known it is used as
+    //the master clock: running ahead of the target fill level stretches the frame, running
+    //behind shortens it, so the queue neither drains nor grows without bound.
+    pub fn wait(&mut self, audio_queued: Option<f64>) {
+        let frame = match self.frame_duration() {
+            Some(frame) => frame,
+            None => {
+                self.next_frame = Instant::now();
+                return;
+            }
+        };
+
+        let mut deadline = self.next_frame + frame;
+        if let (Some(queued), true) = (audio_queued, self.is_realtime()) {
+            let error = (queued - self.audio_target).max(-frame.as_secs_f64()).min(frame.as_secs_f64());
+            let correction = Duration::from_secs_f64(error.abs() * 0.5);
+            if error > 0.0 {
+                deadline += correction;
+            } else {
+                deadline -= correction;
+            }
+        }
+
+        let now = Instant::now();
+        if now > deadline + MAX_LAG {
+            //Host stalled (window drag, breakpoint...), resynchronise instead of fast forwarding
+            self.next_frame = now;
+            return;
+        }
+
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            let remaining = deadline - now;
+            if remaining > SPIN_THRESHOLD {
+                std::thread::sleep(remaining - SPIN_THRESHOLD);
+            } else {
+                std::thread::yield_now();
+            }
+        }
+        self.next_frame = deadline;
+    }
+}
+
+#[test]
+fn test_frame_duration() {
+    let mut pacer = FramePacer::new(NTSC_FRAME_RATE);
+    let normal = pacer.frame_duration().unwrap().as_secs_f64();
+    assert!((normal - 1.0 / 60.0988).abs() < 1e-9);
+
+    pacer.fast_forward = true;
+    assert!((pacer.frame_duration().unwrap().as_secs_f64() - normal / 2.0).abs() < 1e-9);
+    pacer.fast_forward_speed = pacer.fast_forward_speed.next();
+    assert!((pacer.frame_duration().unwrap().as_secs_f64() - normal / 4.0).abs() < 1e-9);
+    pacer.fast_forward_speed = pacer.fast_forward_speed.next();
+    assert!(pacer.frame_duration().is_none());
+
+    pacer.fast_forward = false;
+    pacer.slow_motion = true;
+    assert!((pacer.frame_duration().unwrap().as_secs_f64() - normal * 4.0).abs() < 1e-9);
+    assert_eq!(pacer.is_realtime(), false);
+}
+
+#[test]
+fn test_wait_paces_frames() {
+    let mut pacer = FramePacer::new(NTSC_FRAME_RATE);
+    let start = Instant::now();
+    pacer.wait(None);
+    for _ in 0..5 {
+        pacer.wait(None);
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    assert!(elapsed >= 5.0 / NTSC_FRAME_RATE);
+}
@@ -0,0 +1,251 @@
+use crate::ppu::{build_output_palette, OUTPUT_PALETTE_SIZE, SYSTEM_PALETTE};
+use std::f64::consts::PI;
+use std::fs;
+
+pub type OutputPalette = [(u8, u8, u8); OUTPUT_PALETTE_SIZE];
+
+//https://wiki.nesdev.com/w/index.php/NTSC_video#Terminated_measurement
+//Composite voltages of the 2C02G for each luma row, low and high half of the colour wave
+pub const SIGNAL_LOW: [f64; 4] = [0.228, 0.312, 0.552, 0.880];
+pub const SIGNAL_HIGH: [f64; 4] = [0.616, 0.840, 1.100, 1.100];
+pub const SIGNAL_BLACK: f64 = 0.312;
+pub const SIGNAL_WHITE: f64 = 1.100;
+//Emphasised phases are attenuated to roughly 74.6%
+pub const SIGNAL_EMPHASIS: f64 = 0.746;
+//Phase of the colour burst, lines the decoded hues up with the 2C02's
+const BURST_PHASE: f64 = 4.0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BuiltinPalette {
+    Default,
+    Hardware,
+    Greyscale,
+}
+
+pub const BUILTIN_PALETTES: [BuiltinPalette; 3] =
+    [BuiltinPalette::Default, BuiltinPalette::Hardware, BuiltinPalette::Greyscale];
+
+impl BuiltinPalette {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BuiltinPalette::Default => "default",
+            BuiltinPalette::Hardware => "2c02",
+            BuiltinPalette::Greyscale => "greyscale",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<BuiltinPalette> {
+        BUILTIN_PALETTES.iter().find(|p| p.name() == name).copied()
+    }
+
+    pub fn next(&self) -> BuiltinPalette {
+        let i = BUILTIN_PALETTES.iter().position(|p| p == self).unwrap_or(0);
+        return BUILTIN_PALETTES[(i + 1) % BUILTIN_PALETTES.len()];
+    }
+
+    pub fn output_palette(&self) -> OutputPalette {
+        match self {
+            BuiltinPalette::Default => build_output_palette(&SYSTEM_PALETTE),
+            BuiltinPalette::Hardware => generate_2c02(),
+            BuiltinPalette::Greyscale => build_output_palette(&greyscale()),
+        }
+    }
+}
+
+//Voltage of the composite signal for a colour at one of the 12 subcarrier phases
+pub fn signal(colour: usize, emphasis: usize, phase: usize) -> f64 {
+    let hue = colour & 0x0F;
+    let row = if hue > 0x0D { 1 } else { (colour >> 4) & 0x03 };
+    let in_phase = |c: usize| (c + phase) % 12 < 6;
+
+    let mut high = SIGNAL_HIGH[row];
+    let mut low = SIGNAL_LOW[row];
+    if hue == 0x00 {
+        low = high;
+    }
+    if hue > 0x0C {
+        high = low;
+    }
+    let mut level = if in_phase(hue) { high } else { low };
+
+    //Red, green and blue emphasis line up with hues $C, $4 and $8
+    if hue < 0x0E
+        && ((emphasis & 0x01 != 0 && in_phase(0x0C))
+            || (emphasis & 0x02 != 0 && in_phase(0x04))
+            || (emphasis & 0x04 != 0 && in_phase(0x08)))
+    {
+        level *= SIGNAL_EMPHASIS;
+    }
+    return level;
+}
+
+//0.0 at black and 1.0 at white
+pub fn normalised_signal(colour: usize, emphasis: usize, phase: usize) -> f64 {
+    return (signal(colour, emphasis, phase) - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK);
+}
+
+//Angle of the subcarrier for demodulating I and Q at one of the 12 phases
+pub fn chroma_angle(phase: usize) -> f64 {
+    return PI * (phase as f64 + BURST_PHASE) / 6.0;
+}
+
+//https://wiki.nesdev.com/w/index.php/NTSC_video#Converting_YIQ_to_RGB
+pub fn yiq_to_rgb(y: f64, i: f64, q: f64) -> (u8, u8, u8) {
+    let convert = |v: f64| {
+        //The TV's gamma is higher than a PC monitor's
+        let v = if v < 0.0 { 0.0 } else { v.powf(2.2 / 1.8) };
+        (v * 255.0).round().min(255.0) as u8
+    };
+    return (
+        convert(y + 0.946882 * i + 0.623557 * q),
+        convert(y - 0.274788 * i - 0.635691 * q),
+        convert(y - 1.108545 * i + 1.709007 * q),
+    );
+}
+
+//Decodes the signal of every colour like a TV would, giving a palette close to real hardware
+pub fn generate_2c02() -> OutputPalette {
+    let mut palette = [(0, 0, 0); OUTPUT_PALETTE_SIZE];
+    for emphasis in 0..8 {
+        for colour in 0..64 {
+            let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+            for phase in 0..12 {
+                let level = normalised_signal(colour, emphasis, phase);
+                let angle = chroma_angle(phase);
+                y += level;
+                i += level * angle.cos();
+                q += level * angle.sin();
+            }
+            //Averaging cos^2 over a cycle halves the chroma, so I and Q are doubled
+            palette[emphasis << 6 | colour] = yiq_to_rgb(y / 12.0, i / 6.0, q / 6.0);
+        }
+    }
+    return palette;
+}
+
+//Luma of the default palette, for checking brightness levels without hue
+pub fn greyscale() -> [(u8, u8, u8); 64] {
+    let mut palette = [(0, 0, 0); 64];
+    for (grey, &(r, g, b)) in palette.iter_mut().zip(SYSTEM_PALETTE.iter()) {
+        let luma = (0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64).round() as u8;
+        *grey = (luma, luma, luma);
+    }
+    return palette;
+}
+
+//Standard .pal files: 64 RGB triples, or 512 with every emphasis combination
+pub fn parse_pal(bytes: &[u8]) -> Result<OutputPalette, String> {
+    let rgb = |i: usize| (bytes[i * 3], bytes[i * 3 + 1], bytes[i * 3 + 2]);
+    match bytes.len() {
+        192 => {
+            let mut base = [(0, 0, 0); 64];
+            for (i, colour) in base.iter_mut().enumerate() {
+                *colour = rgb(i);
+            }
+            Ok(build_output_palette(&base))
+        }
+        1536 => {
+            let mut palette = [(0, 0, 0); OUTPUT_PALETTE_SIZE];
+            for (i, colour) in palette.iter_mut().enumerate() {
+                *colour = rgb(i);
+            }
+            Ok(palette)
+        }
+        size => Err(format!("expected a 192 or 1536 byte palette, got {} bytes", size)),
+    }
+}
+
+pub fn load_pal(path: &str) -> Result<OutputPalette, String> {
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    parse_pal(&bytes).map_err(|e| format!("{}: {}", path, e))
+}
+
+//Either the name of a built in palette or the path of a .pal file
+pub fn resolve(setting: &str) -> Result<OutputPalette, String> {
+    match BuiltinPalette::from_name(setting) {
+        Some(builtin) => Ok(builtin.output_palette()),
+        None => load_pal(setting),
+    }
+}
+
+//What the palette hotkey steps through, the built in palettes then a .pal file if one was loaded
+pub struct PaletteCycle {
+    file: Option<(String, OutputPalette)>,
+    //None while the file is showing
+    current: Option<BuiltinPalette>,
+}
+
+impl PaletteCycle {
+    //setting and palette as they came from resolve
+    pub fn new(setting: &str, palette: OutputPalette) -> PaletteCycle {
+        let current = BuiltinPalette::from_name(setting);
+        PaletteCycle {
+            file: if current.is_none() { Some((setting.to_string(), palette)) } else { None },
+            current,
+        }
+    }
+
+    //Moves on and returns the name and colours of the new palette
+    pub fn next(&mut self) -> (&str, OutputPalette) {
+        self.current = match (self.current, &self.file) {
+            (Some(p), Some(_)) if p.next() == BUILTIN_PALETTES[0] => None,
+            (Some(p), _) => Some(p.next()),
+            (None, _) => Some(BUILTIN_PALETTES[0]),
+        };
+        match (self.current, &self.file) {
+            (Some(p), _) => (p.name(), p.output_palette()),
+            (None, Some((name, palette))) => (name, *palette),
+            (None, None) => unreachable!(),
+        }
+    }
+}
+
+#[test]
+fn test_parse_pal() {
+    let mut bytes = vec![0u8; 192];
+    bytes[0x16 * 3] = 200;
+    let palette = parse_pal(&bytes).unwrap();
+    assert_eq!(palette[0x16], (200, 0, 0));
+    assert_eq!(palette[0x40 | 0x16], (200, 0, 0));
+
+    let bytes: Vec<u8> = (0..1536).map(|i| (i / 3) as u8).collect();
+    let palette = parse_pal(&bytes).unwrap();
+    assert_eq!(palette[0x1FF], (0xFF, 0xFF, 0xFF));
+    assert_eq!(palette[0x41], (0x41, 0x41, 0x41));
+
+    assert!(parse_pal(&[0; 100]).is_err());
+}
+
+#[test]
+fn test_generate_2c02() {
+    let palette = generate_2c02();
+    let (r, g, b) = palette[0x16];
+    assert!(r > g && r > b);
+    let (r, g, b) = palette[0x1A];
+    assert!(g > r && g > b);
+    let (r, g, b) = palette[0x12];
+    assert!(b > r && b > g);
+    assert_eq!(palette[0x0F], (0, 0, 0));
+    assert_eq!(palette[0x20].0, 255);
+
+    //Red emphasis darkens a white more in green and blue than in red
+    let (r, g, b) = palette[0x40 | 0x30];
+    assert!(r > g && r > b);
+}
+
+#[test]
+fn test_palette_cycle() {
+    let mut cycle = PaletteCycle::new("2c02", generate_2c02());
+    let names: Vec<String> = (0..4).map(|_| cycle.next().0.to_string()).collect();
+    assert_eq!(names, ["greyscale", "default", "2c02", "greyscale"]);
+
+    //A loaded file comes round again after the built in ones
+    let file = [(1, 2, 3); OUTPUT_PALETTE_SIZE];
+    let mut cycle = PaletteCycle::new("my.pal", file);
+    let names: Vec<String> = (0..4).map(|_| cycle.next().0.to_string()).collect();
+    assert_eq!(names, ["default", "2c02", "greyscale", "my.pal"]);
+    assert_eq!(cycle.next().0, "default");
+    cycle.next();
+    cycle.next();
+    assert!(cycle.next().1[..] == file[..]);
+}
@@ -0,0 +1,70 @@
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+
+//What RAM, OAM, palette and nametable memory hold after a power cycle. Real consoles power up
+//with mostly random contents, some games only work (or only show their bugs) with one of these
+//https://wiki.nesdev.com/w/index.php/CPU_power_up_state
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RamInit {
+    Zero,
+    Ones,
+    Random,
+    //Random but the same every power cycle
+    Seeded(u64),
+}
+
+impl RamInit {
+    pub fn from_name(name: &str, seed: u64) -> Option<RamInit> {
+        match name {
+            "zero" => Some(RamInit::Zero),
+            "ff" => Some(RamInit::Ones),
+            "random" => Some(RamInit::Random),
+            "seeded" => Some(RamInit::Seeded(seed)),
+            _ => None,
+        }
+    }
+
+    //One filler per power cycle, so each memory it fills gets different bytes
+    pub fn filler(&self) -> RamFiller {
+        let rng = match self {
+            RamInit::Random => StdRng::from_entropy(),
+            RamInit::Seeded(seed) => StdRng::seed_from_u64(*seed),
+            _ => StdRng::seed_from_u64(0),
+        };
+        RamFiller { init: *self, rng }
+    }
+}
+
+pub struct RamFiller {
+    init: RamInit,
+    rng: StdRng,
+}
+
+impl RamFiller {
+    pub fn fill(&mut self, memory: &mut [u8]) {
+        match self.init {
+            RamInit::Zero => memory.iter_mut().for_each(|b| *b = 0x00),
+            RamInit::Ones => memory.iter_mut().for_each(|b| *b = 0xFF),
+            RamInit::Random | RamInit::Seeded(_) => self.rng.fill_bytes(memory),
+        }
+    }
+}
+
+#[test]
+fn test_ram_init() {
+    let mut ram = [0x55u8; 64];
+    RamInit::Ones.filler().fill(&mut ram);
+    assert!(ram.iter().all(|&b| b == 0xFF));
+
+    let init = RamInit::from_name("seeded", 42).unwrap();
+    let mut filler = init.filler();
+    filler.fill(&mut ram);
+    let mut oam = [0u8; 64];
+    filler.fill(&mut oam);
+    assert!(ram != oam);
+
+    let mut again = [0u8; 64];
+    init.filler().fill(&mut again);
+    assert_eq!(ram, again);
+    assert_eq!(RamInit::from_name("sometimes", 0), None);
+}
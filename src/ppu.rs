@@ -1,12 +1,48 @@
 use crate::cartridge;
 use crate::cartridge::Cartridge;
 use crate::Mappers::mapper::Mirroring;
+use crate::power::RamFiller;
+use crate::region::Region;
 use std::cell::RefCell;
 use std::rc::Rc;
 pub const RENDER_WIDTH: usize = 256;
 pub const RENDER_HEIGHT: usize = 240;
 pub const RENDER_SIZE: usize = RENDER_WIDTH * RENDER_HEIGHT;
 pub const RENDER_FULL: usize = RENDER_SIZE * 3;
+pub const PATTERN_SIZE: usize = 128 * 128;
+//64 colours for each of the 8 combinations of the emphasis bits in PPUMASK
+pub const OUTPUT_PALETTE_SIZE: usize = 64 * 8;
+//https://wiki.nesdev.com/w/index.php/NTSC_video#Color_Tint_Bits
+const EMPHASIS_ATTENUATION: f32 = 0.84;
+//Bits of the I/O latch fade to 0 about 600ms after they were last driven
+//https://wiki.nesdev.com/w/index.php/Open_bus_behavior#PPU_open_bus
+const OPEN_BUS_DECAY_FRAMES: u32 = 36;
+
+//Byte order in memory of the converted frame buffer. On little endian hosts Rgba8888 and
+//Bgra8888 are SDL's ABGR8888 and ARGB8888.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PixelFormat {
+    Rgb24,
+    Rgba8888,
+    Bgra8888,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Rgb24 => 3,
+            PixelFormat::Rgba8888 | PixelFormat::Bgra8888 => 4,
+        }
+    }
+
+    fn write(&self, out: &mut [u8], (r, g, b): (u8, u8, u8)) {
+        match self {
+            PixelFormat::Rgb24 => out.copy_from_slice(&[r, g, b]),
+            PixelFormat::Rgba8888 => out.copy_from_slice(&[r, g, b, 0xFF]),
+            PixelFormat::Bgra8888 => out.copy_from_slice(&[b, g, r, 0xFF]),
+        }
+    }
+}
 
 //https://wiki.nesdev.com/w/index.php/PPU_registers
 bitfield! {
@@ -73,8 +109,14 @@ pub struct PPU {
     pattern_table: [[u8; 4096]; 2],
     pub frame_complete: bool,
 
-    sprite_screen: [u8; 256 * 240],
-    sprite_pattern_table: [[u8; 128 * 128]; 2],
+    //Colour index with the emphasis bits at the time the pixel was drawn in bits 6-8
+    sprite_screen: [u16; 256 * 240],
+    output_palette: [(u8, u8, u8); OUTPUT_PALETTE_SIZE],
+    //sprite_screen converted through output_palette as each pixel is drawn
+    pixel_format: PixelFormat,
+    frame_buffer: Vec<u8>,
+    sprite_pattern_table: [[u8; PATTERN_SIZE]; 2],
+    pattern_buffers: [Vec<u8>; 2],
 
     scanline: i32,
     cycle: i32,
@@ -88,6 +130,20 @@ pub struct PPU {
     fine_x: u8,
     address_latch: u8,
     data_buffer: u8,
+    //Value left on the PPU's data bus by the last register access and the frame each bit was
+    //last refreshed
+    io_latch: u8,
+    io_latch_refreshed: [u32; 8],
+    frame_count: u32,
+    //Reading $2002 just before vblank starts stops the flag being set that frame
+    suppress_vblank: bool,
+    pub nmi_suppressed: bool,
+    warm_up: u32,
+    //https://wiki.nesdev.com/w/index.php/PPU_frame_timing
+    odd_frame: bool,
+    region: Region,
+    //The PPU's /NMI output, vblank AND $2000 bit 7. nmi_enabled is raised on its edges
+    nmi_line: bool,
     pub nmi_enabled: bool,
     bg_tile_id: u8,
     bg_tile_attr: u8,
@@ -106,9 +162,24 @@ pub struct PPU {
     sprite_shifter_low: [u8; 8],
     sprite_shifter_high: [u8; 8],
 
-    sprite_zero_hit: bool,
+    //Sprite evaluation for the next line, one step per dot
+    secondary_oam: [u8; 32],
+    oam_latch: u8,
+    sprite_count: usize,
+    sprite_copy: u8,
+    evaluation_done: bool,
+    sprite_zero_next: bool,
+
+    sprite_zero_on_line: bool,
     sprite_zero_rendered: bool,
 
+    //When off, sprites past the eighth on a line are drawn too. They only affect the picture,
+    //overflow, sprite 0 and priority still come from the hardware's eight
+    pub sprite_limit: bool,
+    extra_sprites: Vec<Sprite>,
+    extra_shifter_low: Vec<u8>,
+    extra_shifter_high: Vec<u8>,
+
 }
 
 struct Sprite {
@@ -131,14 +202,18 @@ impl Sprite {
 
 impl PPU {
     pub fn new() -> PPU {
-        PPU {
+        let mut ppu = PPU {
             cartridge: None,
             name_table: [[0; 1024]; 2],
             palette_table: [0; 32],
             pattern_table: [[0; 4096]; 2],
             frame_complete: false,
             sprite_screen: [2; RENDER_SIZE],
-            sprite_pattern_table: [[0; 128 * 128]; 2],
+            output_palette: build_output_palette(&SYSTEM_PALETTE),
+            pixel_format: PixelFormat::Rgb24,
+            frame_buffer: vec![0; RENDER_FULL],
+            sprite_pattern_table: [[0; PATTERN_SIZE]; 2],
+            pattern_buffers: [vec![0; PATTERN_SIZE * 3], vec![0; PATTERN_SIZE * 3]],
             scanline: 0,
             cycle: 0,
 
@@ -152,6 +227,15 @@ impl PPU {
             fine_x: 0,
             address_latch: 0,
             data_buffer: 0,
+            io_latch: 0,
+            io_latch_refreshed: [0; 8],
+            frame_count: 0,
+            suppress_vblank: false,
+            nmi_suppressed: false,
+            warm_up: 0,
+            odd_frame: false,
+            region: Region::Ntsc,
+            nmi_line: false,
             nmi_enabled: false,
             bg_tile_id: 0,
             bg_tile_attr: 0,
@@ -168,50 +252,167 @@ impl PPU {
             sprite_shifter_low: [0; 8],
             sprite_shifter_high: [0; 8],
 
-            sprite_zero_hit: false,
+            secondary_oam: [0xFF; 32],
+            oam_latch: 0,
+            sprite_count: 0,
+            sprite_copy: 0,
+            evaluation_done: false,
+            sprite_zero_next: false,
+
+            sprite_zero_on_line: false,
             sprite_zero_rendered: false,
+
+            sprite_limit: true,
+            extra_sprites: Vec::new(),
+            extra_shifter_low: Vec::new(),
+            extra_shifter_high: Vec::new(),
+        };
+        //Until the first frame the window and anything reading raw_frame show the same picture
+        ppu.convert_frame();
+        return ppu;
+    }
+    //The latch with any bits that have decayed cleared
+    fn open_bus(&self) -> u8 {
+        let mut data = self.io_latch;
+        for bit in 0..8 {
+            if self.frame_count.wrapping_sub(self.io_latch_refreshed[bit]) > OPEN_BUS_DECAY_FRAMES {
+                data &= !(1 << bit);
+            }
+        }
+        return data;
+    }
+
+    //Bits in mask were driven by the PPU, the rest come from the latch
+    fn refresh_latch(&mut self, data: u8, mask: u8) {
+        self.io_latch = (self.open_bus() & !mask) | (data & mask);
+        for bit in 0..8 {
+            if mask & (1 << bit) > 0 {
+                self.io_latch_refreshed[bit] = self.frame_count;
+            }
+        }
+    }
+
+    //Called whenever vblank or $2000 bit 7 change, enabling NMI during vblank fires one straight away
+    fn update_nmi(&mut self) {
+        let line = self.status.vertical_blank() && self.controller.generate_nmi();
+        if line && !self.nmi_line {
+            self.nmi_enabled = true;
+        }
+        self.nmi_line = line;
+    }
+
+    //$2007 during rendering bumps coarse X and Y together instead of adding 1 or 32
+    fn increment_vram_address(&mut self) {
+        if self.rendering_enabled() && self.scanline >= -1 && self.scanline < 240 {
+            self.scroll_x();
+            self.scroll_y();
+        } else if self.controller.increment() == true {
+            self.v_address_register =
+                Address(self.v_address_register.get().wrapping_add(32));
+        } else {
+            self.v_address_register =
+                Address(self.v_address_register.get().wrapping_add(1));
+        }
+    }
+
+    //OAM Data, while rendering this is whatever the sprite logic is looking at
+    //https://wiki.nesdev.com/w/index.php/PPU_registers#OAMDATA
+    fn oam_data(&self) -> u8 {
+        let rendering = self.rendering_enabled() && self.scanline >= -1 && self.scanline < 240;
+        if rendering && self.scanline >= 0 && self.cycle >= 1 && self.cycle <= 64 {
+            return 0xFF;
+        } else if rendering && self.cycle >= 257 && self.cycle <= 320 {
+            let slot = ((self.cycle - 257) / 8) as usize;
+            let byte = (((self.cycle - 257) % 8) as usize).min(3);
+            return self.secondary_oam[slot * 4 + byte];
+        }
+        let data = self.oam_ram[self.oam_address_port as usize];
+        //Bits 2-4 of the attribute byte don't exist
+        if self.oam_address_port & 0x03 == 0x02 {
+            return data & 0xE3;
         }
+        return data;
     }
+
+    //What a register read would return, without clearing vblank, the address latch or moving v
+    pub fn peek_cpu(&self, address: u16) -> u8 {
+        let data = self.open_bus();
+        match address & 0x0007 {
+            0x0002 => return (self.status.get() & 0xE0) | (data & 0x1F),
+            0x0004 => return self.oam_data(),
+            0x0007 => {
+                let address = self.v_address_register.get() & 0x3FFF;
+                if address >= 0x3F00 {
+                    return (self.peek_ppu(address) & 0x3F) | (data & 0xC0);
+                }
+                return self.data_buffer;
+            }
+            _ => return data,
+        }
+    }
+
+    //https://wiki.nesdev.com/w/index.php/PPU_registers
+    //Write only registers read back the I/O latch, read_only reads have no side effects
     pub fn cpu_read(&mut self, address: u16, read_only: bool) -> u8 {
-        let mut data: u8 = 0x00;
+        if read_only {
+            return self.peek_cpu(address);
+        }
+        let mut data: u8 = self.open_bus();
         match address {
-            0x0000 => { //Control
-            }
+            0x0000 => (), //Control
             0x0001 => (), //Mask
             0x0002 => {
-                //Status
-                data = (self.status.get() & 0xE0) | (self.data_buffer & 0x1F);
+                //Status, the low 5 bits are open bus
+                data = (self.status.get() & 0xE0) | (data & 0x1F);
+                //https://wiki.nesdev.com/w/index.php/PPU_frame_timing#VBL_Flag_Timing
+                let vblank = self.region.vblank_scanline();
+                if self.scanline == vblank && self.cycle == 1 {
+                    //One dot early, the flag reads clear and is never set this frame
+                    self.suppress_vblank = true;
+                    self.nmi_suppressed = true;
+                } else if self.scanline == vblank && (self.cycle == 2 || self.cycle == 3) {
+                    //Same dot or one later, reads set but the NMI is cancelled
+                    self.nmi_enabled = false;
+                    self.nmi_suppressed = true;
+                }
                 self.status.set_vblank(false);
+                self.update_nmi();
                 self.address_latch = 0;
+                self.refresh_latch(data, 0xE0);
             }
             0x0003 => (), //OAM Address
             0x0004 => {
                 //OAM Data
-                data = self.oam_ram[self.oam_address_port as usize];
+                data = self.oam_data();
+                self.refresh_latch(data, 0xFF);
             }
             0x0005 => (), //Scroll
             0x0006 => (), //PPU Address
             0x0007 => {
                 //PPU data
-                data = self.data_buffer;
-                self.data_buffer = self.ppu_read(self.v_address_register.get(), read_only);
-
-                if self.v_address_register.get() >= 0x3F00 {
-                    data = self.data_buffer;
-                }
-                if self.controller.increment() == true {
-                    self.v_address_register =
-                        Address(self.v_address_register.get().wrapping_add(32));
+                let address = self.v_address_register.get() & 0x3FFF;
+                if address >= 0x3F00 {
+                    //Palette reads skip the buffer, which gets the nametable byte underneath instead.
+                    //Only 6 bits are driven, the top 2 are open bus
+                    data = (self.ppu_read(address, false) & 0x3F) | (data & 0xC0);
+                    self.data_buffer = self.ppu_read(address - 0x1000, false);
+                    self.refresh_latch(data, 0x3F);
                 } else {
-                    self.v_address_register =
-                        Address(self.v_address_register.get().wrapping_add(1));
+                    data = self.data_buffer;
+                    self.data_buffer = self.ppu_read(address, false);
+                    self.refresh_latch(data, 0xFF);
                 }
+                self.increment_vram_address();
             }
             _ => (), //required by rust
         }
         return data;
     }
     pub fn cpu_write(&mut self, address: u16, data: u8) {
+        self.refresh_latch(data, 0xFF);
+        if self.warm_up > 0 && (address == 0x0000 || address == 0x0001 || address == 0x0005 || address == 0x0006) {
+            return;
+        }
         match address {
             0x0000 => {
                 //Control
@@ -220,6 +421,13 @@ impl PPU {
                     .set_nametable_x(self.controller.nametable_x());
                 self.t_address_register
                     .set_nametable_y(self.controller.nametable_y());
+                //Turning NMI off right as vblank starts still cancels it
+                let vblank = self.region.vblank_scanline();
+                if !self.controller.generate_nmi() && self.scanline == vblank && (self.cycle == 2 || self.cycle == 3) {
+                    self.nmi_enabled = false;
+                    self.nmi_suppressed = true;
+                }
+                self.update_nmi();
             }
             0x0001 => {
                 //Mask
@@ -233,8 +441,13 @@ impl PPU {
                 self.oam_address_port = data;
             }
             0x0004 => {
-                //OAM Data
-                self.oam_ram[self.oam_address_port as usize] = data;
+                //OAM Data, writes while rendering are ignored but still bump the sprite index
+                if self.rendering_enabled() && self.scanline >= -1 && self.scanline < 240 {
+                    self.oam_address_port = self.oam_address_port.wrapping_add(4);
+                } else {
+                    self.oam_ram[self.oam_address_port as usize] = data;
+                    self.oam_address_port = self.oam_address_port.wrapping_add(1);
+                }
             }
             0x0005 => {
                 //Scroll
@@ -265,44 +478,104 @@ impl PPU {
             0x0007 => {
                 //PPU Data
                 self.ppu_write(self.v_address_register.get(), data);
-
-                if self.controller.increment() == true {
-                    self.v_address_register =
-                        Address(self.v_address_register.get().wrapping_add(32));
-                } else {
-                    self.v_address_register =
-                        Address(self.v_address_register.get().wrapping_add(1));
-                }
+                self.increment_vram_address();
             }
             _ => (), //required by rust
         }
     }
 
-    pub fn render(&mut self) -> [u8; RENDER_FULL] {
-        let mut frame = [0; RENDER_FULL];
-        for i in 0..RENDER_SIZE {
-            let c = self.sprite_screen[i];
-            
+    //Frames run since power on
+    pub fn frame_count(&self) -> u32 {
+        return self.frame_count;
+    }
 
-            let (mut r, mut g, mut b) = SYSTEM_PALETTE[c as usize];
-            if self.mask.emphasize_red(){r = r * 2;}
-            if self.mask.emphasize_blue(){b = b * 2;}
-            if self.mask.emphasize_green(){g = g * 2;}
+    //Scanline (-1 is pre-render) and dot about to be run
+    pub fn position(&self) -> (i32, i32) {
+        return (self.scanline, self.cycle);
+    }
+
+    //Loopy v, t and fine X, for the nametable viewer
+    pub fn scroll_registers(&self) -> (u16, u16, u8) {
+        return (self.v_address_register.get(), self.t_address_register.get(), self.fine_x);
+    }
+
+    pub fn background_table(&self) -> u16 {
+        return (self.controller.background_table() as u16) << 12;
+    }
+
+    pub fn region(&self) -> Region {
+        return self.region;
+    }
 
-            frame[i * 3 + 0] = r;
-            frame[i * 3 + 1] = g;
-            frame[i * 3 + 2] = b;
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    pub fn set_output_palette(&mut self, palette: [(u8, u8, u8); OUTPUT_PALETTE_SIZE]) {
+        self.output_palette = palette;
+        self.convert_frame();
+    }
+
+    pub fn output_colour(&self, pixel: u16) -> (u8, u8, u8) {
+        return self.output_palette[pixel as usize];
+    }
+
+    pub fn pixel_format(&self) -> PixelFormat {
+        return self.pixel_format;
+    }
+
+    pub fn set_pixel_format(&mut self, format: PixelFormat) {
+        self.pixel_format = format;
+        self.frame_buffer = vec![0; RENDER_SIZE * format.bytes_per_pixel()];
+        for buffer in self.pattern_buffers.iter_mut() {
+            *buffer = vec![0; PATTERN_SIZE * format.bytes_per_pixel()];
         }
-        return frame;
+        self.convert_frame();
     }
 
+    //Only needed when the palette or format changes, drawing keeps the buffer up to date
+    fn convert_frame(&mut self) {
+        let bytes = self.pixel_format.bytes_per_pixel();
+        for (out, &pixel) in self.frame_buffer.chunks_mut(bytes).zip(self.sprite_screen.iter()) {
+            self.pixel_format.write(out, self.output_palette[pixel as usize]);
+        }
+    }
+
+    //Colour index and emphasis bits of every pixel, for filters that work on the signal
+    pub fn raw_frame(&self) -> &[u16] {
+        return &self.sprite_screen;
+    }
+
+    //The current picture in pixel_format, RENDER_WIDTH * bytes_per_pixel bytes per row
+    pub fn frame_buffer(&self) -> &[u8] {
+        return &self.frame_buffer;
+    }
+
+    pub fn frame_pitch(&self) -> usize {
+        return RENDER_WIDTH * self.pixel_format.bytes_per_pixel();
+    }
+
+    //read_only reads go through peek_ppu so the mapper never sees them
+    pub fn ppu_read(&mut self, address: u16, read_only: bool) -> u8 {
+        if !read_only {
+            if let Some(ref c) = self.cartridge {
+                let mut data: u8 = 0x00;
+                if c.borrow_mut().ppu_read(address & 0x3FFF, &mut data) {
+                    return data;
+                }
+            }
+        }
+        return self.peek_ppu(address);
+    }
+
+    //VRAM as the PPU sees it, without any side effects
     #[allow(unused_comparisons)]
-    pub fn ppu_read(&mut self, mut address: u16, _read_only: bool) -> u8 {
+    pub fn peek_ppu(&self, mut address: u16) -> u8 {
         let mut data: u8 = 0x00;
         address &= 0x3FFF;
 
         if let Some(ref c) = self.cartridge {
-            if c.borrow_mut().ppu_read(address, &mut data) {
+            if c.borrow().peek_ppu(address, &mut data) {
                 //Should always be false
             } else if address >= 0x0000 && address <= 0x1FFF {
                 //Pattern memory
@@ -313,7 +586,7 @@ impl PPU {
             } else if address >= 0x2000 && address <= 0x3EFF {
                 //Nametable memory
                 address &= 0x0FFF;
-                if c.borrow_mut().mirror() == Mirroring::Vertical {
+                if c.borrow().mirror() == Mirroring::Vertical {
                     if address >= 0x0000 && address <= 0x03FF {
                         data = self.name_table[0][(address & 0x03FF) as usize];
                     }
@@ -326,7 +599,7 @@ impl PPU {
                     if address >= 0x0C00 && address <= 0x0FFF {
                         data = self.name_table[1][(address & 0x03FF) as usize];
                     }
-                } else if c.borrow_mut().mirror() == Mirroring::Horizontal {
+                } else if c.borrow().mirror() == Mirroring::Horizontal {
                     if address >= 0x0000 && address <= 0x03FF {
                         data = self.name_table[0][(address & 0x03FF) as usize];
                     }
@@ -428,18 +701,19 @@ impl PPU {
         self.cartridge = Some(cartridge);
     }
 
-    pub fn get_pattern_table(&mut self, index: u8, palette: u8) -> [u8; (128 * 128) * 3] {
+    //128x128 in pixel_format, valid until the next call for the same table
+    pub fn get_pattern_table(&mut self, index: u8, palette: u8) -> &[u8] {
         for tile_y in 0..16 {
             for tile_x in 0..16 {
                 let offset: u16 = (tile_y * 256) + (tile_x * 16);
 
                 for row in 0..8 {
                     let address: u16 = index as u16 * 0x1000 + offset + row;
-                    let mut tile_ls = self.ppu_read(address, false);
-                    let mut tile_ms = self.ppu_read(address + 8, false);
+                    let mut tile_ls = self.peek_ppu(address);
+                    let mut tile_ms = self.peek_ppu(address + 8);
 
                     for column in 0..8 {
-                        let pixel = (tile_ls & 0x01) + (tile_ms & 0x01);
+                        let pixel = ((tile_ms & 0x01) << 1) | (tile_ls & 0x01);
                         tile_ls = tile_ls >> 1;
                         tile_ms = tile_ms >> 1;
 
@@ -451,36 +725,31 @@ impl PPU {
                 }
             }
         }
-        return self.render_palette(index);
-    }
-    pub fn render_palette(&self, index: u8) -> [u8; (128 * 128) * 3] {
-        let mut frame = [0; (128 * 128) * 3];
-        for i in 0..(128 * 128) {
-            let c = self.sprite_pattern_table[index as usize][i];
-            let (r, g, b) = SYSTEM_PALETTE[c as usize];
-            frame[i * 3 + 0] = r;
-            frame[i * 3 + 1] = g;
-            frame[i * 3 + 2] = b;
-        }
-        return frame;
+        return &self.pattern_buffers[index as usize];
     }
 
-    pub fn get_colour(&mut self, palette: u8, pixel: u8) -> u8 {
+    pub fn get_colour(&self, palette: u8, pixel: u8) -> u8 {
         let address: u16 = 0x3F00 + ((palette << 2) as u16) + pixel as u16;
-        let i = self.ppu_read(address, false);
+        let i = self.peek_ppu(address);
         return i & 0x3F;
     }
 
     fn draw_pixel(&mut self, x: i32, y: i32, c: SystemColor) {
         if x >= 0 && y >= 0 && x < 256 && y < 240 {
             let i = (x + 256 * y) as usize;
-            self.sprite_screen[i] = c;
+            let emphasis = (self.mask.get() >> 5) as u16;
+            let pixel = (emphasis << 6) | c as u16;
+            self.sprite_screen[i] = pixel;
+            let bytes = self.pixel_format.bytes_per_pixel();
+            self.pixel_format.write(&mut self.frame_buffer[i * bytes..(i + 1) * bytes], self.output_palette[pixel as usize]);
         }
     }
 
     fn write_pattern_pixel(&mut self, x: u16, y: u16, c: SystemColor, index: usize) {
         let i = (x + 128 * y) as usize;
         self.sprite_pattern_table[index][i] = c;
+        let bytes = self.pixel_format.bytes_per_pixel();
+        self.pixel_format.write(&mut self.pattern_buffers[index][i * bytes..(i + 1) * bytes], self.output_palette[c as usize]);
     }
 
     fn scroll_x(&mut self) {
@@ -532,12 +801,37 @@ impl PPU {
         }
     }
 
+    //Memory comes up however `filler` says, registers as on the wiki's power up table
+    pub fn power_on(&mut self, filler: &mut RamFiller) {
+        filler.fill(&mut self.oam_ram);
+        filler.fill(&mut self.palette_table);
+        for entry in self.palette_table.iter_mut() {
+            *entry &= 0x3F;
+        }
+        for table in self.name_table.iter_mut() {
+            filler.fill(table);
+        }
+        self.reset();
+        self.v_address_register = Address(0);
+        self.oam_address_port = 0;
+        self.io_latch = 0;
+        //Vblank and sprite overflow are often set at power on
+        self.status = Status(0xA0);
+    }
+
+    //The reset button, OAM, palettes, nametables and PPUADDR are left alone
     pub fn reset(&mut self) {
         self.fine_x = 0x00;
         self.address_latch = 0x00;
         self.data_buffer = 0x00;
         self.scanline = 0;
         self.cycle = 0;
+        self.warm_up = self.region.ppu_warm_up_dots();
+        self.suppress_vblank = false;
+        self.nmi_suppressed = false;
+        self.nmi_enabled = false;
+        self.nmi_line = false;
+        self.odd_frame = false;
         self.bg_tile_id = 0;
         self.bg_tile_attr = 0;
         self.bg_tile_lsb = 0;
@@ -549,7 +843,6 @@ impl PPU {
         self.status = Status(0);
         self.mask = Mask(0);
         self.controller = Controller(0);
-        self.v_address_register = Address(0);
         self.t_address_register = Address(0);
     }
     fn load_bg_shifters(&mut self) {
@@ -590,45 +883,210 @@ impl PPU {
                     self.sprite_shifter_high[i] <<= 1;
                 }
             }
+            for i in 0..self.extra_sprites.len() {
+                if self.extra_sprites[i].x > 0 {
+                    self.extra_sprites[i].x -= 1;
+                } else {
+                    self.extra_shifter_low[i] <<= 1;
+                    self.extra_shifter_high[i] <<= 1;
+                }
+            }
         }
     }
-    #[allow(unused_assignments)]
+
+    fn rendering_enabled(&self) -> bool {
+        return self.mask.show_background() || self.mask.show_sprites();
+    }
+
+    pub fn sprite_height(&self) -> i32 {
+        return if self.controller.sprite_size() { 16 } else { 8 };
+    }
+
+    //Sprites are evaluated on the line before they are drawn, so a Y of 0 appears on line 1
+    fn sprite_in_range(&self, y: u8) -> bool {
+        let row = self.scanline - y as i32;
+        return row >= 0 && row < self.sprite_height();
+    }
+
+    //https://wiki.nesdev.com/w/index.php/PPU_sprite_evaluation
+    //OAMADDR is the hardware's pointer into OAM, so a non zero OAMADDR starts evaluation part way
+    //through and the sprite index shows up in it
     fn evaluate_sprites(&mut self) {
-        self.oam_sprites.clear();
-        self.sprite_zero_hit = false;
-        for i in 0..64 {
-            let address = i * 4;
-            let sprite = Sprite::new(&self.oam_ram[address..address + 4]);
+        if self.cycle >= 1 && self.cycle <= 64 {
+            //Secondary OAM is cleared to $FF, a byte every two dots
+            if self.cycle % 2 == 0 {
+                self.secondary_oam[(self.cycle / 2 - 1) as usize] = 0xFF;
+            }
+        } else if self.cycle >= 65 && self.cycle <= 256 {
+            if self.cycle == 65 {
+                self.sprite_count = 0;
+                self.sprite_copy = 0;
+                self.evaluation_done = false;
+                self.sprite_zero_next = false;
+            }
+            //Odd dots read from OAM, even dots write to secondary OAM
+            if self.cycle % 2 == 1 {
+                self.oam_latch = self.oam_ram[self.oam_address_port as usize];
+            } else {
+                self.evaluate_sprite_byte();
+            }
+        }
+    }
 
-            let left = self.scanline as usize >= sprite.y as usize;
+    fn evaluate_sprite_byte(&mut self) {
+        let address = self.oam_address_port;
+        if self.evaluation_done {
+            //Keeps failing to copy the Y of the next sprite until hblank
+            self.oam_address_port = address.wrapping_add(4);
+            return;
+        }
 
-            let mut right = false;
-            if self.controller.sprite_size() 
-            {
-                right = (self.scanline as usize) < sprite.y as usize + 16 as usize;
-            }else
-            {
-                right = (self.scanline as usize) < sprite.y as usize + 8 as usize;
+        if self.sprite_copy > 0 {
+            //Tile, attribute and X of a sprite that is in range
+            self.secondary_oam[self.sprite_count * 4 + 4 - self.sprite_copy as usize] = self.oam_latch;
+            self.sprite_copy -= 1;
+            if self.sprite_copy == 0 {
+                self.sprite_count += 1;
             }
+            let (next, wrapped) = address.overflowing_add(1);
+            self.oam_address_port = next;
+            self.evaluation_done = wrapped;
+            return;
+        }
 
-            if left && right
-            {
-                if self.oam_sprites.len() == 8 {
-                    if self.mask.show_background() || self.mask.show_sprites()
-                    {
-                        self.status.set_sprite_overflow(true);
-                    }
-                    break;
+        if self.sprite_count < 8 {
+            self.secondary_oam[self.sprite_count * 4] = self.oam_latch;
+            let step = if self.sprite_in_range(self.oam_latch) {
+                //The first sprite looked at is normally sprite 0
+                if self.cycle == 66 {
+                    self.sprite_zero_next = true;
                 }
-                if i == 0
-                {
-                    self.sprite_zero_hit = true;
-                }
-                self.oam_sprites.push(sprite);
+                self.sprite_copy = 3;
+                1
+            } else {
+                4
+            };
+            let (next, wrapped) = address.overflowing_add(step);
+            self.oam_address_port = next;
+            self.evaluation_done = wrapped;
+            return;
+        }
+
+        //With 8 found the overflow check increments m as well as n, reading the tile, attribute
+        //and X bytes of later sprites as if they were Y
+        if self.sprite_in_range(self.oam_latch) {
+            self.status.set_sprite_overflow(true);
+            self.evaluation_done = true;
+        } else {
+            let (next, wrapped) = (address & 0xFC).overflowing_add(4);
+            self.oam_address_port = next | (address.wrapping_add(1) & 0x03);
+            self.evaluation_done = wrapped;
+        }
+    }
+
+    pub fn sprite_pattern_address(&self, id: u8, attribute: u8, row: u8) -> u16 {
+        let flipped = attribute & 0x80 > 0;
+        if self.controller.sprite_size() {
+            //8x16 sprites pick their table from bit 0 of the tile, the top half is the even tile
+            let row = if flipped { 15 - (row & 0x0F) } else { row & 0x0F };
+            let table = (id & 0x01) as u16;
+            let tile = (id & 0xFE) as u16 + (row >= 8) as u16;
+            return (table << 12) | (tile << 4) | (row & 0x07) as u16;
+        }
+        let row = if flipped { 7 - (row & 0x07) } else { row & 0x07 };
+        return ((self.controller.sprite_table() as u16) << 12) | ((id as u16) << 4) | row as u16;
+    }
+
+    //Dots 257-320 fetch the patterns of the sprites found for the next line, 8 dots each
+    fn fetch_sprites(&mut self) {
+        self.oam_address_port = 0;
+        let slot = ((self.cycle - 257) / 8) as usize;
+        if self.cycle == 257 {
+            //Nothing is evaluated on the pre-render line so there are never sprites on line 0
+            let count = if self.scanline >= 0 { self.sprite_count } else { 0 };
+            self.oam_sprites.clear();
+            for i in 0..count {
+                self.oam_sprites.push(Sprite::new(&self.secondary_oam[i * 4..i * 4 + 4]));
+            }
+            self.sprite_zero_on_line = self.scanline >= 0 && self.sprite_zero_next;
+            for i in 0..8 {
+                self.sprite_shifter_low[i] = 0;
+                self.sprite_shifter_high[i] = 0;
+            }
+            self.evaluate_extra_sprites();
+        }
+
+        let phase = (self.cycle - 257) % 8;
+        if phase != 5 && phase != 7 {
+            return;
+        }
+        //Empty slots still fetch tile $FF, which mappers watching the address bus can see
+        let (address, attribute) = match self.oam_sprites.get(slot) {
+            Some(sprite) => {
+                let row = (self.scanline - sprite.y as i32) as u8;
+                (self.sprite_pattern_address(sprite.id, sprite.attribute, row), sprite.attribute)
+            }
+            None => (self.sprite_pattern_address(0xFF, 0, 0), 0),
+        };
+        if phase == 5 {
+            let mut bits = self.ppu_read(address, false);
+            if attribute & 0x40 > 0 {
+                bits = PPU::reverse_bits(bits);
+            }
+            if slot < self.oam_sprites.len() {
+                self.sprite_shifter_low[slot] = bits;
+            }
+        } else {
+            let mut bits = self.ppu_read(address + 8, false);
+            if attribute & 0x40 > 0 {
+                bits = PPU::reverse_bits(bits);
+            }
+            if slot < self.oam_sprites.len() {
+                self.sprite_shifter_high[slot] = bits;
+            }
+        }
+    }
+
+    //Display only pass for every sprite on the line after the ones the hardware found. The patterns
+    //are read straight away, the real fetches above are the only ones a mapper sees
+    fn evaluate_extra_sprites(&mut self) {
+        self.extra_sprites.clear();
+        self.extra_shifter_low.clear();
+        self.extra_shifter_high.clear();
+        if self.sprite_limit || self.scanline < 0 || self.sprite_count < 8 {
+            return;
+        }
+
+        let mut found = 0;
+        for n in 0..64 {
+            let sprite = Sprite::new(&self.oam_ram[n * 4..n * 4 + 4]);
+            if !self.sprite_in_range(sprite.y) {
+                continue;
+            }
+            found += 1;
+            if found <= 8 {
+                continue;
             }
+            let row = (self.scanline - sprite.y as i32) as u8;
+            let address = self.sprite_pattern_address(sprite.id, sprite.attribute, row);
+            let mut low = self.peek_ppu(address);
+            let mut high = self.peek_ppu(address + 8);
+            if sprite.attribute & 0x40 > 0 {
+                low = PPU::reverse_bits(low);
+                high = PPU::reverse_bits(high);
+            }
+            self.extra_sprites.push(sprite);
+            self.extra_shifter_low.push(low);
+            self.extra_shifter_high.push(high);
         }
     }
 
+    //Sprite 0 can't hit at x = 255, or in the left 8 pixels when either layer is clipped there
+    fn sprite_zero_hit_allowed(&self, x: i32) -> bool {
+        let clipped = !(self.mask.show_background_left() && self.mask.show_sprites_left());
+        return x != 255 && !(clipped && x < 8);
+    }
+
     fn reverse_bits(mut a: u8) -> u8 {
         let mut out = 0u8;
         for _i in 0..8 {
@@ -641,20 +1099,29 @@ impl PPU {
 
     #[allow(unused_assignments)]
     pub fn clock(&mut self) {
+        if self.warm_up > 0 {
+            self.warm_up -= 1;
+        }
         if self.scanline >= -1 && self.scanline < 240 {
-            if self.scanline == 0 && self.cycle == 0 {
-                self.cycle = 1;
-            }
-
             if self.scanline == -1 && self.cycle == 1 {
                 self.status.set_vblank(false);
                 self.status.set_sprite_0(false);
                 self.status.set_sprite_overflow(false);
+                self.update_nmi();
 
                 for i in 0..8{
                     self.sprite_shifter_low[i] = 0;
                     self.sprite_shifter_high[i] = 0;
                 }
+
+                //Starting to render with OAMADDR at 8 or more copies that row of OAM over the first
+                //https://wiki.nesdev.com/w/index.php/PPU_registers#OAMADDR
+                if self.rendering_enabled() && self.oam_address_port >= 8 {
+                    let start = (self.oam_address_port & 0xF8) as usize;
+                    for i in 0..8 {
+                        self.oam_ram[i] = self.oam_ram[start + i];
+                    }
+                }
             }
 
             if (self.cycle >= 2 && self.cycle < 258) || (self.cycle >= 321 && self.cycle < 338) {
@@ -722,114 +1189,35 @@ impl PPU {
             }
 
             //Sprite Rendering
-            if self.cycle == 257 && self.scanline >= 0 
-            {
-                for i in 0..8{
-                    self.sprite_shifter_low[i] = 0;
-                    self.sprite_shifter_high[i] = 0;
+            if self.rendering_enabled() {
+                if self.scanline >= 0 {
+                    self.evaluate_sprites();
                 }
-                self.evaluate_sprites();
-            }
-            if self.cycle == 340
-            {
-                for i in 0..self.oam_sprites.len() 
-                {
-                    let mut sprite_bits_low: u8 = 0;
-                    let mut sprite_bits_high: u8 = 0;
-
-                    let mut sprite_address_low: u16 = 0;
-                    let mut sprite_address_high: u16 = 0;
-
-                    if !self.controller.sprite_size(){
-                        //8x8
-                        if !(self.oam_sprites[i].attribute & 0x80 > 0)
-                        {
-                            let low = (self.controller.sprite_table() as u16) << 12;
-                            let high = (self.oam_sprites[i].id as u16) << 4;
-                            let sl = (self.scanline as u16) - self.oam_sprites[i].y as u16;
-
-                            sprite_address_low = low | high | sl;
-                        }
-                        else    //Sprite is flipped
-                        {
-                            let low = (self.controller.sprite_table() as u16) << 12;
-                            let high = (self.oam_sprites[i].id as u16) << 4;
-                            let sl = (self.scanline as u16) - self.oam_sprites[i].y as u16;
-
-                            sprite_address_low = low | high | 7 - sl;
-                        }
-                    }
-                    else
-                    {
-                        //8x16
-                        if !(self.oam_sprites[i].attribute & 0x80 > 0)
-                        {
-                            if (self.scanline as u16 - self.oam_sprites[i].y as u16) < 8{
-                                //Top half of sprite
-                                let low = ((self.oam_sprites[i].id as u16) & 0x01) << 12;
-                                let high = ((self.oam_sprites[i].id as u16) & 0xFE) << 4;
-                                let sl = ((self.scanline as u16) - self.oam_sprites[i].y as u16) & 0x07;
-
-                                sprite_address_low = low | high | sl;
-
-                            }else
-                            {
-                                //Bottom half of sprite
-                                let low = ((self.oam_sprites[i].id  as u16) & 0x01) << 12;
-                                let high = (((self.oam_sprites[i].id as u16) & 0xFE) + 1) << 4;
-                                let sl = ((self.scanline as u16) - self.oam_sprites[i].y as u16) & 0x07;
-
-                                sprite_address_low = low | high | sl;
-                            }
-                        }
-                        else    //Sprite is flipped
-                        {
-                            if (self.scanline as u16 - self.oam_sprites[i].y as u16) < 8{
-                                //Top half of sprite
-                                let low = ((self.oam_sprites[i].id as u16) & 0x01) << 12;
-                                let high = ((self.oam_sprites[i].id as u16) & 0xFE) << 4;
-                                let sl = ((self.scanline as u16) - self.oam_sprites[i].y as u16) & 0x07;
-                               
-                                sprite_address_low = low | high | 7 - sl;
-
-                            }else
-                            {
-                                //Bottom half of sprite                               
-                                let low = ((self.oam_sprites[i].id as u16) & 0x01) << 12;
-                                let high = (((self.oam_sprites[i].id as u16) & 0xFE)+ 1) << 4;
-                                let sl = ((self.scanline as u16) - self.oam_sprites[i].y as u16) & 0x07;
-
-                                sprite_address_low = low | high | 7 - sl; //KEEP AN EYE ON THIS WITH ABOVE & OPERATOR
-                            }
-                        }
-                    }
-
-                    sprite_address_high = sprite_address_low + 8;
-                    sprite_bits_low = self.ppu_read(sprite_address_low, false);
-                    sprite_bits_high = self.ppu_read(sprite_address_high, false);
-
-                    if (self.oam_sprites[i].attribute & 0x40) > 0{
-                        sprite_bits_low = PPU::reverse_bits(sprite_bits_low);
-                        sprite_bits_high = PPU::reverse_bits(sprite_bits_high);
-                    }
-
-                    self.sprite_shifter_low[i] = sprite_bits_low;
-                    self.sprite_shifter_high[i] = sprite_bits_high;
+                if self.cycle >= 257 && self.cycle <= 320 {
+                    self.fetch_sprites();
                 }
             }
         }
 
-        if self.scanline == 241 && self.cycle == 1 {
-            self.status.set_vblank(true);
-            if self.controller.generate_nmi() == true {
-                self.nmi_enabled = true;
+        if self.scanline == self.region.vblank_scanline() && self.cycle == 1 {
+            if !self.suppress_vblank {
+                self.status.set_vblank(true);
+                self.update_nmi();
             }
+            self.suppress_vblank = false;
         }
 
+        //Only visible dots produce pixels
+        if self.scanline < 0 || self.scanline >= 240 || self.cycle < 1 || self.cycle > 256 {
+            self.next_dot();
+            return;
+        }
+        let x = self.cycle - 1;
+
         let mut background_pixel: u8 = 0x00;
         let mut background_palette: u8 = 0x00;
 
-        if self.mask.show_background() {
+        if self.mask.show_background() && (x >= 8 || self.mask.show_background_left()) {
             let bit_mask = 0x8000 >> (self.fine_x);
             let plane_0 = ((self.bg_shifter_lsb & bit_mask) > 0) as u8;
             let plane_1 = ((self.bg_shifter_msb & bit_mask) > 0) as u8;
@@ -845,7 +1233,7 @@ impl PPU {
         let mut sprite_pal: u8 = 0x00;
         let mut sprite_z_buffer: u8 = 0x00;
 
-        if self.mask.show_sprites(){
+        if self.mask.show_sprites() && (x >= 8 || self.mask.show_sprites_left()) {
             self.sprite_zero_rendered = false;
             for i in 0..self.oam_sprites.len(){
                 if self.oam_sprites[i].x == 0 {
@@ -867,6 +1255,21 @@ impl PPU {
                      }
                 }
             }
+            //Extra sprites only fill in where the hardware's eight left a gap
+            if sprite_pixel == 0 {
+                for i in 0..self.extra_sprites.len() {
+                    if self.extra_sprites[i].x == 0 {
+                        let low = ((self.extra_shifter_low[i] & 0x80) > 0) as u8;
+                        let high = ((self.extra_shifter_high[i] & 0x80) > 0) as u8;
+                        sprite_pixel = (high << 1) | low;
+                        sprite_pal = (self.extra_sprites[i].attribute & 0x03) + 0x04;
+                        sprite_z_buffer = ((self.extra_sprites[i].attribute & 0x20) == 0) as u8;
+                        if sprite_pixel != 0 {
+                            break;
+                        }
+                    }
+                }
+            }
         }
 
         let mut final_pixel: u8 = 0x00;
@@ -898,44 +1301,60 @@ impl PPU {
                 final_palette = background_palette;
             }
 
-            if self.sprite_zero_hit && self.sprite_zero_rendered 
-            {
-                if self.mask.show_background() && self.mask.show_sprites()
-                {
-                    if !(self.mask.show_background_left() | self.mask.show_sprites_left())
-                    {
-                        if self.cycle >= 9 && self.cycle < 258
-                        {
-                            self.status.set_sprite_0(true);
-                        }
-                    }
-                    else 
-                    {
-                        if self.cycle >= 1 && self.cycle < 258
-                        {
-                            self.status.set_sprite_0(true);
-                        }
-                    }
-                }
+            if self.sprite_zero_on_line && self.sprite_zero_rendered && self.sprite_zero_hit_allowed(x) {
+                self.status.set_sprite_0(true);
             }
         }
 
         let colour = self.get_colour(final_palette, final_pixel);
-        self.draw_pixel(self.cycle - 1, self.scanline, colour);
+        self.draw_pixel(x, self.scanline, colour);
+        self.next_dot();
+    }
 
+    fn next_dot(&mut self) {
         self.cycle += 1;
+        //With rendering on, odd NTSC frames skip the last dot of the pre-render line, so frames
+        //alternate between 89342 and 89341 dots
+        if self.scanline == -1 && self.cycle == 340 && self.odd_frame && self.rendering_enabled() && self.region.skips_odd_frame_dot() {
+            self.cycle = 341;
+        }
         if self.cycle >= 341 {
             self.cycle = 0;
             self.scanline += 1;
 
-            if self.scanline >= 261 {
+            if self.scanline >= self.region.scanlines() - 1 {
                 self.scanline = -1;
                 self.frame_complete = true;
+                self.frame_count = self.frame_count.wrapping_add(1);
+                self.odd_frame = !self.odd_frame;
             }
         }
     }
 }
 pub type SystemColor = u8;
+
+//Each emphasis bit darkens the other two colour channels rather than brightening its own, so
+//setting all three darkens everything. Index is colour | red << 6 | green << 7 | blue << 8,
+//the same order as PPUMASK.
+pub fn build_output_palette(base: &[(u8, u8, u8); 64]) -> [(u8, u8, u8); OUTPUT_PALETTE_SIZE] {
+    let mut palette = [(0, 0, 0); OUTPUT_PALETTE_SIZE];
+    for emphasis in 0..8usize {
+        for colour in 0..64 {
+            let (r, g, b) = base[colour];
+            let attenuate = |value: u8, channel: usize| {
+                //Columns $xE and $xF are forced black and unaffected
+                if colour & 0x0E == 0x0E {
+                    return value;
+                }
+                let others = (emphasis & !(1 << channel)).count_ones() as i32;
+                (value as f32 * EMPHASIS_ATTENUATION.powi(others)).round() as u8
+            };
+            palette[emphasis << 6 | colour] = (attenuate(r, 0), attenuate(g, 1), attenuate(b, 2));
+        }
+    }
+    return palette;
+}
+
 pub const SYSTEM_PALETTE: [(u8, u8, u8); 64] = [
     //0x00 - 0x0F
     (84, 84, 84),
@@ -1006,3 +1425,365 @@ pub const SYSTEM_PALETTE: [(u8, u8, u8); 64] = [
     (0, 0, 0),
     (0, 0, 0),
 ];
+
+#[test]
+fn test_output_palette_emphasis() {
+    let palette = build_output_palette(&SYSTEM_PALETTE);
+    assert_eq!(palette[0x30], SYSTEM_PALETTE[0x30]);
+
+    //Red emphasis keeps red and darkens green and blue, no overflow on bright colours
+    let (r, g, b) = palette[0x01 << 6 | 0x30];
+    assert_eq!(r, 236);
+    assert!(g < 238 && b < 236);
+
+    //All three darken everything
+    let (r, g, b) = palette[0x07 << 6 | 0x30];
+    assert!(r < 236 && g < 238 && b < 236);
+    let (r, g, b) = palette[0x06 << 6 | 0x16];
+    assert!(r < 152 && g < 34 && b < 32);
+    assert_eq!(palette[0x07 << 6 | 0x0E], (0, 0, 0));
+}
+
+#[test]
+fn test_emphasis_captured_per_pixel() {
+    let mut ppu = PPU::new();
+    ppu.cpu_write(0x0001, 0x20);
+    ppu.draw_pixel(0, 0, 0x30);
+    ppu.cpu_write(0x0001, 0x00);
+    ppu.draw_pixel(1, 0, 0x30);
+
+    let frame = ppu.frame_buffer();
+    assert_eq!(frame[0], 236);
+    assert!(frame[1] < 238);
+    assert_eq!(&frame[3..6], &[236, 238, 236]);
+}
+
+#[test]
+fn test_frame_buffer_formats() {
+    let mut ppu = PPU::new();
+    let (r, g, b) = SYSTEM_PALETTE[ppu.raw_frame()[0] as usize];
+    assert_eq!(&ppu.frame_buffer()[0..3], &[r, g, b]);
+
+    ppu.draw_pixel(1, 0, 0x16);
+    assert_eq!(ppu.raw_frame()[1], 0x16);
+    let (r, g, b) = SYSTEM_PALETTE[0x16];
+    assert_eq!(&ppu.frame_buffer()[3..6], &[r, g, b]);
+    assert_eq!(ppu.frame_pitch(), 256 * 3);
+
+    //Switching format or palette converts what is already on screen
+    ppu.set_pixel_format(PixelFormat::Bgra8888);
+    assert_eq!(ppu.frame_buffer().len(), RENDER_SIZE * 4);
+    assert_eq!(&ppu.frame_buffer()[4..8], &[b, g, r, 0xFF]);
+    ppu.set_pixel_format(PixelFormat::Rgba8888);
+    assert_eq!(&ppu.frame_buffer()[4..8], &[r, g, b, 0xFF]);
+
+    let mut palette = build_output_palette(&SYSTEM_PALETTE);
+    palette[0x16] = (1, 2, 3);
+    ppu.set_output_palette(palette);
+    assert_eq!(&ppu.frame_buffer()[4..8], &[1, 2, 3, 0xFF]);
+
+    ppu.draw_pixel(2, 0, 0x16);
+    assert_eq!(&ppu.frame_buffer()[8..12], &[1, 2, 3, 0xFF]);
+}
+
+#[cfg(test)]
+fn clock_to(ppu: &mut PPU, scanline: i32, cycle: i32) {
+    while !(ppu.scanline == scanline && ppu.cycle == cycle) {
+        ppu.clock();
+    }
+}
+
+#[test]
+fn test_sprite_evaluation_limit() {
+    let mut ppu = PPU::new();
+    ppu.oam_ram = [0xF0; 256];
+    for i in 0..9 {
+        ppu.oam_ram[i * 4] = 20;
+        ppu.oam_ram[i * 4 + 3] = i as u8;
+    }
+    ppu.cpu_write(0x0001, 0x18);
+    clock_to(&mut ppu, 25, 258);
+    assert_eq!(ppu.oam_sprites.len(), 8);
+    assert!(ppu.sprite_zero_on_line);
+    assert!(ppu.status.sprite_overflow());
+    assert_eq!(ppu.oam_address_port, 0);
+}
+
+#[test]
+fn test_sprite_overflow_bug() {
+    //Eight sprites on the line, a ninth that isn't, then a tenth whose tile number looks like an
+    //in range Y, which the buggy scan reads because it moves to byte 1 of the next sprite
+    let mut ppu = PPU::new();
+    ppu.oam_ram = [0xF0; 256];
+    for i in 0..8 {
+        ppu.oam_ram[i * 4] = 20;
+    }
+    ppu.oam_ram[37] = 22;
+    ppu.cpu_write(0x0001, 0x18);
+    clock_to(&mut ppu, 25, 258);
+    assert_eq!(ppu.oam_sprites.len(), 8);
+    assert!(ppu.status.sprite_overflow());
+
+    //And a real ninth sprite missed because the scan is looking at the wrong byte
+    ppu.oam_ram = [0xF0; 256];
+    for i in 0..8 {
+        ppu.oam_ram[i * 4] = 20;
+    }
+    ppu.oam_ram[36] = 22;
+    clock_to(&mut ppu, -1, 2);
+    clock_to(&mut ppu, 25, 258);
+    assert!(!ppu.status.sprite_overflow());
+}
+
+#[test]
+fn test_sprite_8x16_addresses() {
+    let mut ppu = PPU::new();
+    ppu.cpu_write(0x0000, 0x20);
+    assert_eq!(ppu.sprite_pattern_address(0x01, 0x00, 0), 0x1000);
+    assert_eq!(ppu.sprite_pattern_address(0x01, 0x00, 9), 0x1011);
+    //Flipped, the bottom tile comes first
+    assert_eq!(ppu.sprite_pattern_address(0x04, 0x80, 0), 0x0057);
+    ppu.cpu_write(0x0000, 0x08);
+    assert_eq!(ppu.sprite_pattern_address(0x01, 0x80, 1), 0x1016);
+}
+
+#[test]
+fn test_oam_data_port() {
+    let mut ppu = PPU::new();
+    ppu.cpu_write(0x0003, 0x02);
+    ppu.cpu_write(0x0004, 0xFF);
+    assert_eq!(ppu.oam_address_port, 0x03);
+    ppu.cpu_write(0x0003, 0x02);
+    assert_eq!(ppu.cpu_read(0x0004, false), 0xE3);
+
+    //Reads during secondary OAM clear see $FF, writes while rendering only move OAMADDR
+    ppu.oam_ram[0] = 0x12;
+    ppu.cpu_write(0x0003, 0x00);
+    ppu.cpu_write(0x0001, 0x18);
+    clock_to(&mut ppu, 10, 30);
+    assert_eq!(ppu.cpu_read(0x0004, false), 0xFF);
+    ppu.cpu_write(0x0004, 0x34);
+    assert_eq!(ppu.oam_address_port, 0x04);
+    assert_eq!(ppu.oam_ram[0], 0x12);
+    assert!(ppu.sprite_zero_hit_allowed(8) && !ppu.sprite_zero_hit_allowed(7) && !ppu.sprite_zero_hit_allowed(255));
+}
+
+#[test]
+fn test_sprite_limit_off() {
+    let mut ppu = PPU::new();
+    ppu.oam_ram = [0xF0; 256];
+    for i in 0..10 {
+        ppu.oam_ram[i * 4] = 20;
+        ppu.oam_ram[i * 4 + 3] = i as u8 * 8;
+    }
+    ppu.sprite_limit = false;
+    ppu.cpu_write(0x0001, 0x18);
+    clock_to(&mut ppu, 25, 258);
+    assert_eq!(ppu.oam_sprites.len(), 8);
+    assert_eq!(ppu.extra_sprites.len(), 2);
+    assert_eq!(ppu.extra_sprites[0].x, 64);
+    assert!(ppu.status.sprite_overflow());
+
+    ppu.sprite_limit = true;
+    clock_to(&mut ppu, 26, 258);
+    assert!(ppu.extra_sprites.is_empty());
+}
+
+#[test]
+fn test_open_bus_decay() {
+    let mut ppu = PPU::new();
+    ppu.cpu_write(0x0000, 0x5A);
+    assert_eq!(ppu.cpu_read(0x0001, false), 0x5A);
+    assert_eq!(ppu.cpu_read(0x0002, false), 0x1A);
+    //The status read drove the top 3 bits low
+    assert_eq!(ppu.cpu_read(0x0005, false), 0x1A);
+    ppu.frame_count += OPEN_BUS_DECAY_FRAMES;
+    assert_eq!(ppu.cpu_read(0x0003, false), 0x1A);
+    ppu.frame_count += 1;
+    assert_eq!(ppu.cpu_read(0x0003, false), 0x00);
+}
+
+#[test]
+fn test_palette_read_buffer() {
+    let mut ppu = PPU::new();
+    ppu.connect_cartridge(Rc::new(RefCell::new(Cartridge::new("src/test/nestest.nes".to_string()).unwrap())));
+    let set_address = |ppu: &mut PPU, address: u16| {
+        ppu.cpu_write(0x0006, (address >> 8) as u8);
+        ppu.cpu_write(0x0006, address as u8);
+    };
+    set_address(&mut ppu, 0x2F00);
+    ppu.cpu_write(0x0007, 0x77);
+    set_address(&mut ppu, 0x3F00);
+    ppu.cpu_write(0x0007, 0x21);
+
+    //Palette data comes straight back with the top 2 bits from open bus
+    set_address(&mut ppu, 0x3F00);
+    ppu.cpu_write(0x0003, 0xC0);
+    assert_eq!(ppu.cpu_read(0x0007, false), 0xE1);
+    //And the buffer now holds the nametable byte under the palette
+    set_address(&mut ppu, 0x2000);
+    assert_eq!(ppu.cpu_read(0x0007, false), 0x77);
+}
+
+#[test]
+fn test_vblank_read_race() {
+    let mut ppu = PPU::new();
+    ppu.cpu_write(0x0000, 0x80);
+    //A dot before the flag is set it reads clear and the flag and NMI never happen
+    clock_to(&mut ppu, 241, 1);
+    assert_eq!(ppu.cpu_read(0x0002, false) & 0x80, 0);
+    clock_to(&mut ppu, 241, 10);
+    assert_eq!(ppu.cpu_read(0x0002, false) & 0x80, 0);
+    assert!(!ppu.nmi_enabled);
+
+    //On the dot it reads set but the NMI is cancelled
+    clock_to(&mut ppu, 241, 2);
+    assert_eq!(ppu.cpu_read(0x0002, false) & 0x80, 0x80);
+    assert!(!ppu.nmi_enabled && ppu.nmi_suppressed);
+
+    //Later the next frame nothing is suppressed
+    ppu.nmi_suppressed = false;
+    clock_to(&mut ppu, 240, 0);
+    clock_to(&mut ppu, 241, 5);
+    assert_eq!(ppu.cpu_read(0x0002, false) & 0x80, 0x80);
+    assert!(ppu.nmi_enabled && !ppu.nmi_suppressed);
+}
+
+#[test]
+fn test_vram_increment_while_rendering() {
+    let mut ppu = PPU::new();
+    ppu.cpu_write(0x0006, 0x20);
+    ppu.cpu_write(0x0006, 0x00);
+    ppu.cpu_read(0x0007, false);
+    assert_eq!(ppu.v_address_register.get(), 0x2001);
+
+    ppu.cpu_write(0x0001, 0x08);
+    clock_to(&mut ppu, 10, 100);
+    ppu.cpu_write(0x0006, 0x20);
+    ppu.cpu_write(0x0006, 0x00);
+    ppu.cpu_read(0x0007, false);
+    //Coarse X and fine Y both step
+    assert_eq!(ppu.v_address_register.get(), 0x3001);
+}
+
+#[test]
+fn test_warm_up_ignores_writes() {
+    let mut ppu = PPU::new();
+    ppu.power_on(&mut crate::power::RamInit::Seeded(1).filler());
+    assert_eq!(ppu.cpu_read(0x0002, false) & 0x80, 0x80);
+    assert!(ppu.palette_table.iter().all(|&c| c < 0x40));
+
+    ppu.cpu_write(0x0000, 0x80);
+    ppu.cpu_write(0x0003, 0x10);
+    assert!(!ppu.controller.generate_nmi());
+    assert_eq!(ppu.oam_address_port, 0x10);
+    for _ in 0..Region::Ntsc.ppu_warm_up_dots() {
+        ppu.clock();
+    }
+    ppu.cpu_write(0x0000, 0x80);
+    assert!(ppu.controller.generate_nmi());
+
+    //Reset starts it over but keeps OAM
+    let oam = ppu.oam_ram;
+    ppu.reset();
+    ppu.cpu_write(0x0001, 0x18);
+    assert!(!ppu.mask.show_background());
+    assert_eq!(ppu.oam_ram[..], oam[..]);
+
+    //PAL warms up for longer
+    ppu.set_region(Region::Pal);
+    ppu.reset();
+    for _ in 0..Region::Ntsc.ppu_warm_up_dots() {
+        ppu.clock();
+    }
+    ppu.cpu_write(0x0001, 0x18);
+    assert!(!ppu.mask.show_background());
+    for _ in Region::Ntsc.ppu_warm_up_dots()..Region::Pal.ppu_warm_up_dots() {
+        ppu.clock();
+    }
+    ppu.cpu_write(0x0001, 0x18);
+    assert!(ppu.mask.show_background());
+}
+
+#[cfg(test)]
+fn frame_length(ppu: &mut PPU) -> u32 {
+    let mut dots = 0;
+    ppu.frame_complete = false;
+    while !ppu.frame_complete {
+        ppu.clock();
+        dots += 1;
+    }
+    return dots;
+}
+
+#[test]
+fn test_odd_frame_skip() {
+    let mut ppu = PPU::new();
+    frame_length(&mut ppu);
+    assert_eq!(frame_length(&mut ppu), 341 * 262);
+    assert_eq!(frame_length(&mut ppu), 341 * 262);
+
+    //Rendering on, every other frame is a dot short
+    ppu.cpu_write(0x0001, 0x08);
+    let lengths = [frame_length(&mut ppu), frame_length(&mut ppu)];
+    assert_eq!(lengths[0] + lengths[1], 341 * 262 * 2 - 1);
+    assert!(lengths.contains(&(341 * 262 - 1)));
+}
+
+#[test]
+fn test_nmi_edges() {
+    let mut ppu = PPU::new();
+    clock_to(&mut ppu, 241, 1);
+    assert!(!ppu.status.vertical_blank());
+    ppu.clock();
+    assert!(ppu.status.vertical_blank() && !ppu.nmi_enabled);
+
+    //Enabling NMI during vblank raises one, and again after toggling it off and on
+    ppu.cpu_write(0x0000, 0x80);
+    assert!(ppu.nmi_enabled);
+    ppu.nmi_enabled = false;
+    ppu.cpu_write(0x0000, 0x80);
+    assert!(!ppu.nmi_enabled);
+    ppu.cpu_write(0x0000, 0x00);
+    ppu.cpu_write(0x0000, 0x80);
+    assert!(ppu.nmi_enabled);
+
+    //Not once vblank has been read
+    ppu.nmi_enabled = false;
+    ppu.cpu_read(0x0002, false);
+    ppu.cpu_write(0x0000, 0x00);
+    ppu.cpu_write(0x0000, 0x80);
+    assert!(!ppu.nmi_enabled);
+
+    //Disabling it as vblank starts cancels the NMI
+    clock_to(&mut ppu, 0, 0);
+    clock_to(&mut ppu, 241, 2);
+    assert!(ppu.nmi_enabled);
+    ppu.cpu_write(0x0000, 0x00);
+    assert!(!ppu.nmi_enabled && ppu.nmi_suppressed);
+
+    //Flag clears at dot 1 of the pre-render line
+    ppu.cpu_write(0x0000, 0x80);
+    clock_to(&mut ppu, -1, 1);
+    assert!(ppu.status.vertical_blank());
+    ppu.clock();
+    assert!(!ppu.status.vertical_blank());
+}
+
+#[test]
+fn test_region_frames() {
+    let mut ppu = PPU::new();
+    ppu.set_region(Region::Pal);
+    ppu.cpu_write(0x0001, 0x08);
+    frame_length(&mut ppu);
+    //No dot is skipped on PAL
+    assert_eq!(frame_length(&mut ppu), 341 * 312);
+    assert_eq!(frame_length(&mut ppu), 341 * 312);
+
+    //Dendy has the extra lines before vblank instead of after
+    ppu.set_region(Region::Dendy);
+    clock_to(&mut ppu, 241, 2);
+    assert!(!ppu.status.vertical_blank());
+    clock_to(&mut ppu, 291, 2);
+    assert!(ppu.status.vertical_blank());
+}
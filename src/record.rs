@@ -0,0 +1,123 @@
+use crate::apu::CAPTURE_SAMPLE_RATE;
+use crate::avi::{AviWriter, MAX_SIZE};
+use crate::region::Region;
+use crate::screenshot::{save_png, Image};
+use crate::wav::WavWriter;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RecordFormat {
+    Avi,
+    //Numbered PNGs and a WAV in a directory
+    Png,
+}
+
+impl RecordFormat {
+    pub fn from_name(name: &str) -> Option<RecordFormat> {
+        match name {
+            "avi" => Some(RecordFormat::Avi),
+            "png" => Some(RecordFormat::Png),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RecordFormat::Avi => "avi",
+            RecordFormat::Png => "frames",
+        }
+    }
+}
+
+enum Output {
+    Avi(AviWriter),
+    Png(WavWriter),
+}
+
+//Writes every emulated frame with the capture samples the APU made during it, so the timing comes
+//from the emulation and not the host
+pub struct Recorder {
+    output: Output,
+    pub path: PathBuf,
+    width: usize,
+    height: usize,
+    sample_rate: u32,
+    //Frames per second as rate / scale
+    frame_rate: (u32, u32),
+    frames: u32,
+    part: u32,
+}
+
+impl Recorder {
+    //The APU's capture output must be on, see APU::set_capture
+    pub fn start(path: &Path, format: RecordFormat, width: usize, height: usize, region: Region) -> io::Result<Recorder> {
+        let (rate, scale) = region.frame_rate_fraction();
+        let sample_rate = CAPTURE_SAMPLE_RATE as u32;
+        let output = match format {
+            RecordFormat::Avi => Output::Avi(AviWriter::create(path, width, height, rate, scale, sample_rate)?),
+            RecordFormat::Png => {
+                fs::create_dir_all(path)?;
+                Output::Png(WavWriter::create(&path.join("audio.wav"), sample_rate, 1)?)
+            }
+        };
+        Ok(Recorder {
+            output,
+            path: path.to_path_buf(),
+            width,
+            height,
+            sample_rate,
+            frame_rate: (rate, scale),
+            frames: 0,
+            part: 0,
+        })
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    //Long AVI recordings continue in capture-1.avi, capture-2.avi ... before hitting the 32 bit limits
+    fn next_part(&mut self) -> io::Result<()> {
+        self.part += 1;
+        let stem = self.path.file_stem().and_then(|s| s.to_str()).unwrap_or("capture");
+        let path = self.path.with_file_name(format!("{}-{}.avi", stem, self.part));
+        println!("continuing recording in {}", path.display());
+        let (rate, scale) = self.frame_rate;
+        self.output = Output::Avi(AviWriter::create(&path, self.width, self.height, rate, scale, self.sample_rate)?);
+        Ok(())
+    }
+
+    pub fn frame(&mut self, image: &mut Image, samples: &[i16]) -> Result<(), String> {
+        if image.width != self.width || image.height != self.height {
+            return Err(format!("frame is {}x{}, recording is {}x{}", image.width, image.height, self.width, self.height));
+        }
+        let split = match self.output {
+            Output::Avi(ref writer) => writer.size() > MAX_SIZE,
+            Output::Png(_) => false,
+        };
+        if split {
+            self.next_part().map_err(|e| e.to_string())?;
+        }
+        match self.output {
+            Output::Avi(ref mut writer) => {
+                writer.write_frame(&image.pixels).map_err(|e| e.to_string())?;
+                writer.write_samples(samples).map_err(|e| e.to_string())?;
+            }
+            Output::Png(ref mut wav) => {
+                save_png(image, &self.path.join(format!("frame_{:06}.png", self.frames)))?;
+                wav.write_samples(samples).map_err(|e| e.to_string())?;
+            }
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn stop(mut self) -> io::Result<()> {
+        match self.output {
+            Output::Avi(ref mut writer) => writer.finish(),
+            Output::Png(ref mut wav) => wav.finish(),
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//https://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+//Timing differences between the console families. Dendy is a PAL famiclone with NTSC style
+//CPU and APU timing at the PAL frame rate, vblank starts 50 lines late instead.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Region::Ntsc => "ntsc",
+            Region::Pal => "pal",
+            Region::Dendy => "dendy",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Region> {
+        match name {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            "dendy" => Some(Region::Dendy),
+            _ => None,
+        }
+    }
+
+    //NES 2.0 header byte 12, multi-region games run as NTSC
+    //https://wiki.nesdev.com/w/index.php/NES_2.0#CPU.2FPPU_Timing
+    pub fn from_timing(timing: u8) -> Region {
+        match timing & 0x03 {
+            1 => Region::Pal,
+            3 => Region::Dendy,
+            _ => Region::Ntsc,
+        }
+    }
+
+    pub fn cpu_clock_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc => 1_789_773.0,
+            Region::Pal => 1_662_607.0,
+            Region::Dendy => 1_773_448.0,
+        }
+    }
+
+    //PPU dots per 5 CPU cycles, 3.2 dots a cycle on PAL
+    pub fn ppu_dots_per_5_cycles(&self) -> u32 {
+        match self {
+            Region::Pal => 16,
+            _ => 15,
+        }
+    }
+
+    //After power on or reset, writes to $2000, $2001, $2005 and $2006 are ignored for this many CPU
+    //cycles while the PPU warms up, until about the first pre-render line. Dendy has PAL's frame
+    //at NTSC's 3 dots a cycle so takes longer again
+    //https://wiki.nesdev.com/w/index.php/PPU_power_up_state
+    pub fn ppu_warm_up_cycles(&self) -> u32 {
+        match self {
+            Region::Ntsc => 29658,
+            Region::Pal => 33132,
+            Region::Dendy => 35341,
+        }
+    }
+
+    pub fn ppu_warm_up_dots(&self) -> u32 {
+        return self.ppu_warm_up_cycles() * self.ppu_dots_per_5_cycles() / 5;
+    }
+
+    //Scanlines per frame including the pre-render line
+    pub fn scanlines(&self) -> i32 {
+        match self {
+            Region::Ntsc => 262,
+            _ => 312,
+        }
+    }
+
+    pub fn vblank_scanline(&self) -> i32 {
+        match self {
+            Region::Dendy => 291,
+            _ => 241,
+        }
+    }
+
+    //Only the NTSC PPU drops a dot on odd frames
+    pub fn skips_odd_frame_dot(&self) -> bool {
+        return *self == Region::Ntsc;
+    }
+
+    //Frames per second as an exact fraction (rate / scale) of the master clock
+    pub fn frame_rate_fraction(&self) -> (u32, u32) {
+        match self {
+            //21477272 / 4 / 89341.5
+            Region::Ntsc => (39_375_000, 655_171),
+            //26601712 / 5 / (341 * 312)
+            _ => (3_325_214, 66_495),
+        }
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        let (rate, scale) = self.frame_rate_fraction();
+        return rate as f64 / scale as f64;
+    }
+}
+
+#[test]
+fn test_region_timing() {
+    assert_eq!(Region::from_timing(0x01), Region::Pal);
+    assert_eq!(Region::from_timing(0x02), Region::Ntsc);
+    assert_eq!(Region::from_name("dendy"), Some(Region::Dendy));
+    assert!((Region::Ntsc.frame_rate() - 60.0988).abs() < 1e-4);
+    assert!((Region::Pal.frame_rate() - 50.0070).abs() < 1e-4);
+    //A PAL frame is 341 * 312 dots at 3.2 dots per CPU cycle
+    let cycles = 341.0 * 312.0 * 5.0 / Region::Pal.ppu_dots_per_5_cycles() as f64;
+    assert!((Region::Pal.cpu_clock_rate() / cycles - Region::Pal.frame_rate()).abs() < 1e-3);
+}
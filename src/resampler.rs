@@ -0,0 +1,270 @@
+//Band limited step synthesis, see http://www.slack.net/~ant/bl-synth/
+//The APU output only changes in steps, so instead of point sampling it every few cycles each
+//change in level is added to the output as a windowed-sinc impulse at its exact sub-sample
+//position and the buffer is integrated when read back.
+
+use std::f64::consts::PI;
+
+//https://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+pub const NTSC_CLOCK_RATE: f64 = 1_789_773.0;
+
+const WIDTH: usize = 16;
+const PHASES: usize = 32;
+//Fraction of the output nyquist frequency kept by the kernel
+const CUTOFF: f64 = 0.90;
+//Largest pitch change dynamic rate control may apply, 0.5% is inaudible
+const MAX_RATE_DELTA: f64 = 0.005;
+
+pub struct BlipBuffer {
+    kernel: Vec<[f32; WIDTH]>,
+    buffer: Vec<f32>,
+    factor: f64,
+    offset: f64,
+    integrator: f32,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: f64, sample_rate: f64) -> BlipBuffer {
+        BlipBuffer {
+            kernel: BlipBuffer::build_kernel(),
+            buffer: vec![0.0; WIDTH * 4],
+            factor: sample_rate / clock_rate,
+            offset: 0.0,
+            integrator: 0.0,
+        }
+    }
+
+    fn build_kernel() -> Vec<[f32; WIDTH]> {
+        let centre = (WIDTH / 2) as f64;
+        let mut kernel = Vec::with_capacity(PHASES + 1);
+        for phase in 0..=PHASES {
+            let fraction = phase as f64 / PHASES as f64;
+            let mut taps = [0.0f64; WIDTH];
+            let mut sum = 0.0;
+            for (k, tap) in taps.iter_mut().enumerate() {
+                let t = k as f64 - centre - fraction;
+                let x = t * CUTOFF;
+                let sinc = if x.abs() < 1e-9 { 1.0 } else { (PI * x).sin() / (PI * x) };
+                //Blackman window
+                let window = if t.abs() >= centre {
+                    0.0
+                } else {
+                    0.42 + 0.5 * (PI * t / centre).cos() + 0.08 * (2.0 * PI * t / centre).cos()
+                };
+                *tap = sinc * window;
+                sum += *tap;
+            }
+            let mut row = [0.0f32; WIDTH];
+            for k in 0..WIDTH {
+                row[k] = (taps[k] / sum) as f32;
+            }
+            kernel.push(row);
+        }
+        kernel
+    }
+
+    pub fn set_factor(&mut self, factor: f64) {
+        self.factor = factor;
+    }
+
+    //Adds a change in level of `delta` at `time` clocks since the last end_frame
+    pub fn add_delta(&mut self, time: u32, delta: f32) {
+        let position = self.offset + time as f64 * self.factor;
+        let index = position as usize;
+        let phase = ((position - index as f64) * PHASES as f64 + 0.5) as usize;
+
+        if self.buffer.len() < index + WIDTH {
+            self.buffer.resize(index + WIDTH, 0.0);
+        }
+        let taps = &self.kernel[phase];
+        for k in 0..WIDTH {
+            self.buffer[index + k] += delta * taps[k];
+        }
+    }
+
+    //Ends a run of `clocks` clocks and hands every completed sample to `output`
+    pub fn end_frame<F: FnMut(f32)>(&mut self, clocks: u32, mut output: F) {
+        self.offset += clocks as f64 * self.factor;
+        let count = self.offset as usize;
+        if self.buffer.len() < count + WIDTH {
+            self.buffer.resize(count + WIDTH, 0.0);
+        }
+
+        for i in 0..count {
+            self.integrator += self.buffer[i];
+            output(self.integrator);
+        }
+
+        self.buffer.copy_within(count.., 0);
+        let length = self.buffer.len();
+        for sample in &mut self.buffer[length - count..] {
+            *sample = 0.0;
+        }
+        self.offset -= count as f64;
+    }
+}
+
+//First order filters as described in https://wiki.nesdev.com/w/index.php/APU_Mixer
+pub struct HighPass {
+    alpha: f32,
+    previous_in: f32,
+    previous_out: f32,
+}
+
+impl HighPass {
+    pub fn new(cutoff: f64, sample_rate: f64) -> HighPass {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        HighPass {
+            alpha: (rc / (rc + dt)) as f32,
+            previous_in: 0.0,
+            previous_out: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        let output = self.alpha * (self.previous_out + input - self.previous_in);
+        self.previous_in = input;
+        self.previous_out = output;
+        output
+    }
+}
+
+pub struct LowPass {
+    alpha: f32,
+    previous_out: f32,
+}
+
+impl LowPass {
+    pub fn new(cutoff: f64, sample_rate: f64) -> LowPass {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        LowPass {
+            alpha: (dt / (rc + dt)) as f32,
+            previous_out: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        self.previous_out += self.alpha * (input - self.previous_out);
+        self.previous_out
+    }
+}
+
+//Takes the APU level once per CPU clock and produces filtered 16 bit samples at the output rate
+pub struct Resampler {
+    blip: BlipBuffer,
+    clock_rate: f64,
+    sample_rate: f64,
+    rate_adjust: f64,
+    level: f32,
+
+    high_pass_90: HighPass,
+    high_pass_440: HighPass,
+    low_pass_14k: LowPass,
+}
+
+impl Resampler {
+    pub fn new(clock_rate: f64, sample_rate: f64) -> Resampler {
+        Resampler {
+            blip: BlipBuffer::new(clock_rate, sample_rate),
+            clock_rate,
+            sample_rate,
+            rate_adjust: 1.0,
+            level: 0.0,
+            high_pass_90: HighPass::new(90.0, sample_rate),
+            high_pass_440: HighPass::new(440.0, sample_rate),
+            low_pass_14k: LowPass::new(14_000.0, sample_rate),
+        }
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    pub fn clock_rate(&self) -> f64 {
+        self.clock_rate
+    }
+
+    //Nudges the output rate by up to MAX_RATE_DELTA to steer the host queue towards its target.
+    //`fill` is queued audio divided by the target amount, 1.0 meaning on target.
+    pub fn set_rate_control(&mut self, fill: f64) {
+        let error = (1.0 - fill).max(-1.0).min(1.0);
+        self.rate_adjust = 1.0 + error * MAX_RATE_DELTA;
+    }
+
+    pub fn rate_adjust(&self) -> f64 {
+        self.rate_adjust
+    }
+
+    //Starts a new resampler at the same sub-sample position as one already running, so both
+    //produce the same number of samples at every end_frame
+    pub fn align_with(&mut self, other: &Resampler) {
+        self.blip.offset = other.blip.offset;
+    }
+
+    //Level is expected in the 0.0 - 1.0 range of the APU mixer
+    pub fn set_level(&mut self, time: u32, level: f32) {
+        if level != self.level {
+            self.blip.add_delta(time, level - self.level);
+            self.level = level;
+        }
+    }
+
+    pub fn end_frame(&mut self, clocks: u32, samples: &mut Vec<i16>) {
+        let high_pass_90 = &mut self.high_pass_90;
+        let high_pass_440 = &mut self.high_pass_440;
+        let low_pass_14k = &mut self.low_pass_14k;
+        self.blip.end_frame(clocks, |sample| {
+            let filtered = low_pass_14k.process(high_pass_440.process(high_pass_90.process(sample)));
+            let scaled = (filtered * 32767.0).max(-32768.0).min(32767.0);
+            samples.push(scaled as i16);
+        });
+        //Only applied between frames so every delta in a frame shares the same time base
+        self.blip
+            .set_factor(self.sample_rate * self.rate_adjust / self.clock_rate);
+    }
+}
+
+#[test]
+fn test_sample_count_follows_rate() {
+    let mut resampler = Resampler::new(NTSC_CLOCK_RATE, 44100.0);
+    let mut samples = Vec::new();
+    for _ in 0..60 {
+        resampler.end_frame(29781, &mut samples);
+    }
+    //One second of clocks, give or take the kernel delay
+    let expected = (60.0 * 29781.0 * 44100.0 / NTSC_CLOCK_RATE) as i64;
+    assert!((samples.len() as i64 - expected).abs() <= 1);
+
+    resampler.set_rate_control(0.0);
+    assert!((resampler.rate_adjust() - 1.005).abs() < 1e-9);
+    resampler.set_rate_control(2.0);
+    assert!((resampler.rate_adjust() - 0.995).abs() < 1e-9);
+    resampler.set_rate_control(1.0);
+    assert!((resampler.rate_adjust() - 1.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_step_is_band_limited() {
+    let mut blip = BlipBuffer::new(NTSC_CLOCK_RATE, 44100.0);
+    let mut out = Vec::new();
+    blip.add_delta(1000, 1.0);
+    blip.end_frame(4000, |s| out.push(s));
+    //Settles at the full step height, ringing limited to the Gibbs overshoot of the cutoff
+    let last = *out.last().unwrap();
+    assert!((last - 1.0).abs() < 1e-3);
+    assert!(out.iter().all(|&s| s > -0.15 && s < 1.15));
+    //Nothing leaks in before the kernel starts
+    assert!(out[..20].iter().all(|&s| s == 0.0));
+}
+
+#[test]
+fn test_high_pass_removes_dc() {
+    let mut filter = HighPass::new(90.0, 44100.0);
+    let mut output = 1.0;
+    for _ in 0..44100 {
+        output = filter.process(1.0);
+    }
+    assert!(output.abs() < 1e-3);
+}
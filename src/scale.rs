@@ -0,0 +1,546 @@
+use crate::ppu::{RENDER_HEIGHT, RENDER_WIDTH};
+
+//NES pixels are slightly wider than they are tall on an NTSC TV
+//https://wiki.nesdev.com/w/index.php/Overscan#Aspect_ratio
+pub const PIXEL_ASPECT: f64 = 8.0 / 7.0;
+pub const MAX_SCALE: u32 = 8;
+//Brightness of the gaps between scanlines
+const SCANLINE_LEVEL: u32 = 160;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ScaleFilter {
+    None,
+    Scanlines,
+    Hq2x,
+    Hq4x,
+    Xbr,
+}
+
+pub const SCALE_FILTERS: [ScaleFilter; 5] =
+    [ScaleFilter::None, ScaleFilter::Scanlines, ScaleFilter::Hq2x, ScaleFilter::Hq4x, ScaleFilter::Xbr];
+
+impl ScaleFilter {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScaleFilter::None => "none",
+            ScaleFilter::Scanlines => "scanlines",
+            ScaleFilter::Hq2x => "hq2x",
+            ScaleFilter::Hq4x => "hq4x",
+            ScaleFilter::Xbr => "xbr",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ScaleFilter> {
+        SCALE_FILTERS.iter().find(|f| f.name() == name).copied()
+    }
+
+    pub fn next(&self) -> ScaleFilter {
+        let i = SCALE_FILTERS.iter().position(|f| f == self).unwrap_or(0);
+        return SCALE_FILTERS[(i + 1) % SCALE_FILTERS.len()];
+    }
+
+    //Output is this many times the PPU picture in each direction
+    pub fn factor(&self) -> usize {
+        match self {
+            ScaleFilter::None => 1,
+            ScaleFilter::Scanlines | ScaleFilter::Hq2x | ScaleFilter::Xbr => 2,
+            ScaleFilter::Hq4x => 4,
+        }
+    }
+}
+
+//Lines and columns hidden at each edge, TVs lost about 8 lines top and bottom and games often
+//leave garbage tiles there
+//https://wiki.nesdev.com/w/index.php/Overscan
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Overscan {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+impl Overscan {
+    //Always leaves at least one pixel visible
+    pub fn new(top: usize, bottom: usize, left: usize, right: usize) -> Overscan {
+        let top = top.min(RENDER_HEIGHT - 1);
+        let left = left.min(RENDER_WIDTH - 1);
+        Overscan {
+            top,
+            bottom: bottom.min(RENDER_HEIGHT - 1 - top),
+            left,
+            right: right.min(RENDER_WIDTH - 1 - left),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        RENDER_WIDTH - self.left - self.right
+    }
+
+    pub fn height(&self) -> usize {
+        RENDER_HEIGHT - self.top - self.bottom
+    }
+
+    //Edges in pixels of a picture at another resolution, e.g. the wider NTSC output
+    fn edges(&self, width: usize, height: usize) -> (usize, usize, usize, usize) {
+        return (
+            self.top * height / RENDER_HEIGHT,
+            self.bottom * height / RENDER_HEIGHT,
+            self.left * width / RENDER_WIDTH,
+            self.right * width / RENDER_WIDTH,
+        );
+    }
+
+    pub fn cropped_size(&self, width: usize, height: usize) -> (usize, usize) {
+        let (top, bottom, left, right) = self.edges(width, height);
+        (width - left - right, height - top - bottom)
+    }
+
+    //Crops an RGB24 frame of the whole picture at any resolution into output, reusing its buffer
+    pub fn crop(&self, frame: &[u8], width: usize, height: usize, output: &mut Vec<u8>) {
+        let (top, bottom, left, right) = self.edges(width, height);
+        output.clear();
+        for y in top..height - bottom {
+            output.extend_from_slice(&frame[(y * width + left) * 3..(y * width + width - right) * 3]);
+        }
+    }
+}
+
+//Window size for a whole number multiple of the visible picture, optionally stretched to 8:7 pixels
+pub fn display_size(scale: u32, aspect_correct: bool, overscan: &Overscan) -> (u32, u32) {
+    let width = overscan.width() as f64 * scale as f64;
+    let width = if aspect_correct { width * PIXEL_ASPECT } else { width };
+    return (width.round() as u32, overscan.height() as u32 * scale);
+}
+
+//Sits between PPU::render (after cropping) and the texture upload
+pub struct Scaler {
+    pub filter: ScaleFilter,
+    pixels: Vec<u32>,
+    output: Vec<u8>,
+}
+
+impl Scaler {
+    pub fn new(filter: ScaleFilter) -> Scaler {
+        Scaler {
+            filter,
+            pixels: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+        (width * self.filter.factor(), height * self.filter.factor())
+    }
+
+    //Takes an RGB24 frame, returns RGB24 at output_size
+    pub fn scale(&mut self, frame: &[u8], width: usize, height: usize) -> &[u8] {
+        self.output.clear();
+        if self.filter == ScaleFilter::None {
+            self.output.extend_from_slice(frame);
+            return &self.output;
+        }
+        self.pixels.clear();
+        self.pixels.extend(frame.chunks(3).map(|p| (p[0] as u32) << 16 | (p[1] as u32) << 8 | p[2] as u32));
+
+        let scaled = match self.filter {
+            ScaleFilter::Scanlines => scanlines(&self.pixels, width, height),
+            ScaleFilter::Hq2x => hqx(&self.pixels, width, height, 2),
+            ScaleFilter::Hq4x => hqx(&self.pixels, width, height, 4),
+            ScaleFilter::Xbr => xbr2x(&self.pixels, width, height),
+            ScaleFilter::None => unreachable!(),
+        };
+
+        for p in scaled {
+            self.output.extend_from_slice(&[(p >> 16) as u8, (p >> 8) as u8, p as u8]);
+        }
+        return &self.output;
+    }
+}
+
+fn channels(p: u32) -> [u32; 3] {
+    return [(p >> 16) & 0xFF, (p >> 8) & 0xFF, p & 0xFF];
+}
+
+//Weighted average of a few colours
+fn blend(colours: &[(u32, u32)]) -> u32 {
+    let total: u32 = colours.iter().map(|&(_, w)| w).sum();
+    let mut mixed = [0u32; 3];
+    for &(colour, weight) in colours {
+        for (m, c) in mixed.iter_mut().zip(channels(colour).iter()) {
+            *m += c * weight;
+        }
+    }
+    return (mixed[0] / total) << 16 | (mixed[1] / total) << 8 | mixed[2] / total;
+}
+
+fn yuv(p: u32) -> (f32, f32, f32) {
+    let [r, g, b] = channels(p);
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+    return (
+        0.299 * r + 0.587 * g + 0.114 * b,
+        -0.169 * r - 0.331 * g + 0.5 * b,
+        0.5 * r - 0.419 * g - 0.081 * b,
+    );
+}
+
+//hqx's similarity test, thresholds from the original implementation
+fn different(a: u32, b: u32) -> bool {
+    if a == b {
+        return false;
+    }
+    let (y1, u1, v1) = yuv(a);
+    let (y2, u2, v2) = yuv(b);
+    return (y1 - y2).abs() > 48.0 || (u1 - u2).abs() > 7.0 || (v1 - v2).abs() > 6.0;
+}
+
+fn distance(a: u32, b: u32) -> f32 {
+    let (y1, u1, v1) = yuv(a);
+    let (y2, u2, v2) = yuv(b);
+    return 48.0 * (y1 - y2).abs() + 7.0 * (u1 - u2).abs() + 6.0 * (v1 - v2).abs();
+}
+
+//Reads past the edges repeat the border pixels
+fn sampler(pixels: &[u32], width: usize, height: usize) -> impl Fn(i32, i32) -> u32 + '_ {
+    move |x: i32, y: i32| {
+        let x = x.max(0).min(width as i32 - 1) as usize;
+        let y = y.max(0).min(height as i32 - 1) as usize;
+        pixels[y * width + x]
+    }
+}
+
+fn scanlines(pixels: &[u32], width: usize, height: usize) -> Vec<u32> {
+    let mut output = vec![0; width * height * 4];
+    for y in 0..height {
+        for x in 0..width {
+            let p = pixels[y * width + x];
+            let [r, g, b] = channels(p);
+            let dark = (r * SCANLINE_LEVEL / 255) << 16 | (g * SCANLINE_LEVEL / 255) << 8 | b * SCANLINE_LEVEL / 255;
+            for dx in 0..2 {
+                output[(y * 2) * width * 2 + x * 2 + dx] = p;
+                output[(y * 2 + 1) * width * 2 + x * 2 + dx] = dark;
+            }
+        }
+    }
+    return output;
+}
+
+//Which blend each output corner gets, indexed by which of the eight neighbours differ from the
+//centre, seen from the top left corner with bit 0 the top left neighbour. The other corners turn
+//their neighbourhood onto it. hq2x's 256 cases folded into twenty rules, from byuu's hq2x
+//https://en.wikipedia.org/wiki/Hqx
+#[rustfmt::skip]
+const HQ_RULES: [u8; 256] = [
+    4, 4, 6,  2, 4, 4, 6,  2, 5,  3, 15, 12, 5,  3, 17, 13,
+    4, 4, 6, 18, 4, 4, 6, 18, 5,  3, 12, 12, 5,  3,  1, 12,
+    4, 4, 6,  2, 4, 4, 6,  2, 5,  3, 17, 13, 5,  3, 16, 14,
+    4, 4, 6, 18, 4, 4, 6, 18, 5,  3, 16, 12, 5,  3,  1, 14,
+    4, 4, 6,  2, 4, 4, 6,  2, 5, 19, 12, 12, 5, 19, 16, 12,
+    4, 4, 6,  2, 4, 4, 6,  2, 5,  3, 16, 12, 5,  3, 16, 12,
+    4, 4, 6,  2, 4, 4, 6,  2, 5, 19,  1, 12, 5, 19,  1, 14,
+    4, 4, 6,  2, 4, 4, 6, 18, 5,  3, 16, 12, 5, 19,  1, 14,
+    4, 4, 6,  2, 4, 4, 6,  2, 5,  3, 15, 12, 5,  3, 17, 13,
+    4, 4, 6,  2, 4, 4, 6,  2, 5,  3, 16, 12, 5,  3, 16, 12,
+    4, 4, 6,  2, 4, 4, 6,  2, 5,  3, 17, 13, 5,  3, 16, 14,
+    4, 4, 6,  2, 4, 4, 6,  2, 5,  3, 16, 13, 5,  3,  1, 14,
+    4, 4, 6,  2, 4, 4, 6,  2, 5,  3, 16, 12, 5,  3, 16, 13,
+    4, 4, 6,  2, 4, 4, 6,  2, 5,  3, 16, 12, 5,  3,  1, 12,
+    4, 4, 6,  2, 4, 4, 6,  2, 5,  3, 16, 12, 5,  3,  1, 14,
+    4, 4, 6,  2, 4, 4, 6,  2, 5,  3,  1, 12, 5,  3,  1, 14,
+];
+
+//In pattern bit order: top left, top, top right, left, right, bottom left, bottom, bottom right
+const HQ_NEIGHBOURS: [(i32, i32); 8] = [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)];
+
+//Quarter turns clockwise, the top left corner goes to the top right, bottom right, bottom left
+fn rotate((x, y): (i32, i32), turns: usize) -> (i32, i32) {
+    return (0..turns).fold((x, y), |(x, y), _| (-y, x));
+}
+
+//hq2x and hq4x, the table picks a rule for each corner which fills one output pixel for hq2x
+//or a 2x2 quarter of the output for hq4x
+fn hqx(pixels: &[u32], width: usize, height: usize, factor: usize) -> Vec<u32> {
+    let get = sampler(pixels, width, height);
+    let output_width = width * factor;
+    let half = factor / 2;
+    let mut output = vec![0; output_width * height * factor];
+    for y in 0..height as i32 {
+        for x in 0..width as i32 {
+            let e = get(x, y);
+            let mut grid = [[0; 3]; 3];
+            let mut differs = [[false; 3]; 3];
+            for dy in 0..3 {
+                for dx in 0..3 {
+                    grid[dy][dx] = get(x + dx as i32 - 1, y + dy as i32 - 1);
+                    differs[dy][dx] = different(e, grid[dy][dx]);
+                }
+            }
+            for turns in 0..4 {
+                let mut n = [0; 8];
+                let mut pattern = 0;
+                for (i, &offset) in HQ_NEIGHBOURS.iter().enumerate() {
+                    let (dx, dy) = rotate(offset, turns);
+                    n[i] = grid[(dy + 1) as usize][(dx + 1) as usize];
+                    if differs[(dy + 1) as usize][(dx + 1) as usize] {
+                        pattern |= 1 << i;
+                    }
+                }
+                let rule = HQ_RULES[pattern];
+                let quarter = if factor == 2 { [hq2x_corner(rule, e, &n); 4] } else { hq4x_quarter(rule, e, &n) };
+                //Place each pixel of the quarter by turning its offset from the centre of the block
+                for qy in 0..half {
+                    for qx in 0..half {
+                        let offset = ((qx * 2) as i32 + 1 - factor as i32, (qy * 2) as i32 + 1 - factor as i32);
+                        let (ox, oy) = rotate(offset, turns);
+                        let ox = x as usize * factor + ((ox + factor as i32 - 1) / 2) as usize;
+                        let oy = y as usize * factor + ((oy + factor as i32 - 1) / 2) as usize;
+                        output[oy * output_width + ox] = quarter[qy * half + qx];
+                    }
+                }
+            }
+        }
+    }
+    return output;
+}
+
+//The corner pixel of hq2x, n is the neighbourhood turned so the corner is at the top left
+fn hq2x_corner(rule: u8, e: u32, n: &[u32; 8]) -> u32 {
+    let [a, b, _, d, f, _, h, _] = *n;
+    //Rules 12 to 19 only blend across the corner when an edge runs along it
+    let edge = !different(b, d);
+    match rule {
+        1 => blend(&[(e, 3), (a, 1)]),
+        2 => blend(&[(e, 3), (d, 1)]),
+        3 => blend(&[(e, 3), (b, 1)]),
+        4 => blend(&[(e, 2), (d, 1), (b, 1)]),
+        5 => blend(&[(e, 2), (a, 1), (b, 1)]),
+        6 => blend(&[(e, 2), (a, 1), (d, 1)]),
+        12 | 13 | 14 if !edge => e,
+        15 | 16 | 17 if !edge => blend(&[(e, 3), (a, 1)]),
+        12 | 15 => blend(&[(e, 2), (d, 1), (b, 1)]),
+        13 | 17 => blend(&[(e, 2), (d, 3), (b, 3)]),
+        14 => blend(&[(e, 14), (d, 1), (b, 1)]),
+        16 => blend(&[(e, 6), (d, 1), (b, 1)]),
+        18 if !different(b, f) => blend(&[(e, 5), (b, 2), (d, 1)]),
+        18 => blend(&[(e, 3), (d, 1)]),
+        19 if !different(d, h) => blend(&[(e, 5), (d, 2), (b, 1)]),
+        19 => blend(&[(e, 3), (b, 1)]),
+        _ => e,
+    }
+}
+
+//The top left quarter of hq4x: the corner, the pixel beside it, the one below it and the inner
+//one. Same rules as hq2x with hq4x's blends, which spread the corner over the quarter
+fn hq4x_quarter(rule: u8, e: u32, n: &[u32; 8]) -> [u32; 4] {
+    let [a, b, _, d, f, _, h, _] = *n;
+    let edge = !different(b, d);
+    let towards_corner = || {
+        let side = blend(&[(e, 3), (a, 1)]);
+        [blend(&[(e, 5), (a, 3)]), side, side, blend(&[(e, 7), (a, 1)])]
+    };
+    let towards_left = || {
+        let (outer, inner) = (blend(&[(e, 5), (d, 3)]), blend(&[(e, 7), (d, 1)]));
+        [outer, inner, outer, inner]
+    };
+    let towards_top = || {
+        let (outer, inner) = (blend(&[(e, 5), (b, 3)]), blend(&[(e, 7), (b, 1)]));
+        [outer, outer, inner, inner]
+    };
+    match rule {
+        1 => towards_corner(),
+        2 => towards_left(),
+        3 => towards_top(),
+        4 => [
+            blend(&[(e, 2), (b, 1), (d, 1)]),
+            blend(&[(e, 5), (b, 2), (d, 1)]),
+            blend(&[(e, 5), (d, 2), (b, 1)]),
+            blend(&[(e, 6), (b, 1), (d, 1)]),
+        ],
+        5 => [
+            blend(&[(e, 5), (a, 3)]),
+            blend(&[(e, 5), (b, 2), (a, 1)]),
+            blend(&[(e, 3), (a, 1)]),
+            blend(&[(e, 7), (a, 1)]),
+        ],
+        6 => [
+            blend(&[(e, 5), (a, 3)]),
+            blend(&[(e, 3), (a, 1)]),
+            blend(&[(e, 5), (d, 2), (a, 1)]),
+            blend(&[(e, 7), (a, 1)]),
+        ],
+        12 | 13 | 14 if !edge => [e; 4],
+        15 | 16 | 17 if !edge => towards_corner(),
+        12 | 15 => [blend(&[(b, 1), (d, 1)]), blend(&[(b, 1), (e, 1)]), blend(&[(d, 1), (e, 1)]), e],
+        13 | 17 => [
+            blend(&[(b, 1), (d, 1)]),
+            blend(&[(b, 2), (e, 1), (d, 1)]),
+            blend(&[(d, 2), (e, 1), (b, 1)]),
+            e,
+        ],
+        14 => [blend(&[(e, 2), (b, 1), (d, 1)]), e, e, e],
+        16 => [blend(&[(e, 2), (b, 1), (d, 1)]), blend(&[(e, 3), (b, 1)]), blend(&[(e, 3), (d, 1)]), e],
+        //The start of a shallow slope running off to the right, or a steep one running down
+        18 if !different(b, f) => [
+            blend(&[(e, 3), (b, 1)]),
+            blend(&[(b, 3), (e, 1)]),
+            blend(&[(e, 5), (d, 3)]),
+            blend(&[(e, 7), (d, 1)]),
+        ],
+        18 => towards_left(),
+        19 if !different(d, h) => [
+            blend(&[(e, 3), (d, 1)]),
+            blend(&[(e, 5), (b, 3)]),
+            blend(&[(d, 3), (e, 1)]),
+            blend(&[(e, 7), (b, 1)]),
+        ],
+        19 => towards_top(),
+        _ => [e; 4],
+    }
+}
+
+//2xBR, level 1
+//https://forums.libretro.com/t/xbr-algorithm-tutorial/123
+fn xbr2x(pixels: &[u32], width: usize, height: usize) -> Vec<u32> {
+    let get = sampler(pixels, width, height);
+    let mut output = vec![0; width * height * 4];
+    for y in 0..height as i32 {
+        for x in 0..width as i32 {
+            let e = get(x, y);
+            //Each corner is the bottom right case mirrored, the rule is symmetric about the diagonal
+            for &(sx, sy) in [(-1, -1), (1, -1), (-1, 1), (1, 1)].iter() {
+                let at = |dx: i32, dy: i32| get(x + dx * sx, y + dy * sy);
+                let (b, c, d, f, g, h, i) = (at(0, -1), at(1, -1), at(-1, 0), at(1, 0), at(-1, 1), at(0, 1), at(1, 1));
+                let (f4, h5, i4, i5) = (at(2, 0), at(0, 2), at(2, 1), at(1, 2));
+
+                let edge = distance(e, c) + distance(e, g) + distance(i, f4) + distance(i, h5) + 4.0 * distance(h, f);
+                let across = distance(h, d) + distance(h, i5) + distance(f, i4) + distance(f, b) + 4.0 * distance(e, i);
+                let corner = if edge < across {
+                    let nearest = if distance(e, f) <= distance(e, h) { f } else { h };
+                    blend(&[(e, 1), (nearest, 1)])
+                } else {
+                    e
+                };
+                let ox = (x * 2 + (sx + 1) / 2) as usize;
+                let oy = (y * 2 + (sy + 1) / 2) as usize;
+                output[oy * width * 2 + ox] = corner;
+            }
+        }
+    }
+    return output;
+}
+
+#[test]
+fn test_scale_filters() {
+    let mut frame = vec![0u8; RENDER_WIDTH * RENDER_HEIGHT * 3];
+    //White triangle below the diagonal, the upscalers should smooth its edge
+    for y in 0..RENDER_HEIGHT {
+        for x in 0..y.min(RENDER_WIDTH) {
+            let i = (y * RENDER_WIDTH + x) * 3;
+            frame[i..i + 3].copy_from_slice(&[255, 255, 255]);
+        }
+    }
+
+    for &filter in SCALE_FILTERS.iter() {
+        let mut scaler = Scaler::new(filter);
+        let (width, height) = scaler.output_size(RENDER_WIDTH, RENDER_HEIGHT);
+        let output = scaler.scale(&frame, RENDER_WIDTH, RENDER_HEIGHT);
+        assert_eq!(output.len(), width * height * 3);
+        //Flat areas stay flat
+        let p = |x: usize, y: usize| output[(y * width + x) * 3];
+        assert_eq!(p(width - 1, 0), 0);
+        assert_eq!(p(0, height - 2), 255);
+
+        let factor = filter.factor();
+        //hq4x gives the very corner over to the edge, so look beside it
+        let edge = p(10 * factor + factor / 2 - 1, 10 * factor + factor - 1);
+        match filter {
+            ScaleFilter::Hq2x | ScaleFilter::Hq4x | ScaleFilter::Xbr => assert!(edge > 0 && edge < 255),
+            _ => assert!(edge == 0 || edge == 255),
+        }
+    }
+
+    let mut scaler = Scaler::new(ScaleFilter::Scanlines);
+    let output = scaler.scale(&frame, RENDER_WIDTH, RENDER_HEIGHT).to_vec();
+    assert_eq!(output[(RENDER_WIDTH * 2 * 201) * 3], SCANLINE_LEVEL as u8);
+}
+
+#[test]
+fn test_hqx_symmetry() {
+    //Mirroring a neighbourhood about the top left to bottom right diagonal swaps top for left,
+    //so the rule has to swap for its mirror image too
+    let mirror_bits = [0, 3, 5, 1, 6, 2, 4, 7];
+    let mirror_rule = |rule: u8| match rule {
+        2 => 3,
+        3 => 2,
+        5 => 6,
+        6 => 5,
+        18 => 19,
+        19 => 18,
+        r => r,
+    };
+    for pattern in 0..256 {
+        let mirrored = (0..8).filter(|&i| pattern & (1 << i) != 0).map(|i| 1 << mirror_bits[i]).sum::<usize>();
+        assert_eq!(HQ_RULES[mirrored], mirror_rule(HQ_RULES[pattern]), "pattern {}", pattern);
+    }
+
+    //So a picture symmetric about its diagonal scales to one that still is, which also needs
+    //the turned corners to agree with the table
+    let size = 32;
+    let mut seed = 1u32;
+    let mut frame = vec![0u8; size * size * 3];
+    for y in 0..size {
+        for x in 0..=y {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let c = if seed >> 16 & 1 == 1 { [255, 255, 255] } else { [0, 0, 0] };
+            frame[(y * size + x) * 3..(y * size + x) * 3 + 3].copy_from_slice(&c);
+            frame[(x * size + y) * 3..(x * size + y) * 3 + 3].copy_from_slice(&c);
+        }
+    }
+    for &filter in [ScaleFilter::Hq2x, ScaleFilter::Hq4x].iter() {
+        let mut scaler = Scaler::new(filter);
+        let (width, _) = scaler.output_size(size, size);
+        let output = scaler.scale(&frame, size, size);
+        let p = |x: usize, y: usize| &output[(y * width + x) * 3..(y * width + x) * 3 + 3];
+        for y in 0..width {
+            for x in 0..y {
+                assert_eq!(p(x, y), p(y, x), "{:?} at {},{}", filter, x, y);
+            }
+        }
+    }
+
+    //hq4x is its own pass rather than hq2x twice
+    let mut hq2x = Scaler::new(ScaleFilter::Hq2x);
+    let twice = hq2x.scale(&frame, size, size).to_vec();
+    let twice = hq2x.scale(&twice, size * 2, size * 2).to_vec();
+    assert_ne!(Scaler::new(ScaleFilter::Hq4x).scale(&frame, size, size), &twice[..]);
+}
+
+#[test]
+fn test_display_size() {
+    let full = Overscan::default();
+    assert_eq!(display_size(4, false, &full), (1024, 960));
+    assert_eq!(display_size(3, true, &full), (878, 720));
+    assert_eq!(display_size(2, false, &Overscan::new(8, 8, 0, 0)), (512, 448));
+}
+
+#[test]
+fn test_overscan_crop() {
+    let frame: Vec<u8> = (0..RENDER_WIDTH * RENDER_HEIGHT).flat_map(|i| vec![(i % RENDER_WIDTH) as u8, (i / RENDER_WIDTH) as u8, 0]).collect();
+    let overscan = Overscan::new(8, 16, 4, 2);
+    let mut cropped = Vec::new();
+    overscan.crop(&frame, RENDER_WIDTH, RENDER_HEIGHT, &mut cropped);
+    assert_eq!(cropped.len(), overscan.width() * overscan.height() * 3);
+    assert_eq!(overscan.width(), 250);
+    assert_eq!(overscan.height(), 216);
+    assert_eq!(&cropped[0..2], &[4, 8]);
+    let last = cropped.len() - 3;
+    assert_eq!(&cropped[last..last + 2], &[253, 223]);
+
+    //Doubled resolution crops twice as much
+    let wide = vec![0u8; RENDER_WIDTH * 2 * RENDER_HEIGHT * 3];
+    overscan.crop(&wide, RENDER_WIDTH * 2, RENDER_HEIGHT, &mut cropped);
+    assert_eq!(cropped.len(), 500 * 216 * 3);
+    assert_eq!(overscan.cropped_size(RENDER_WIDTH * 2, RENDER_HEIGHT), (500, 216));
+
+    assert_eq!(Overscan::new(300, 300, 0, 0).height(), 1);
+}
@@ -0,0 +1,112 @@
+use crate::ntsc::{NtscFilter, NTSC_HEIGHT, NTSC_WIDTH};
+use crate::ppu::{PixelFormat, PPU, RENDER_HEIGHT, RENDER_WIDTH};
+use crate::scale::{Overscan, ScaleFilter, Scaler};
+use sdl2::image::SaveSurface;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::surface::Surface;
+use std::path::Path;
+
+//RGB24 pixels and their size
+pub struct Image {
+    pub pixels: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Image {
+    pub fn new() -> Image {
+        Image { pixels: Vec::new(), width: 0, height: 0 }
+    }
+}
+
+//The visible part of the current frame at 256x240 (less overscan)
+pub fn native_image(ppu: &PPU, overscan: &Overscan) -> Image {
+    let mut image = Image::new();
+    native_image_into(ppu, overscan, &mut image);
+    return image;
+}
+
+//Same as native_image but reuses image's buffer, for callers that take every frame
+pub fn native_image_into(ppu: &PPU, overscan: &Overscan, image: &mut Image) {
+    image.width = overscan.width();
+    image.height = overscan.height();
+    if ppu.pixel_format() == PixelFormat::Rgb24 {
+        overscan.crop(ppu.frame_buffer(), RENDER_WIDTH, RENDER_HEIGHT, &mut image.pixels);
+        return;
+    }
+    //Other formats convert just the visible part from the colour indices, straight into the image
+    image.pixels.clear();
+    for y in overscan.top..overscan.top + image.height {
+        let start = y * RENDER_WIDTH + overscan.left;
+        for &p in &ppu.raw_frame()[start..start + image.width] {
+            let (r, g, b) = ppu.output_colour(p);
+            image.pixels.extend_from_slice(&[r, g, b]);
+        }
+    }
+}
+
+//The current frame as it goes to the window, through the NTSC filter or the scaler. The NTSC
+//filter has already run for this frame, running it again would flip the dot crawl phase
+pub fn display_image(ppu: &PPU, scaler: &mut Scaler, ntsc: &Option<NtscFilter>, overscan: &Overscan) -> Image {
+    let mut image = Image::new();
+    display_image_into(ppu, scaler, ntsc, overscan, &mut image);
+    return image;
+}
+
+pub fn display_image_into(ppu: &PPU, scaler: &mut Scaler, ntsc: &Option<NtscFilter>, overscan: &Overscan, image: &mut Image) {
+    if let Some(filter) = ntsc {
+        let (width, height) = overscan.cropped_size(NTSC_WIDTH, NTSC_HEIGHT);
+        overscan.crop(filter.output(), NTSC_WIDTH, NTSC_HEIGHT, &mut image.pixels);
+        image.width = width;
+        image.height = height;
+        return;
+    }
+    native_image_into(ppu, overscan, image);
+    if scaler.filter == ScaleFilter::None {
+        return;
+    }
+    let (width, height) = scaler.output_size(image.width, image.height);
+    let scaled = scaler.scale(&image.pixels, image.width, image.height);
+    image.pixels.clear();
+    image.pixels.extend_from_slice(scaled);
+    image.width = width;
+    image.height = height;
+}
+
+pub fn save_png(image: &mut Image, path: &Path) -> Result<(), String> {
+    let pitch = image.width as u32 * 3;
+    let surface = Surface::from_data(
+        &mut image.pixels,
+        image.width as u32,
+        image.height as u32,
+        pitch,
+        PixelFormatEnum::RGB24,
+    )?;
+    surface.save(path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+//Saved next to `path` with -scaled added to the name
+pub fn scaled_path(path: &Path) -> std::path::PathBuf {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("screenshot");
+    path.with_file_name(format!("{}-scaled.png", stem))
+}
+
+#[test]
+fn test_native_image() {
+    let mut ppu = PPU::new();
+    let image = native_image(&ppu, &Overscan::new(8, 8, 0, 0));
+    assert_eq!((image.width, image.height), (256, 224));
+    assert_eq!(image.pixels.len(), 256 * 224 * 3);
+
+    //Any other pixel format gives the same picture
+    let overscan = Overscan::new(8, 16, 4, 2);
+    let rgb = native_image(&ppu, &overscan);
+    ppu.set_pixel_format(PixelFormat::Bgra8888);
+    let bgra = native_image(&ppu, &overscan);
+    assert!(rgb.pixels == bgra.pixels && (rgb.width, rgb.height) == (bgra.width, bgra.height));
+
+    let mut scaler = Scaler::new(ScaleFilter::Hq2x);
+    let image = display_image(&ppu, &mut scaler, &None, &Overscan::default());
+    assert_eq!((image.width, image.height), (512, 480));
+    assert_eq!(scaled_path(Path::new("roms/mario-1.png")), Path::new("roms/mario-1-scaled.png"));
+}
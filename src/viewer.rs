@@ -0,0 +1,276 @@
+use crate::ppu::PPU;
+use crate::screenshot::Image;
+
+//Debug views of PPU memory, read through the peek API so they don't disturb the emulation
+
+//The four logical nametables, 2x2 as they are addressed
+pub const NAMETABLE_VIEW_WIDTH: usize = 512;
+pub const NAMETABLE_VIEW_HEIGHT: usize = 480;
+
+#[derive(Debug, PartialEq)]
+pub struct TileInfo {
+    pub nametable: u8,
+    pub column: u8,
+    pub row: u8,
+    //PPU address of the tile index and of its attribute byte
+    pub address: u16,
+    pub tile: u8,
+    pub attribute_address: u16,
+    pub attribute: u8,
+    pub palette: u8,
+}
+
+//https://wiki.nesdev.com/w/index.php/PPU_attribute_tables
+pub fn nametable_tile(ppu: &PPU, nametable: u8, column: u8, row: u8) -> TileInfo {
+    let base = 0x2000 | ((nametable as u16) << 10);
+    let address = base | ((row as u16) << 5) | column as u16;
+    let attribute_address = base | 0x03C0 | (((row >> 2) as u16) << 3) | (column >> 2) as u16;
+    let attribute = ppu.peek_ppu(attribute_address);
+    let shift = ((row & 0x02) << 1) | (column & 0x02);
+    TileInfo {
+        nametable,
+        column,
+        row,
+        address,
+        tile: ppu.peek_ppu(address),
+        attribute_address,
+        attribute,
+        palette: (attribute >> shift) & 0x03,
+    }
+}
+
+//Tile under a point of the nametable view
+pub fn nametable_tile_at(ppu: &PPU, x: usize, y: usize) -> TileInfo {
+    let x = x % NAMETABLE_VIEW_WIDTH;
+    let y = y % NAMETABLE_VIEW_HEIGHT;
+    let nametable = ((x / 256) | (y / 240) << 1) as u8;
+    nametable_tile(ppu, nametable, ((x % 256) / 8) as u8, ((y % 240) / 8) as u8)
+}
+
+//Every nametable through the cartridge's mirroring, drawn with the current background pattern
+//table and palettes
+pub fn nametables(ppu: &PPU) -> Image {
+    let mut pixels = vec![0; NAMETABLE_VIEW_WIDTH * NAMETABLE_VIEW_HEIGHT * 3];
+    let table = ppu.background_table();
+    for nametable in 0..4u8 {
+        for row in 0..30u8 {
+            for column in 0..32u8 {
+                let tile = nametable_tile(ppu, nametable, column, row);
+                let left = (nametable as usize & 1) * 256 + column as usize * 8;
+                let top = (nametable as usize >> 1) * 240 + row as usize * 8;
+                for fine_y in 0..8 {
+                    let address = table | ((tile.tile as u16) << 4) | fine_y as u16;
+                    let low = ppu.peek_ppu(address);
+                    let high = ppu.peek_ppu(address + 8);
+                    for fine_x in 0..8 {
+                        let pixel = (((high >> (7 - fine_x)) & 1) << 1) | ((low >> (7 - fine_x)) & 1);
+                        let colour = if pixel == 0 { ppu.get_colour(0, 0) } else { ppu.get_colour(tile.palette, pixel) };
+                        let (r, g, b) = ppu.output_colour(colour as u16);
+                        let i = ((top + fine_y) * NAMETABLE_VIEW_WIDTH + left + fine_x) * 3;
+                        pixels[i] = r;
+                        pixels[i + 1] = g;
+                        pixels[i + 2] = b;
+                    }
+                }
+            }
+        }
+    }
+    Image {
+        pixels,
+        width: NAMETABLE_VIEW_WIDTH,
+        height: NAMETABLE_VIEW_HEIGHT,
+    }
+}
+
+//Where a loopy address register and fine X point in the nametable view
+//https://wiki.nesdev.com/w/index.php/PPU_scrolling
+pub fn scroll_position(address: u16, fine_x: u8) -> (usize, usize) {
+    let coarse_x = (address & 0x1F) as usize;
+    let coarse_y = ((address >> 5) & 0x1F) as usize;
+    let nametable_x = ((address >> 10) & 1) as usize;
+    let nametable_y = ((address >> 11) & 1) as usize;
+    let fine_y = ((address >> 12) & 0x07) as usize;
+    (
+        nametable_x * 256 + coarse_x * 8 + fine_x as usize,
+        (nametable_y * 240 + coarse_y * 8 + fine_y) % NAMETABLE_VIEW_HEIGHT,
+    )
+}
+
+//The 256x240 screen starting at (x, y), split into up to four rectangles where it wraps
+pub fn viewport_rects(x: usize, y: usize) -> Vec<(usize, usize, usize, usize)> {
+    let mut rects = Vec::new();
+    let widths = [(x, 256.min(NAMETABLE_VIEW_WIDTH - x)), (0, (x + 256).saturating_sub(NAMETABLE_VIEW_WIDTH))];
+    let heights = [(y, 240.min(NAMETABLE_VIEW_HEIGHT - y)), (0, (y + 240).saturating_sub(NAMETABLE_VIEW_HEIGHT))];
+    for &(left, width) in widths.iter().filter(|w| w.1 > 0) {
+        for &(top, height) in heights.iter().filter(|h| h.1 > 0) {
+            rects.push((left, top, width, height));
+        }
+    }
+    return rects;
+}
+
+//The 32 palette RAM entries as ($3Fxx address, colour index). $3F10/$3F14/$3F18/$3F1C show
+//the background entries they mirror
+//https://wiki.nesdev.com/w/index.php/PPU_palettes
+pub fn palette_entries(ppu: &PPU) -> Vec<(u16, u8)> {
+    return (0x3F00..0x3F20).map(|address| (address, ppu.peek_ppu(address) & 0x3F)).collect();
+}
+
+//https://wiki.nesdev.com/w/index.php/PPU_OAM
+#[derive(Debug, PartialEq)]
+pub struct SpriteInfo {
+    pub index: usize,
+    pub x: u8,
+    pub y: u8,
+    pub tile: u8,
+    pub attribute: u8,
+    pub palette: u8,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+    pub behind_background: bool,
+}
+
+pub fn sprite(ppu: &PPU, index: usize) -> SpriteInfo {
+    let entry = &ppu.oam_ram[index * 4..index * 4 + 4];
+    SpriteInfo {
+        index,
+        x: entry[3],
+        y: entry[0],
+        tile: entry[1],
+        attribute: entry[2],
+        palette: entry[2] & 0x03,
+        flip_horizontal: entry[2] & 0x40 > 0,
+        flip_vertical: entry[2] & 0x80 > 0,
+        behind_background: entry[2] & 0x20 > 0,
+    }
+}
+
+//Sprites are drawn a line below their Y, the same test the PPU's evaluation makes
+pub fn sprite_on_scanline(ppu: &PPU, sprite: &SpriteInfo, scanline: i32) -> bool {
+    let row = scanline - (sprite.y as i32 + 1);
+    return row >= 0 && row < ppu.sprite_height();
+}
+
+//All 64 sprites side by side, 8 pixels wide and 16 high, with their palette and flips applied.
+//8x8 sprites leave the bottom half as backdrop, as does every transparent pixel
+pub fn sprite_sheet(ppu: &PPU) -> Image {
+    let width = 64 * 8;
+    let mut pixels = vec![0; width * 16 * 3];
+    let (r, g, b) = {
+        let backdrop = ppu.get_colour(0, 0);
+        ppu.output_colour(backdrop as u16)
+    };
+    for pixel in pixels.chunks_mut(3) {
+        pixel.copy_from_slice(&[r, g, b]);
+    }
+    for index in 0..64 {
+        let info = sprite(ppu, index);
+        for row in 0..ppu.sprite_height() as u8 {
+            //The pattern address already accounts for vertical flip
+            let address = ppu.sprite_pattern_address(info.tile, info.attribute, row);
+            let low = ppu.peek_ppu(address);
+            let high = ppu.peek_ppu(address + 8);
+            for column in 0..8 {
+                let bit = if info.flip_horizontal { column } else { 7 - column };
+                let pixel = (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+                if pixel == 0 {
+                    continue;
+                }
+                let colour = ppu.get_colour(4 + info.palette, pixel);
+                let (r, g, b) = ppu.output_colour(colour as u16);
+                let i = (row as usize * width + index * 8 + column as usize) * 3;
+                pixels[i] = r;
+                pixels[i + 1] = g;
+                pixels[i + 2] = b;
+            }
+        }
+    }
+    Image { pixels, width, height: 16 }
+}
+
+#[test]
+fn test_nametable_view() {
+    use crate::cartridge::Cartridge;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let mut ppu = PPU::new();
+    ppu.connect_cartridge(Rc::new(RefCell::new(Cartridge::new("src/test/nestest.nes".to_string()).unwrap())));
+    let write = |ppu: &mut PPU, address: u16, data: u8| {
+        ppu.cpu_write(0x0006, (address >> 8) as u8);
+        ppu.cpu_write(0x0006, address as u8);
+        ppu.cpu_write(0x0007, data);
+    };
+    //Tile 3 at column 5, row 2 of the second nametable, palette 2 in the bottom left attribute quadrant
+    write(&mut ppu, 0x2445, 0x03);
+    write(&mut ppu, 0x27C1, 0x02 << 4);
+    write(&mut ppu, 0x3F09, 0x16);
+
+    let info = nametable_tile_at(&ppu, 256 + 5 * 8 + 3, 2 * 8 + 1);
+    assert_eq!((info.nametable, info.column, info.row), (1, 5, 2));
+    assert_eq!((info.address, info.tile), (0x2445, 0x03));
+    assert_eq!((info.attribute_address, info.palette), (0x27C1, 2));
+
+    let image = nametables(&ppu);
+    assert_eq!(image.pixels.len(), 512 * 480 * 3);
+
+    //Scrolled to x = 300 and y = 250 wraps around both edges
+    assert_eq!(scroll_position(0x0C00 | (1 << 5) | 5 | (2 << 12), 4), (256 + 44, 240 + 10));
+    assert_eq!(viewport_rects(300, 250), vec![(300, 250, 212, 230), (300, 0, 212, 10), (0, 250, 44, 230), (0, 0, 44, 10)]);
+    assert_eq!(viewport_rects(0, 0), vec![(0, 0, 256, 240)]);
+}
+
+#[test]
+fn test_palette_entries() {
+    use crate::cartridge::Cartridge;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let mut ppu = PPU::new();
+    ppu.connect_cartridge(Rc::new(RefCell::new(Cartridge::new("src/test/nestest.nes".to_string()).unwrap())));
+    ppu.cpu_write(0x0006, 0x3F);
+    ppu.cpu_write(0x0006, 0x10);
+    ppu.cpu_write(0x0007, 0x21);
+    ppu.cpu_write(0x0007, 0x16);
+    let entries = palette_entries(&ppu);
+    assert_eq!(entries.len(), 32);
+    assert_eq!(entries[0], (0x3F00, 0x21));
+    assert_eq!(entries[0x11], (0x3F11, 0x16));
+    assert_eq!(entries[0x10], (0x3F10, 0x21));
+}
+
+#[test]
+fn test_sprite_view() {
+    use crate::cartridge::Cartridge;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let mut ppu = PPU::new();
+    ppu.connect_cartridge(Rc::new(RefCell::new(Cartridge::new("src/test/nestest.nes".to_string()).unwrap())));
+    ppu.cpu_write(0x0006, 0x3F);
+    ppu.cpu_write(0x0006, 0x00);
+    for colour in [0x0F, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0F, 0x00, 0x00, 0x00, 0x0F, 0x00, 0x00, 0x30].iter() {
+        ppu.cpu_write(0x0007, *colour);
+    }
+    //Sprite 1 at (20, 100) with palette 1 and flipped horizontally
+    ppu.oam_ram[4..8].copy_from_slice(&[100, 0x41, 0x41, 20]);
+    let info = sprite(&ppu, 1);
+    assert_eq!((info.x, info.y, info.tile, info.palette), (20, 100, 0x41, 1));
+    assert!(info.flip_horizontal && !info.flip_vertical && !info.behind_background);
+    assert!(!sprite_on_scanline(&ppu, &info, 100));
+    assert!(sprite_on_scanline(&ppu, &info, 101));
+    assert!(sprite_on_scanline(&ppu, &info, 108));
+    assert!(!sprite_on_scanline(&ppu, &info, 109));
+
+    //Mirrored across the sprite compared with reading the pattern directly
+    let sheet = sprite_sheet(&ppu);
+    assert_eq!((sheet.width, sheet.height), (512, 16));
+    let low = ppu.peek_ppu(0x0410);
+    let high = ppu.peek_ppu(0x0418);
+    let white = ppu.output_colour(0x30);
+    for column in 0..8 {
+        let set = (low >> column) & (high >> column) & 1 > 0;
+        let i = (8 + column) * 3;
+        assert_eq!((sheet.pixels[i], sheet.pixels[i + 1], sheet.pixels[i + 2]) == white, set);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::apu::{APU, CAPTURE_SAMPLE_RATE, CHANNELS};
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::path::{Path, PathBuf};
+
+//http://soundfile.sapp.org/doc/WaveFormat/
+const HEADER_SIZE: u32 = 44;
+
+//16 bit PCM wav file, the chunk sizes are patched in once the length is known
+pub struct WavWriter {
+    file: BufWriter<File>,
+    data_size: u32,
+    finished: bool,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32, channels: u16) -> io::Result<WavWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let block_align = channels * 2;
+        file.write_all(b"RIFF")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?; //PCM
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter {
+            file,
+            data_size: 0,
+            finished: false,
+        })
+    }
+
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_size += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_size.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+//Records the APU's capture output, optionally with every channel in its own file next to the mix
+//(capture.wav, capture.pulse_0.wav, capture.pulse_1.wav ...)
+pub struct AudioCapture {
+    mix: WavWriter,
+    channels: Vec<WavWriter>,
+    pub path: PathBuf,
+}
+
+impl AudioCapture {
+    pub fn start(path: &Path, apu: &mut APU, split_channels: bool) -> io::Result<AudioCapture> {
+        let sample_rate = CAPTURE_SAMPLE_RATE as u32;
+        let mix = WavWriter::create(path, sample_rate, 1)?;
+        let mut channels = Vec::new();
+        if split_channels {
+            for &channel in CHANNELS.iter() {
+                let channel_path = path.with_extension(format!("{}.wav", channel.name()));
+                channels.push(WavWriter::create(&channel_path, sample_rate, 1)?);
+            }
+        }
+        apu.set_capture(true);
+        apu.set_channel_capture(split_channels);
+        Ok(AudioCapture {
+            mix,
+            channels,
+            path: path.to_path_buf(),
+        })
+    }
+
+    //Must be called every frame before the frontend clears APU::capture_samples
+    pub fn capture(&mut self, apu: &mut APU) -> io::Result<()> {
+        self.mix.write_samples(&apu.capture_samples)?;
+        for (writer, samples) in self.channels.iter_mut().zip(apu.channel_samples.iter_mut()) {
+            writer.write_samples(samples)?;
+            samples.clear();
+        }
+        Ok(())
+    }
+
+    //Leaves the APU's capture output running, a recording may still be using it
+    pub fn stop(mut self, apu: &mut APU) -> io::Result<()> {
+        apu.set_channel_capture(false);
+        self.mix.finish()?;
+        for writer in self.channels.iter_mut() {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_wav_header() {
+    let path = std::env::temp_dir().join("nes_test_wav_header.wav");
+    {
+        let mut writer = WavWriter::create(&path, 44100, 1).unwrap();
+        writer.write_samples(&[0, 1, -1, 32767]).unwrap();
+    }
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(bytes.len(), 44 + 8);
+    assert_eq!(&bytes[0..4], b"RIFF");
+    assert_eq!(u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]), 36 + 8);
+    assert_eq!(&bytes[8..12], b"WAVE");
+    assert_eq!(u32::from_le_bytes([bytes[24], bytes[25], bytes[26], bytes[27]]), 44100);
+    assert_eq!(u32::from_le_bytes([bytes[40], bytes[41], bytes[42], bytes[43]]), 8);
+    assert_eq!(&bytes[50..52], &32767i16.to_le_bytes());
+}